@@ -0,0 +1,399 @@
+/*!
+    #[derive(Serialize)] for the bitpacker protocol.
+
+    Generates an `Object` impl whose serialize function walks every field in declaration order,
+    and a `Packet` impl when the struct is tagged with `#[packet_type(...)]`.
+
+    Field attributes:
+    - #[range(min, max)]                  integer serialized with serialize_int in [min, max]
+    - #[bits(n)]                          unsigned integer serialized with n bits
+    - #[compressed(min, max, precision)]  f32 or Vector3d<f32> compressed to a precision
+    - #[max_len(n)]                       String or Vec with at most n bytes / items
+    - #[check]                            serialize_check after the field, to catch desyncs
+
+    Fields without attributes are inferred from their type (bool, f32, u8/u16/u32/u64/u128,
+    i8/i16/i32, Vector3d<f32>), anything else is serialized as a nested Object.
+    Attributes on a Vec field apply to each of its items.
+
+    The generated code refers to the serialize macros, Stream, Object and Packet through `crate::`
+    paths, so the derive only works inside the bitpacker crate itself.
+*/
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{
+    parse_macro_input, punctuated::Punctuated, spanned::Spanned, Attribute, Data, DeriveInput,
+    Error, Expr, Fields, GenericArgument, Member, PathArguments, Result, Token, Type,
+};
+
+#[proc_macro_derive(
+    Serialize,
+    attributes(range, bits, compressed, max_len, check, packet_type)
+)]
+pub fn derive_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/** Serialization attributes found on a single field */
+#[derive(Default)]
+struct FieldAttributes {
+    range: Option<(Expr, Expr)>,
+    bits: Option<Expr>,
+    compressed: Option<(Expr, Expr, Expr)>,
+    max_len: Option<Expr>,
+    check: bool,
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "#[derive(Serialize)] only supports structs",
+            ))
+        }
+    };
+
+    let mut field_code = vec![];
+    let members: Vec<(Member, &syn::Field)> = match fields {
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|field| (Member::Named(field.ident.clone().unwrap()), field))
+            .collect(),
+        Fields::Unnamed(unnamed) => unnamed
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(index, field)| (Member::Unnamed(index.into()), field))
+            .collect(),
+        Fields::Unit => vec![],
+    };
+
+    for (member, field) in members {
+        let attributes = parse_field_attributes(&field.attrs)?;
+        let place = quote!(self.#member);
+        field_code.push(serialize_field(&place, &field.ty, &attributes)?);
+
+        if attributes.check {
+            let label = match &member {
+                Member::Named(ident) => format!("{}::{}", name, ident),
+                Member::Unnamed(index) => format!("{}::{}", name, index.index),
+            };
+            field_code.push(quote! {
+                crate::serialize_check!(stream, #label);
+            });
+        }
+    }
+
+    let mut output = quote! {
+        impl #impl_generics crate::protocol::packets::object::Object for #name #type_generics #where_clause {
+            #[allow(unused_variables, clippy::unnecessary_cast)]
+            fn serialize<__S: crate::protocol::streams::Stream>(&mut self, stream: &mut __S) -> bool {
+                #[allow(unused_imports)]
+                use crate::protocol::streams::Stream;
+                #(#field_code)*
+                true
+            }
+        }
+    };
+
+    if let Some(packet_type) = parse_packet_type(&input.attrs)? {
+        output.extend(quote! {
+            impl #impl_generics crate::protocol::packets::object::Packet for #name #type_generics #where_clause {
+                fn get_packet_type(&self) -> u32 {
+                    (#packet_type) as u32
+                }
+            }
+        });
+    }
+
+    Ok(output)
+}
+
+fn parse_args(attribute: &Attribute, expected: usize) -> Result<Vec<Expr>> {
+    let args = attribute.parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)?;
+    if args.len() != expected {
+        return Err(Error::new(
+            attribute.span(),
+            format!("expected {} argument(s)", expected),
+        ));
+    }
+    Ok(args.into_iter().collect())
+}
+
+fn parse_field_attributes(attrs: &[Attribute]) -> Result<FieldAttributes> {
+    let mut attributes = FieldAttributes::default();
+
+    for attribute in attrs {
+        if attribute.path.is_ident("range") {
+            let mut args = parse_args(attribute, 2)?.into_iter();
+            attributes.range = Some((args.next().unwrap(), args.next().unwrap()));
+        } else if attribute.path.is_ident("bits") {
+            attributes.bits = parse_args(attribute, 1)?.pop();
+        } else if attribute.path.is_ident("compressed") {
+            let mut args = parse_args(attribute, 3)?.into_iter();
+            attributes.compressed = Some((
+                args.next().unwrap(),
+                args.next().unwrap(),
+                args.next().unwrap(),
+            ));
+        } else if attribute.path.is_ident("max_len") {
+            attributes.max_len = parse_args(attribute, 1)?.pop();
+        } else if attribute.path.is_ident("check") {
+            attributes.check = true;
+        }
+    }
+
+    let encodings = attributes.range.is_some() as u32
+        + attributes.bits.is_some() as u32
+        + attributes.compressed.is_some() as u32;
+    if encodings > 1 {
+        return Err(Error::new(
+            attrs[0].span(),
+            "only one of #[range], #[bits] and #[compressed] can be used on a field",
+        ));
+    }
+
+    Ok(attributes)
+}
+
+fn parse_packet_type(attrs: &[Attribute]) -> Result<Option<Expr>> {
+    for attribute in attrs {
+        if attribute.path.is_ident("packet_type") {
+            return Ok(parse_args(attribute, 1)?.pop());
+        }
+    }
+    Ok(None)
+}
+
+/** Last path segment of a type, ex. "u32" or "Vec" */
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string()),
+        _ => None,
+    }
+}
+
+/** First generic argument of a type, ex. T for Vec<T> */
+fn type_argument(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let PathArguments::AngleBracketed(arguments) = &path.path.segments.last()?.arguments else {
+        return None;
+    };
+    arguments.args.iter().find_map(|argument| match argument {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+fn is_integer(name: &str) -> bool {
+    matches!(name, "u8" | "u16" | "u32" | "i8" | "i16" | "i32")
+}
+
+/** Generates the serialize code for one value, where place is an assignable expression */
+fn serialize_field(
+    place: &TokenStream2,
+    ty: &Type,
+    attributes: &FieldAttributes,
+) -> Result<TokenStream2> {
+    let name = type_name(ty).unwrap_or_default();
+    let span = ty.span();
+
+    if name == "Vec" || name == "String" {
+        return serialize_sequence(place, ty, &name, attributes);
+    }
+
+    if let Some((min, max, precision)) = &attributes.compressed {
+        return match name.as_str() {
+            "f32" => Ok(quote_spanned! {span=>
+                crate::serialize_compressed_float!(stream, #place, #min, #max, #precision);
+            }),
+            "Vector3d" => Ok(quote_spanned! {span=>
+                crate::serialize_compressed_vector!(stream, #place, #min, #max, #precision);
+            }),
+            _ => Err(Error::new(
+                span,
+                "#[compressed] can only be used on f32 and Vector3d<f32>",
+            )),
+        };
+    }
+
+    if let Some((min, max)) = &attributes.range {
+        if !is_integer(&name) {
+            return Err(Error::new(span, "#[range] can only be used on integers"));
+        }
+        return Ok(quote_spanned! {span=>
+            {
+                let mut value: i32 = #place as i32;
+                crate::serialize_int!(stream, value, (#min) as i32, (#max) as i32);
+                if stream.is_reading() {
+                    #place = value as #ty;
+                }
+            }
+        });
+    }
+
+    if let Some(bits) = &attributes.bits {
+        return match name.as_str() {
+            "u8" | "u16" | "u32" => Ok(quote_spanned! {span=>
+                {
+                    let mut value: u32 = #place as u32;
+                    crate::serialize_bits!(stream, value, (#bits) as u32);
+                    if stream.is_reading() {
+                        #place = value as #ty;
+                    }
+                }
+            }),
+            "u64" => Ok(quote_spanned! {span=>
+                crate::serialize_bits_u64!(stream, #place, (#bits) as u32);
+            }),
+            _ => Err(Error::new(
+                span,
+                "#[bits] can only be used on u8, u16, u32 and u64",
+            )),
+        };
+    }
+
+    // No attribute, infer from the type
+    let default_attributes = match name.as_str() {
+        "u8" => Some(FieldAttributes {
+            bits: Some(syn::parse_quote!(8)),
+            ..Default::default()
+        }),
+        "u16" => Some(FieldAttributes {
+            bits: Some(syn::parse_quote!(16)),
+            ..Default::default()
+        }),
+        "u32" => Some(FieldAttributes {
+            bits: Some(syn::parse_quote!(32)),
+            ..Default::default()
+        }),
+        "i8" | "i16" | "i32" => {
+            let ident = syn::Ident::new(&name, span);
+            Some(FieldAttributes {
+                range: Some((
+                    syn::parse_quote!(#ident::MIN),
+                    syn::parse_quote!(#ident::MAX),
+                )),
+                ..Default::default()
+            })
+        }
+        _ => None,
+    };
+    if let Some(default_attributes) = default_attributes {
+        return serialize_field(place, ty, &default_attributes);
+    }
+
+    Ok(match name.as_str() {
+        "bool" => quote_spanned! {span=>
+            crate::serialize_bool!(stream, #place);
+        },
+        "f32" => quote_spanned! {span=>
+            crate::serialize_float!(stream, #place);
+        },
+        "u64" => quote_spanned! {span=>
+            crate::serialize_u64!(stream, #place);
+        },
+        "u128" => quote_spanned! {span=>
+            crate::serialize_u128!(stream, #place);
+        },
+        "Vector3d" => quote_spanned! {span=>
+            crate::serialize_vector!(stream, #place);
+        },
+        _ => quote_spanned! {span=>
+            crate::serialize_object!(stream, #place);
+        },
+    })
+}
+
+/** Strings, byte vectors and vectors of items, all prefixed by their length */
+fn serialize_sequence(
+    place: &TokenStream2,
+    ty: &Type,
+    name: &str,
+    attributes: &FieldAttributes,
+) -> Result<TokenStream2> {
+    let span = ty.span();
+    let max_len = match &attributes.max_len {
+        Some(max_len) => max_len,
+        None => {
+            return Err(Error::new(
+                span,
+                format!("{} fields need a #[max_len(n)] attribute", name),
+            ))
+        }
+    };
+
+    if name == "String" {
+        // serialize_string takes a buffer size including room for a terminator, like the original,
+        // and reads and writes at most max_len bytes
+        return Ok(quote_spanned! {span=>
+            crate::serialize_string!(stream, #place, (#max_len) as u32 + 2);
+        });
+    }
+
+    let item_type = match type_argument(ty) {
+        Some(item_type) => item_type,
+        None => return Err(Error::new(span, "could not find the Vec item type")),
+    };
+
+    let length = quote_spanned! {span=>
+        let mut length: i32 = #place.len() as i32;
+        crate::serialize_int!(stream, length, 0, (#max_len) as i32);
+    };
+
+    if type_name(item_type).as_deref() == Some("u8")
+        && attributes.range.is_none()
+        && attributes.bits.is_none()
+    {
+        return Ok(quote_spanned! {span=>
+            {
+                #length
+                if stream.is_reading() {
+                    #place = vec![0; length as usize];
+                }
+                if length > 0 {
+                    crate::serialize_bytes!(stream, #place, length as u32);
+                }
+            }
+        });
+    }
+
+    let item_attributes = FieldAttributes {
+        range: attributes.range.clone(),
+        bits: attributes.bits.clone(),
+        compressed: attributes.compressed.clone(),
+        max_len: None,
+        check: false,
+    };
+    let item_code = serialize_field(&quote!((*item)), item_type, &item_attributes)?;
+
+    Ok(quote_spanned! {span=>
+        {
+            #length
+            if stream.is_reading() {
+                #place.clear();
+                #place.resize_with(length as usize, ::std::default::Default::default);
+            }
+            for item in #place.iter_mut() {
+                #item_code
+            }
+        }
+    })
+}
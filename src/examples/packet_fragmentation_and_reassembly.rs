@@ -1,4 +1,6 @@
+use bitpacker_derive::Serialize;
 use rand::Rng;
+use std::cell::Cell;
 
 /*
    SENDING:
@@ -14,122 +16,28 @@ use rand::Rng;
 */
 
 use crate::{
-    impl_object_for_packet, packet_factory_methods,
+    packet_factory_methods,
     protocol::{
         constants::*,
         helpers::calc_packet_crc32,
         packets::object::Object,
         packets::{
-            fragment_packet::FragmentPacket, object::Packet, packet_buffer::PacketBuffer,
-            packet_data::PacketData, packet_factory::PacketFactory, packet_info::PacketInfo,
-            write_packet,
+            fragment_packet::{split_packet_into_fragments, FragmentPacket},
+            measure_packet,
+            object::Packet,
+            packet_buffer::PacketBuffer,
+            packet_data::PacketData,
+            packet_factory::PacketFactory,
+            packet_info::PacketInfo,
+            read_packet, write_packet,
         },
-        serialization::*,
-        streams::{read_stream::ReadStream, write_stream::WriteStream, Stream},
+        streams::{write_stream::WriteStream, Stream},
     },
+    serialize_int,
 };
 
 const NUM_ITERATIONS: u32 = 10;
 
-/**
-Takes a buffer larger than a single packets max since, and:
-- Splits into fragment size chunks
-- Serializes each fragment (adds data about the fragmentation to each packet)
-- Copies that serialized data back into the array passed into the function
-*/
-fn split_packet_into_fragments(
-    sequence: u16,
-    packet_data: &mut Buffer,
-    big_packet_size: u32,
-    num_fragments: &mut u32,
-    fragment_packets: &mut Vec<PacketData>,
-) -> bool {
-    *num_fragments = 0;
-
-    assert!(big_packet_size > 0);
-    assert!(big_packet_size < MAX_PACKET_SIZE as u32);
-
-    // Calculate number of fragments in packet
-    if big_packet_size % MAX_FRAGMENT_SIZE as u32 != 0 {
-        *num_fragments = (big_packet_size / MAX_FRAGMENT_SIZE as u32) + 1
-    } else {
-        *num_fragments = big_packet_size / MAX_FRAGMENT_SIZE as u32
-    };
-
-    assert!(*num_fragments > 0);
-    assert!(*num_fragments <= MAX_FRAGMENTS_PER_PACKET as u32);
-    println!("Splitting packet into {:?} fragments", *num_fragments);
-
-    // Pointer to our big packets buffer
-    let mut src_ptr = packet_data.as_ptr() as *mut u8;
-
-    for i in 0..*num_fragments as usize {
-        let mut bytes_processed: u32 = 0;
-
-        // Calculate fragment size
-        let fragment_size = if i as u32 == *num_fragments - 1 {
-            big_packet_size % (MAX_FRAGMENT_SIZE as u32)
-        } else {
-            MAX_FRAGMENT_SIZE as u32
-        };
-
-        // const MAX_PACKET_FRAGMENT_SIZE is max packet size + number of header bytes
-        fragment_packets[i as usize].data = vec![0; MAX_PACKET_FRAGMENT_SIZE];
-        let mut stream = WriteStream::new(
-            &mut fragment_packets[i as usize].data,
-            MAX_PACKET_FRAGMENT_SIZE,
-        );
-
-        let mut fragment_packet = FragmentPacket::new();
-        fragment_packet.fragment_size = fragment_size;
-        fragment_packet.sequence = sequence;
-        fragment_packet.fragment_id = i as u8;
-        fragment_packet.num_fragments = (*num_fragments) as u8;
-
-        {
-            unsafe {
-                // Copy fragment_size * bytes into fragment packet data from packet data
-                let dest_ptr = fragment_packet.fragment_data.as_mut_ptr();
-                std::ptr::copy_nonoverlapping(src_ptr, dest_ptr, fragment_size as usize);
-            }
-
-            // Serialize fragment packet into our packet data buffer.
-            if !fragment_packet.serialize(&mut stream) {
-                *num_fragments = 0;
-                for _ in 0..i {
-                    fragment_packets[i as usize].size = 0;
-                }
-                return false;
-            };
-
-            stream.writer.flush();
-            bytes_processed = stream.get_bytes_processed();
-        }
-
-        // -- Calc crc32 and add into packet data
-        // TODO: host_to_network(protocolID) ??
-        let fragment_packets_data_ptr = &fragment_packets[i as usize].data;
-        let crc32 = calc_packet_crc32(fragment_packets_data_ptr, PROTOCOL_ID);
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                crc32.to_le_bytes().as_ptr(),
-                fragment_packets[i].data.as_mut_ptr(),
-                4,
-            );
-        }
-
-        println!("Fragment packet {:?}: {:?} bytes", i, bytes_processed);
-        fragment_packets[i as usize].size = bytes_processed;
-
-        unsafe {
-            // Advance src ptr by fragment size
-            src_ptr = src_ptr.add(fragment_size as usize);
-        }
-    }
-
-    return true;
-}
-
 enum TestPacketTypes {
     FRAGMENT = 0,
     A = 1,
@@ -161,27 +69,27 @@ impl TestPacketA {
         }
         return pack;
     }
+}
 
-    fn serialize(&mut self, stream: &mut dyn Stream) -> bool {
+impl Object for TestPacketA {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
         for i in 0..self.items.len() {
-            serialize_int_macro(stream, &mut self.items[i], -100, 100);
+            serialize_int!(stream, self.items[i], -100, 100);
         }
         true
     }
 }
 
-impl_object_for_packet!(TestPacketA);
-
 struct TestPacketFactory {
     num_packet_types: u32,
-    num_allocated_packets: u32,
+    num_allocated_packets: Cell<u32>,
 }
 
 impl TestPacketFactory {
     pub fn new() -> TestPacketFactory {
         return TestPacketFactory {
             num_packet_types: TestPacketTypes::NumTypes as u32,
-            num_allocated_packets: 0,
+            num_allocated_packets: Cell::new(0),
         };
     }
 }
@@ -189,6 +97,8 @@ impl TestPacketFactory {
 impl PacketFactory for TestPacketFactory {
     fn create_packet(&self, packet_type: u32) -> Box<dyn Packet> {
         if packet_type == TestPacketTypes::A as u32 {
+            self.num_allocated_packets
+                .set(self.num_allocated_packets.get() + 1);
             return Box::new(TestPacketA::new());
         }
         panic!();
@@ -197,96 +107,218 @@ impl PacketFactory for TestPacketFactory {
     packet_factory_methods!();
 }
 
+#[derive(Serialize, Default)]
 struct TestPacketHeader {
     sequence: u16,
 }
 
-impl TestPacketHeader {
-    fn serialize(&mut self, stream: &mut dyn Stream) -> bool {
-        let mut temp_sequence: u32 = self.sequence as u32;
-        serialize_bits_macro(stream, &mut temp_sequence, 16);
-        if stream.is_reading() {
-            self.sequence = temp_sequence as u16;
-        }
-        true
-    }
-}
-
-impl_object_for_packet!(TestPacketHeader);
-
 #[test]
 pub fn test() {
-    let mut packet_buffer = PacketBuffer::new();
+    use crate::protocol::protocol_config::ProtocolConfig;
+    use crate::protocol::transport::{LoopbackNetwork, Transport};
+    use rand::seq::SliceRandom;
+
+    let config = ProtocolConfig::builder()
+        .num_packet_types(TestPacketTypes::NumTypes as usize)
+        .build()
+        .unwrap();
+    let mut packet_buffer = PacketBuffer::new(&config);
+
+    // Packets go through an in-process network, so the whole pipeline runs without a real one
+    let network = LoopbackNetwork::new();
+    let mut sender = network.bind("127.0.0.1:40000".parse().unwrap()).unwrap();
+    let mut receiver = network.bind("127.0.0.1:40001".parse().unwrap()).unwrap();
     let packet_factory = TestPacketFactory::new();
-    let sequence: u16 = 0;
+    let mut rng = rand::thread_rng();
 
-    for _ in 0..NUM_ITERATIONS {
+    for sequence in 0..NUM_ITERATIONS as u16 {
         let packet_type: u32 = 1;
         // (1 + rand::random::<u32>()) % ((TestPacketTypes::NumTypes as u32) - 1); // 0 Indicates packet fragment
         println!("PACKET TYPE: {:?}", packet_type);
         let mut packet = packet_factory.create_packet(packet_type);
         assert!(packet.get_packet_type() == packet_type);
 
-        let mut buffer: Buffer = vec![0; MAX_PACKET_SIZE as usize];
-        let mut error: bool = false;
+        let mut buffer: Buffer = vec![0; config.max_packet_size()];
         let mut write_packet_header = TestPacketHeader { sequence };
         let mut info = PacketInfo::new(&packet_factory);
-        info.protocol_id = PROTOCOL_ID;
+        info.config = config;
+        info.allowed_packet_types = vec![TestPacketTypes::A as u32];
 
         let bytes_written = write_packet(
             &info,
             packet.as_mut(),
             &mut buffer,
-            MAX_PACKET_SIZE,
+            config.max_packet_size(),
             Some(&mut write_packet_header),
         );
 
         println!("====================================");
         println!("Writing packet {:?}", sequence);
 
-        if bytes_written > 0 {
-            println!(
-                "Wrote packet type {:?} ({:?} bytes)",
-                packet.get_packet_type(),
-                bytes_written
-            );
-        } else {
-            println!("Write packet error");
-            error = true;
-        }
-
-        if bytes_written > MAX_FRAGMENT_SIZE as u32 {
-            let mut num_fragments: u32 = 0;
+        let bytes_measured = measure_packet(
+            &info,
+            packet.as_mut(),
+            Some(&mut TestPacketHeader { sequence }),
+        );
+        assert_eq!(bytes_measured, bytes_written);
 
-            // Create vector of PacketData objects to hold data we will split out of our large packet
-            let mut fragment_packets: Vec<PacketData> = vec![];
-            for _i in 0..MAX_FRAGMENTS_PER_PACKET {
-                fragment_packets.push(PacketData::new());
-            }
+        assert!(bytes_written > 0, "Write packet error");
+        println!(
+            "Wrote packet type {:?} ({:?} bytes)",
+            packet.get_packet_type(),
+            bytes_written
+        );
 
+        if bytes_written > config.max_fragment_size() as u32 {
             // Split large packet
-            split_packet_into_fragments(
-                sequence,
-                &mut buffer,
-                bytes_written,
-                &mut num_fragments,
-                &mut fragment_packets,
+            let fragment_packets =
+                split_packet_into_fragments(&config, sequence, &buffer[..bytes_written as usize])
+                    .unwrap();
+
+            assert_eq!(
+                fragment_packets.len(),
+                (bytes_written as usize).div_ceil(config.max_fragment_size())
             );
+            for fragment in fragment_packets.iter() {
+                assert_eq!(fragment.size as usize, fragment.data.len());
+                assert!(fragment.data.len() <= config.max_packet_fragment_size());
+            }
 
-            // ... sending across the network ...
+            // Fragments can arrive in any order, and more than once
+            let mut sent_fragments: Vec<&PacketData> = fragment_packets.iter().collect();
+            sent_fragments.push(&fragment_packets[1]);
+            sent_fragments.shuffle(&mut rng);
+            for fragment in sent_fragments.iter() {
+                sender
+                    .send_to(receiver.local_address(), &fragment.data)
+                    .unwrap();
+            }
 
             // Process the fragment packets
-            for j in 0..num_fragments as usize {
-                let fragment_size = fragment_packets[j].size;
-                packet_buffer.process_packet(&mut fragment_packets[j].data, fragment_size);
+            let mut receive_buffer = vec![0; config.max_packet_fragment_size()];
+            while let Some((size, _)) = receiver.recv_from(&mut receive_buffer).unwrap() {
+                packet_buffer.process_packet(&receive_buffer, size as u32);
             }
+            assert_eq!(packet_buffer.num_invalid_crc32_packets, 0);
+
+            // A fragment damaged in transit is dropped and counted
+            let mut damaged_fragment = fragment_packets[0].data.clone();
+            damaged_fragment[20] ^= 1;
+            assert!(!packet_buffer.process_packet(&damaged_fragment, fragment_packets[0].size));
+            assert_eq!(packet_buffer.num_invalid_crc32_packets, 1);
+            packet_buffer.num_invalid_crc32_packets = 0;
         } else {
             println!("Sending packet {:?} as a regular packet", sequence);
-            // ... sending across the network ...
+            sender
+                .send_to(receiver.local_address(), &buffer[..bytes_written as usize])
+                .unwrap();
+
             // Process the fragment packet
-            packet_buffer.process_packet(&mut buffer, bytes_written);
+            let mut receive_buffer = vec![0; config.max_packet_fragment_size()];
+            let (size, _) = receiver.recv_from(&mut receive_buffer).unwrap().unwrap();
+            packet_buffer.process_packet(&receive_buffer, size as u32);
         }
+
+        // The reassembled packet is exactly what was written, and reads back like any other packet
+        let received_packets = packet_buffer.receive_packets();
+        assert_eq!(received_packets.len(), 1);
+        assert_eq!(received_packets[0].size, bytes_written);
+        assert_eq!(
+            received_packets[0].data,
+            buffer[..bytes_written as usize].to_vec()
+        );
+        assert_eq!(packet_buffer.num_buffered_fragments, 0);
+
+        let mut read_packet_header = TestPacketHeader::default();
+        let read_packet = read_packet(
+            &info,
+            &received_packets[0].data,
+            Some(&mut read_packet_header),
+        )
+        .unwrap();
+        assert_eq!(read_packet.get_packet_type(), packet_type);
+        assert_eq!(read_packet_header.sequence, sequence);
+    }
+
+    // A packet missing a fragment is never received, and is evicted once the sequence moves on
+    let fragment_packets = split_packet_into_fragments(&config, 1000, &[7; 3000]).unwrap();
+    assert_eq!(fragment_packets.len(), 3);
+    for fragment in fragment_packets.iter().skip(1) {
+        assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+    assert!(packet_buffer.receive_packets().is_empty());
+    assert_eq!(packet_buffer.num_buffered_fragments, 2);
+
+    let fragment_packets = split_packet_into_fragments(
+        &config,
+        1000 + config.packet_buffer_size() as u16,
+        &[9; 100],
+    )
+    .unwrap();
+    assert!(packet_buffer.process_packet(&fragment_packets[0].data, fragment_packets[0].size));
+    assert_eq!(packet_buffer.num_buffered_fragments, 1);
+    let received_packets = packet_buffer.receive_packets();
+    assert_eq!(received_packets.len(), 1);
+    assert_eq!(received_packets[0].data, vec![9; 100]);
+
+    // A sequence wildly out of range of the current sequence is rejected without moving the buffer
+    let fragment_packets = split_packet_into_fragments(&config, 40000, &[3; 100]).unwrap();
+    assert!(!packet_buffer.process_packet(&fragment_packets[0].data, fragment_packets[0].size));
+    assert_eq!(
+        packet_buffer.current_sequence(),
+        1000 + config.packet_buffer_size() as u16
+    );
+
+    // A fragment with a valid crc32 that doesn't parse, here one with no fragment data, is dropped and counted
+    let fragment_packets = split_packet_into_fragments(&config, 1001, &[5; 100]).unwrap();
+    let mut empty_fragment = fragment_packets[0].data.clone();
+    empty_fragment.truncate(empty_fragment.len() - 100);
+    let crc32 = calc_packet_crc32(&empty_fragment, config.protocol_id());
+    empty_fragment[..4].copy_from_slice(&crc32.to_le_bytes());
+    assert!(!packet_buffer.process_packet(&empty_fragment, empty_fragment.len() as u32));
+    assert_eq!(packet_buffer.num_invalid_fragment_packets, 1);
+    assert_eq!(packet_buffer.num_invalid_crc32_packets, 0);
+
+    // Fragments with a size the config doesn't allow fail to write instead of panicking
+    for fragment_size in [0, config.max_fragment_size() as u32 + 1] {
+        let mut fragment_packet = FragmentPacket::new(&config);
+        fragment_packet.num_fragments = 1;
+        fragment_packet.fragment_size = fragment_size;
+        fragment_packet.fragment_data = vec![0; fragment_size as usize];
+        let mut buffer = vec![0; config.max_packet_fragment_size() * 2];
+        assert!(!fragment_packet.serialize(&mut WriteStream::new(&mut buffer)));
+    }
+
+    // Factories with many packet types size the fragment packet type field to match
+    let config = ProtocolConfig::builder()
+        .num_packet_types(100)
+        .build()
+        .unwrap();
+    let mut packet_buffer = PacketBuffer::new(&config);
+    let fragment_packets = split_packet_into_fragments(&config, 0, &[1; 3000]).unwrap();
+    for fragment in fragment_packets.iter() {
+        assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
     }
+    let received_packets = packet_buffer.receive_packets();
+    assert_eq!(received_packets.len(), 1);
+    assert_eq!(received_packets[0].data, vec![1; 3000]);
+
+    // A regular packet with a packet type past what used to fit in the field
+    let mut regular_packet = vec![0; 16];
+    let bytes_written = {
+        let mut stream = WriteStream::new(&mut regular_packet);
+        let (mut crc32, mut sequence, mut packet_type) = (0, 1, 50);
+        assert!(stream.serialize_bits(&mut crc32, 32));
+        assert!(stream.serialize_bits(&mut sequence, 16));
+        assert!(stream.serialise_int(&mut packet_type, 0, 99));
+        assert!(stream.flush());
+        stream.get_bytes_processed() as usize
+    };
+    regular_packet.truncate(bytes_written);
+    let crc32 = calc_packet_crc32(&regular_packet, config.protocol_id());
+    regular_packet[..4].copy_from_slice(&crc32.to_le_bytes());
+    assert!(packet_buffer.process_packet(&regular_packet, bytes_written as u32));
+    assert_eq!(packet_buffer.receive_packets()[0].data, regular_packet);
 
     //     /*
 
@@ -350,3 +382,164 @@ pub fn test() {
     // assert( src == packetData + packetSize );
     // true
 }
+
+#[test]
+pub fn test_protocol_profiles() {
+    use crate::protocol::protocol_config::ProtocolConfig;
+
+    // Two profiles side by side in the same process
+    let lan_config = ProtocolConfig::builder()
+        .protocol_id(0x11111111)
+        .max_fragment_size(4096)
+        .max_fragments_per_packet(64)
+        .build()
+        .unwrap();
+    let internet_config = ProtocolConfig::builder()
+        .protocol_id(0x22222222)
+        .max_fragment_size(512)
+        .packet_buffer_size(64)
+        .build()
+        .unwrap();
+
+    let packet_data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let lan_fragments = split_packet_into_fragments(&lan_config, 7, &packet_data).unwrap();
+    let internet_fragments =
+        split_packet_into_fragments(&internet_config, 7, &packet_data).unwrap();
+    assert_eq!(lan_fragments.len(), 3);
+    assert_eq!(internet_fragments.len(), 20);
+    for fragment in internet_fragments.iter() {
+        assert!(fragment.data.len() <= internet_config.max_packet_fragment_size());
+    }
+
+    let mut lan_packet_buffer = PacketBuffer::new(&lan_config);
+    let mut internet_packet_buffer = PacketBuffer::new(&internet_config);
+
+    for fragment in lan_fragments.iter() {
+        assert!(lan_packet_buffer.process_packet(&fragment.data, fragment.size));
+        assert!(!internet_packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+    for fragment in internet_fragments.iter() {
+        assert!(internet_packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+
+    // Packets from the other profile fail the crc32 check
+    assert_eq!(
+        internet_packet_buffer.num_invalid_crc32_packets,
+        lan_fragments.len() as u64
+    );
+
+    assert_eq!(lan_packet_buffer.receive_packets()[0].data, packet_data);
+    assert_eq!(
+        internet_packet_buffer.receive_packets()[0].data,
+        packet_data
+    );
+
+    // Too big for the internet profile
+    assert!(split_packet_into_fragments(&internet_config, 8, &[0; 512 * 256 + 1]).is_none());
+}
+
+#[test]
+pub fn test_fragment_parity() {
+    use crate::protocol::packets::fragment_packet::split_packet_into_fragments_with_parity;
+    use crate::protocol::protocol_config::ProtocolConfig;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let config = ProtocolConfig::default();
+    let packet_data: Vec<u8> = (0..10 * 1024 - 300).map(|i| (i % 253) as u8).collect();
+
+    // No parity fragments unless asked for
+    let fragments = split_packet_into_fragments(&config, 0, &packet_data).unwrap();
+    assert_eq!(fragments.len(), 10);
+
+    // 3 parity fragments for 10 fragments, parity fragments come after the fragments
+    let fragments = split_packet_into_fragments_with_parity(&config, 0, &packet_data, 0.3).unwrap();
+    assert_eq!(fragments.len(), 13);
+
+    // A burst of 3 lost fragments is rebuilt, one from each parity fragment
+    let mut packet_buffer = PacketBuffer::new(&config);
+    for (i, fragment) in fragments.iter().enumerate() {
+        if (3..6).contains(&i) {
+            continue;
+        }
+        assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+    assert_eq!(packet_buffer.num_recovered_fragments, 3);
+    let received_packets = packet_buffer.receive_packets();
+    assert_eq!(received_packets.len(), 1);
+    assert_eq!(received_packets[0].data, packet_data);
+    assert_eq!(packet_buffer.num_buffered_fragments, 0);
+
+    // The last fragment is shorter, it is rebuilt at its own size
+    let fragments = split_packet_into_fragments_with_parity(&config, 1, &packet_data, 0.3).unwrap();
+    for (i, fragment) in fragments.iter().enumerate() {
+        if i == 9 {
+            continue;
+        }
+        assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+    assert_eq!(packet_buffer.receive_packets()[0].data, packet_data);
+
+    // Two lost fragments covered by the same parity fragment can't be rebuilt
+    let fragments = split_packet_into_fragments_with_parity(&config, 2, &packet_data, 0.3).unwrap();
+    for (i, fragment) in fragments.iter().enumerate() {
+        if i == 0 || i == 3 {
+            continue;
+        }
+        assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
+    }
+    assert!(packet_buffer.receive_packets().is_empty());
+
+    // Duplicate parity fragments are ignored
+    assert!(!packet_buffer.process_packet(&fragments[10].data, fragments[10].size));
+
+    // With 10% loss, parity fragments save most of the packets that would otherwise be dropped
+    let mut rng = StdRng::seed_from_u64(20);
+    let mut num_received = [0, 0];
+    for (parity_index, parity_ratio) in [0.0, 0.25].into_iter().enumerate() {
+        let mut packet_buffer = PacketBuffer::new(&config);
+        for sequence in 100..300 {
+            let fragments = split_packet_into_fragments_with_parity(
+                &config,
+                sequence,
+                &packet_data,
+                parity_ratio,
+            )
+            .unwrap();
+            for fragment in fragments.iter() {
+                if rng.gen_range(0..100) >= 10 {
+                    packet_buffer.process_packet(&fragment.data, fragment.size);
+                }
+            }
+            for received_packet in packet_buffer.receive_packets() {
+                assert_eq!(received_packet.data, packet_data);
+                num_received[parity_index] += 1;
+            }
+        }
+    }
+    assert!(num_received[1] > num_received[0] + 50);
+}
+
+#[test]
+pub fn test_packet_buffer_first_sequence() {
+    use crate::protocol::protocol_config::ProtocolConfig;
+
+    let config = ProtocolConfig::default();
+    let packet_data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+    // The sender can start anywhere, the first packet sets the current sequence
+    let mut packet_buffer = PacketBuffer::new(&config);
+    for sequence in [40000, 40001, 39999] {
+        for fragment in split_packet_into_fragments(&config, sequence, &packet_data).unwrap() {
+            assert!(packet_buffer.process_packet(&fragment.data, fragment.size));
+        }
+    }
+    assert_eq!(packet_buffer.current_sequence(), 40001);
+    assert_eq!(packet_buffer.receive_packets().len(), 3);
+
+    // After that, sequences wildly out of range of the current sequence are still discarded
+    let fragments = split_packet_into_fragments(&config, 40001 + 2000, &packet_data).unwrap();
+    assert!(!packet_buffer.process_packet(&fragments[0].data, fragments[0].size));
+    let fragments = split_packet_into_fragments(&config, 0, &packet_data).unwrap();
+    assert!(!packet_buffer.process_packet(&fragments[0].data, fragments[0].size));
+    assert_eq!(packet_buffer.current_sequence(), 40001);
+}
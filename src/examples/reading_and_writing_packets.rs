@@ -1,45 +1,41 @@
 /*
     NOTE: DONT DELETE
-    - We use the macro to delcare the 'Object' methods when we can use the packet.Serialize
-    - Otherwise we declare them ourselves
+    - Every packet implements 'Object' with a single serialize<S: Stream> method
+    - That one method runs against read, write and measure streams
 
-    How we choose from above depends on whether or not we need to separate the read and write serialize functions, or whether they just call .Serialize
-
-    We either implement the object methods or write a Serialzie method in the packet struct and then fulfil Object with the macro.
-
-    "Packet" interface does need a Serialize func, we just implement one if we want all of the Object methods to do the same thing.
+    If the read and write paths need to differ (like TestPacketB below), branch on stream.is_reading() / stream.is_writing()
+    inside serialize rather than writing separate functions per stream.
 */
 
 /*
     NOTE: DONT DELETE
     Recapping...
 
-    Packet trait implements Object
-        Object has:
-            - serialize_w
-            - serialise_r
+    Packet trait implements DynObject
+        DynObject has:
+            - serialize_dyn(&mut dyn Stream), implemented for every Object
 
     Packets are passed to write_packet method
-    - Write packet does not care about the packet type, it just creates a write_stream and calls the packet.serialize_w method with the correct args.
+    - Write packet does not care about the packet type, it just creates a write_stream and calls packet.serialize_dyn with it.
 */
 
+use bitpacker_derive::Serialize;
 use rand::random;
 
-use crate::{
-    impl_object_for_packet,
-    protocol::{
-        constants::{Buffer, ProtocolError, MAX_PACKET_SIZE},
-        helpers::get_error_string,
-        packets::{
-            self,
-            object::{Object, Packet},
-            packet_factory::PacketFactory,
-            packet_info::PacketInfo,
-            read_packet,
-        },
-        serialization::*,
-        streams::{read_stream::ReadStream, write_stream::WriteStream, Stream},
+use crate::{read_object_index, serialize_int, write_object_index};
+
+use crate::protocol::{
+    constants::{Buffer, MAX_PACKET_SIZE},
+    packets::{
+        self,
+        object::{Object, Packet},
+        packet_factory::PacketFactory,
+        packet_info::PacketInfo,
+        read_packet,
     },
+    protocol_error::ProtocolErrorKind,
+    serialization::*,
+    streams::{write_stream::WriteStream, Stream},
 };
 
 const NUM_ITERATIONS: u32 = 100;
@@ -61,7 +57,8 @@ enum TestPacketTypes {
     NumTypes = 2,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[packet_type(TestPacketTypes::A)]
 pub struct TestPacketA {
     a: u32,
     b: u32,
@@ -70,29 +67,14 @@ pub struct TestPacketA {
 
 impl TestPacketA {
     fn new() -> TestPacketA {
-        return TestPacketA {
+        TestPacketA {
             a: random::<u32>(),
             b: random::<u32>(),
             c: random::<f32>(),
-        };
-    }
-
-    fn serialize(&mut self, stream: &mut dyn Stream) -> bool {
-        serialize_int_macro(stream, &mut (self.a as i32), i32::MIN, i32::MAX);
-        serialize_int_macro(stream, &mut (self.b as i32), i32::MIN, i32::MAX);
-        serialize_float_macro(stream, &mut self.c);
-        true
-    }
-}
-
-impl Packet for TestPacketA {
-    fn get_packet_type(&self) -> u32 {
-        TestPacketTypes::A as u32
+        }
     }
 }
 
-impl_object_for_packet!(TestPacketA);
-
 #[derive(PartialEq)]
 pub struct TestPacketB {
     scene: SceneA,
@@ -121,51 +103,48 @@ impl Packet for TestPacketB {
 }
 
 impl Object for TestPacketB {
-    fn serialize_internal_r(&mut self, stream: &mut ReadStream) -> bool {
-        read_scene_a(stream, &mut self.scene);
-        true
-    }
-    fn serialize_internal_w(&mut self, stream: &mut WriteStream) -> bool {
-        write_scene_a(stream, &mut self.scene);
-        true
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        if stream.is_reading() {
+            read_scene_a(stream, &mut self.scene)
+        } else {
+            write_scene_a(stream, &mut self.scene)
+        }
     }
 }
 
-fn write_scene_a(stream: &mut WriteStream, scene: &mut SceneA) -> bool {
+fn write_scene_a(stream: &mut dyn Stream, scene: &mut SceneA) -> bool {
     let mut previous_index = -1;
 
     for i in 0..scene.objects.len() {
-        if !scene.objects[i as usize].send {
+        if !scene.objects[i].send {
             continue;
         }
-        write_object_index_macro(stream, &mut previous_index, i as i32);
+        write_object_index!(stream, previous_index, i as i32);
         // Write object
-        serialize_int_macro(stream, &mut scene.objects[i as usize].a, i32::MIN, i32::MAX);
+        serialize_int!(stream, scene.objects[i].a, i32::MIN, i32::MAX);
     }
 
     // Write sentinel value
-    write_object_index_macro(stream, &mut previous_index, MAX_OBJECTS as i32);
+    write_object_index!(stream, previous_index, MAX_OBJECTS as i32);
     true
 }
 
-fn read_scene_a(stream: &mut ReadStream, scene: &mut SceneA) -> bool {
+fn read_scene_a(stream: &mut dyn Stream, scene: &mut SceneA) -> bool {
     let mut previous_index = -1;
 
     loop {
         let mut index = 0;
-        read_object_index_macro(stream, &mut previous_index, &mut index);
+        read_object_index!(stream, previous_index, index);
         if index == MAX_OBJECTS as i32 {
             // When we hit 'sentinel' value
             break;
         }
+        if index as usize >= scene.objects.len() {
+            return false;
+        }
 
         // Read object
-        serialize_int_macro(
-            stream,
-            &mut scene.objects[index as usize].a,
-            i32::MIN,
-            i32::MAX,
-        );
+        serialize_int!(stream, scene.objects[index as usize].a, i32::MIN, i32::MAX);
     }
 
     true
@@ -203,7 +182,7 @@ impl PacketFactory for TestPacketFactory {
 
 #[test]
 pub fn test() {
-    return;
+    use crate::protocol::protocol_config::ProtocolConfig;
 
     let packet_factory: TestPacketFactory = TestPacketFactory {
         num_allocated_packets: 0,
@@ -217,48 +196,246 @@ pub fn test() {
         assert!(write_packet.get_packet_type() == packet_type);
 
         let mut buffer: Buffer = vec![0; MAX_PACKET_SIZE];
-        let mut error: bool = false;
 
         let info: PacketInfo = PacketInfo {
             raw_format: false,
             prefix_bytes: 4,
-            protocol_id: u32::MAX,
+            config: ProtocolConfig::builder()
+                .protocol_id(u32::MAX)
+                .build()
+                .unwrap(),
             allowed_packet_types: vec![TestPacketTypes::A as u32, TestPacketTypes::B as u32],
             packet_factory: &packet_factory,
+            context: None,
         };
 
         let bytes_written = packets::write_packet(
             &info,
             write_packet.as_mut(),
             &mut buffer,
-            MAX_PACKET_SIZE as usize,
+            MAX_PACKET_SIZE,
             None,
         );
 
-        if bytes_written > 0 {
-            println!(
-                "Wrote packet type {} ({} bytes)",
-                write_packet.get_packet_type(),
-                bytes_written
-            );
-        } else {
-            println!("Write packet error. Didnt write any bytes.");
-            error = true;
-        }
+        assert!(
+            bytes_written > 0,
+            "Write packet error. Didnt write any bytes."
+        );
+        println!(
+            "Wrote packet type {} ({} bytes)",
+            write_packet.get_packet_type(),
+            bytes_written
+        );
 
-        let mut error: ProtocolError = ProtocolError::None;
-        let read_packet = read_packet(&info, &mut buffer, None, &mut error);
+        let read_packet = read_packet(&info, &buffer[..bytes_written as usize], None);
         match read_packet {
-            Some(packet) => {
+            Ok(packet) => {
                 println!(
                     "Read packet type {} ({} bytes)",
                     packet.get_packet_type(),
                     bytes_written
                 );
             }
-            None => {
-                println!("Packet read error {:?}", get_error_string(error));
+            Err(error) => {
+                panic!("Packet read error: {}", error);
             }
         }
     }
 }
+
+#[test]
+pub fn test_read_errors() {
+    use crate::protocol::protocol_config::ProtocolConfig;
+    use std::error::Error;
+
+    let packet_factory: TestPacketFactory = TestPacketFactory {
+        num_allocated_packets: 0,
+        num_packet_types: TestPacketTypes::NumTypes as u32,
+    };
+
+    // Raw format, so the damaged packets below get past the crc32 and into the packet itself
+    let info: PacketInfo = PacketInfo {
+        raw_format: true,
+        prefix_bytes: 0,
+        config: ProtocolConfig::builder()
+            .protocol_id(u32::MAX)
+            .build()
+            .unwrap(),
+        allowed_packet_types: vec![TestPacketTypes::A as u32],
+        packet_factory: &packet_factory,
+        context: None,
+    };
+
+    let mut write_packet = TestPacketA::new();
+    let mut buffer: Buffer = vec![0; 64];
+    let bytes_written =
+        packets::write_packet(&info, &mut write_packet, &mut buffer, 64, None) as usize;
+    assert!(bytes_written > 0);
+
+    // Truncated in the middle of the packet
+    let error = read_packet(&info, &buffer[..6], None).err().unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::StreamOverflow);
+    assert_eq!(error.packet_type, Some(TestPacketTypes::A as u32));
+    assert_eq!(error.bit_offset, error.bit_error.unwrap().bit_offset);
+    assert!(error.bit_offset > 0 && error.bit_offset < 48);
+    assert!(error.source().is_some());
+
+    // Damaged end of packet check
+    buffer[bytes_written - 1] ^= 0xFF;
+    let error = read_packet(&info, &buffer[..bytes_written], None)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::SerializeCheckFailed);
+    assert_eq!(error.field.as_deref(), Some("end of packet"));
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Serialize check failed in packet type 0 at check 'end of packet' at bit {}",
+            error.bit_offset
+        )
+    );
+
+    // Not an allowed packet type
+    let mut write_packet = TestPacketB::new();
+    let mut buffer: Buffer = vec![0; MAX_PACKET_SIZE];
+    packets::write_packet(&info, &mut write_packet, &mut buffer, MAX_PACKET_SIZE, None);
+    let error = read_packet(&info, &buffer, None).err().unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::PacketTypeNotAllowed);
+    assert_eq!(error.packet_type, Some(TestPacketTypes::B as u32));
+
+    // Corrupt packets, and packets from another protocol, fail the crc32 check instead of panicking
+    let mut info = info;
+    info.raw_format = false;
+    info.prefix_bytes = 2;
+    info.config = ProtocolConfig::builder()
+        .protocol_id(0x12345678)
+        .build()
+        .unwrap();
+
+    let mut write_packet = TestPacketA::new();
+    let mut buffer: Buffer = vec![0; 64];
+    let bytes_written =
+        packets::write_packet(&info, &mut write_packet, &mut buffer, 64, None) as usize;
+    assert!(read_packet(&info, &buffer[..bytes_written], None).is_ok());
+
+    buffer[bytes_written / 2] ^= 0x10;
+    let error = read_packet(&info, &buffer[..bytes_written], None)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::InvalidCrc32);
+    assert_eq!(error.bit_offset, 16);
+    buffer[bytes_written / 2] ^= 0x10;
+
+    info.config = ProtocolConfig::builder()
+        .protocol_id(0x12345679)
+        .build()
+        .unwrap();
+    let error = read_packet(&info, &buffer[..bytes_written], None)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::InvalidCrc32);
+
+    // Empty datagrams, and a factory without packet types, are errors too
+    let error = read_packet(&info, &[], None).err().unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::StreamOverflow);
+
+    let empty_packet_factory = TestPacketFactory {
+        num_allocated_packets: 0,
+        num_packet_types: 0,
+    };
+    info.raw_format = true;
+    info.packet_factory = &empty_packet_factory;
+    let error = read_packet(&info, &buffer[..bytes_written], None)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::InvalidPacketType);
+
+    // A packet type past the factory's last one, even when it is allowed, never reaches create_packet
+    let three_packet_factory = TestPacketFactory {
+        num_allocated_packets: 0,
+        num_packet_types: 3,
+    };
+    info.packet_factory = &three_packet_factory;
+    info.allowed_packet_types = vec![0, 1, 2, 3];
+    info.prefix_bytes = 0;
+    let mut buffer: Buffer = vec![0; 16];
+    {
+        let mut stream = WriteStream::new(&mut buffer);
+        let mut packet_type = 3;
+        assert!(stream.serialize_bits(&mut packet_type, 2));
+        assert!(stream.flush());
+    }
+    let error = read_packet(&info, &buffer, None).err().unwrap();
+    assert_eq!(error.kind, ProtocolErrorKind::InvalidPacketType);
+    assert_eq!(error.packet_type, Some(3));
+}
+
+#[test]
+pub fn test_write_errors() {
+    use crate::protocol::protocol_config::ProtocolConfig;
+
+    // A header that can never be serialized, like one holding an out of range value
+    struct BadHeader;
+
+    impl Object for BadHeader {
+        fn serialize<S: Stream>(&mut self, _stream: &mut S) -> bool {
+            false
+        }
+    }
+
+    let packet_factory: TestPacketFactory = TestPacketFactory {
+        num_allocated_packets: 0,
+        num_packet_types: TestPacketTypes::NumTypes as u32,
+    };
+
+    let mut info: PacketInfo = PacketInfo {
+        raw_format: false,
+        prefix_bytes: 0,
+        config: ProtocolConfig::builder()
+            .protocol_id(0x12345678)
+            .build()
+            .unwrap(),
+        allowed_packet_types: vec![TestPacketTypes::A as u32],
+        packet_factory: &packet_factory,
+        context: None,
+    };
+
+    let mut write_packet = TestPacketA::new();
+    let mut buffer: Buffer = vec![0; 64];
+
+    // Failed headers are reported the same way as failed packets
+    let bytes_written = packets::write_packet(
+        &info,
+        &mut write_packet,
+        &mut buffer,
+        64,
+        Some(&mut BadHeader),
+    );
+    assert_eq!(bytes_written, 0);
+    assert_eq!(
+        packets::measure_packet(&info, &mut write_packet, Some(&mut BadHeader)),
+        0
+    );
+
+    // Buffers that are empty, or longer than the length given, are rejected up front
+    assert_eq!(
+        packets::write_packet(&info, &mut write_packet, &mut [], 64, None),
+        0
+    );
+    assert_eq!(
+        packets::write_packet(&info, &mut write_packet, &mut buffer, 32, None),
+        0
+    );
+
+    // As are factories without any packet types
+    let empty_packet_factory = TestPacketFactory {
+        num_allocated_packets: 0,
+        num_packet_types: 0,
+    };
+    info.packet_factory = &empty_packet_factory;
+    assert_eq!(
+        packets::write_packet(&info, &mut write_packet, &mut buffer, 64, None),
+        0
+    );
+    assert_eq!(packets::measure_packet(&info, &mut write_packet, None), 0);
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+
+/** Reason a BitWriter or BitReader operation was rejected */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BitErrorKind {
+    Overflow,        // not enough bits left in the buffer
+    InvalidBitCount, // zero bits, or more bits than the call supports
+    NotAligned,      // byte operation attempted when not on a byte boundary
+    NonZeroPadding,  // alignment padding was read back as something other than zeros
+    BufferTooSmall,  // the caller's bytes are shorter than the number of bytes asked for
+}
+
+/** Error returned by the bitpacker instead of panicking on a bad read or write */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BitError {
+    pub kind: BitErrorKind,
+    pub bit_offset: u32, // bit position in the buffer where the operation started
+    pub requested_bits: u32, // number of bits the operation asked for
+    pub available_bits: u32, // number of bits left in the buffer at that point
+}
+
+impl BitError {
+    pub fn new(
+        kind: BitErrorKind,
+        bit_offset: u32,
+        requested_bits: u32,
+        available_bits: u32,
+    ) -> BitError {
+        BitError {
+            kind,
+            bit_offset,
+            requested_bits,
+            available_bits,
+        }
+    }
+}
+
+impl fmt::Display for BitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            BitErrorKind::Overflow => "buffer overflow",
+            BitErrorKind::InvalidBitCount => "invalid bit count",
+            BitErrorKind::NotAligned => "not byte aligned",
+            BitErrorKind::NonZeroPadding => "non-zero alignment padding",
+            BitErrorKind::BufferTooSmall => "byte buffer too small",
+        };
+        write!(
+            f,
+            "{} at bit {} (requested {} bits, {} available)",
+            reason, self.bit_offset, self.requested_bits, self.available_bits
+        )
+    }
+}
+
+impl std::error::Error for BitError {}
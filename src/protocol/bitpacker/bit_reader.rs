@@ -1,6 +1,6 @@
-use crate::protocol::constants::Buffer;
+use super::bit_error::{BitError, BitErrorKind};
 
-fn print_word(bytes: &Vec<u8>, idx: usize) {
+fn print_word(bytes: &[u8], idx: usize) {
     println!(
         "BUFFER: {:#010b} {:#010b} {:#010b} {:#010b}",
         bytes[idx],
@@ -11,7 +11,7 @@ fn print_word(bytes: &Vec<u8>, idx: usize) {
 }
 
 pub struct BitReader<'a> {
-    buffer: &'a Buffer,     // Pointer to buffer
+    buffer: &'a [u8],       // Buffer being read from
     scratch: u64,           // 64 bit scratch buffer
     pub scratch_bits: u32,  // Number of bits in scratch buffer
     num_bits: u32,          // Bits in buffer
@@ -19,21 +19,26 @@ pub struct BitReader<'a> {
     pub word_index: u32,    // Current word index
 }
 
-impl BitReader<'_> {
-    pub fn new(buffer: &mut Buffer, bytes: usize) -> BitReader {
-        assert!(buffer.len() % 4 == 0, "bytes must be a multiple of 4");
-        return BitReader {
+impl<'a> BitReader<'a> {
+    /** Reads from any length of buffer. A trailing partial word is read as if padded with zeros */
+    pub fn new(buffer: &'a [u8]) -> BitReader<'a> {
+        BitReader {
             scratch: 0,
             scratch_bits: 0,
-            num_bits: (bytes as u32) * 8,
+            num_bits: (buffer.len() as u32) * 8,
             num_bits_read: 0,
             word_index: 0,
             buffer,
-        };
+        }
+    }
+
+    /** Builds an error describing a failed read of 'bits' bits at the current position */
+    fn error(&self, kind: BitErrorKind, bits: u32) -> BitError {
+        BitError::new(kind, self.num_bits_read, bits, self.get_bits_remaining())
     }
 
     pub fn get_word_index(&self) -> usize {
-        return self.word_index as usize;
+        self.word_index as usize
     }
 
     pub fn print_word(&self, idx: usize) {
@@ -42,58 +47,60 @@ impl BitReader<'_> {
 
     pub fn would_read_past_end(&self, bits: u32) -> bool {
         // Returns whether or not reading 'bits' bits would overflow the available bits to read
-        return self.num_bits_read + bits > self.num_bits;
+        self.num_bits_read + bits > self.num_bits
     }
 
     // Align self.bits_read to the nearest byte
-    pub fn read_align(&mut self) -> bool {
+    pub fn read_align(&mut self) -> Result<(), BitError> {
         let remainder_bits = self.num_bits_read % 8;
 
         if remainder_bits != 0 {
-            let val = self.read_bits(8 - remainder_bits);
-
-            if (self.num_bits_read % 8) != 0 {
-                return false;
-            }
+            let val = self.read_bits(8 - remainder_bits)?;
             if val != 0 {
-                return false;
+                return Err(BitError::new(
+                    BitErrorKind::NonZeroPadding,
+                    self.num_bits_read - (8 - remainder_bits),
+                    8 - remainder_bits,
+                    self.get_bits_remaining() + (8 - remainder_bits),
+                ));
             }
         }
 
-        return true;
+        Ok(())
     }
 
-    pub fn read_bits(&mut self, bits: u32) -> u32 {
-        assert!(bits > 0); // Read at least one bit
-        assert!(bits <= 32); // Read a maximum of 32 bits
-
-        // Only read up to number of bits
-        assert!(self.num_bits_read + bits <= self.num_bits);
+    /** Reads the word at word_index, zero padding a trailing partial word */
+    fn read_word(&self) -> u32 {
+        let start = (self.word_index * 4) as usize;
+        let end = usize::min(start + 4, self.buffer.len());
+        let mut word_bytes = [0; 4];
+        if start < end {
+            word_bytes[..end - start].copy_from_slice(&self.buffer[start..end]);
+        }
+        u32::from_le_bytes(word_bytes)
+    }
 
-        self.num_bits_read += bits;
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, BitError> {
+        // Read between 1 and 32 bits
+        if bits == 0 || bits > 32 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, bits));
+        }
 
-        // Check scratch_bits is in a valid range for a 64 bit number
-        assert!(self.scratch_bits <= 64);
+        // Only read up to number of bits
+        if self.would_read_past_end(bits) {
+            return Err(self.error(BitErrorKind::Overflow, bits));
+        }
 
         // If there aren't enough bits in scratch to read off the specified amount..
         if self.scratch_bits < bits {
-            // TODO: Can i do this without
             // Read a word from buffer into scratch, shifted left by bits in scratch
-            let word_index = (self.word_index * 4) as usize;
-            let word_bytes = [
-                self.buffer[word_index],
-                self.buffer[word_index + 1],
-                self.buffer[word_index + 2],
-                self.buffer[word_index + 3],
-            ];
-            let word = u32::from_le_bytes(word_bytes);
+            let word = self.read_word();
             self.scratch |= (word as u64) << self.scratch_bits;
             self.scratch_bits += 32;
             self.word_index += 1;
         }
 
-        // Check that theres enough bits in scratch to read specified amount
-        assert!(self.scratch_bits >= bits);
+        self.num_bits_read += bits;
 
         // Copy 'bits' number of bits from scratch into output variable
         let mask = u64::pow(2, bits) - 1;
@@ -104,23 +111,63 @@ impl BitReader<'_> {
         // Subtract number of bits read from scratch_bits
         self.scratch_bits -= bits;
 
-        output
+        Ok(output)
     }
 
-    pub fn read_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) {
+    /** Reads up to 64 bits, written by BitWriter::write_bits_u64 */
+    pub fn read_bits_u64(&mut self, bits: u32) -> Result<u64, BitError> {
+        if bits == 0 || bits > 64 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, bits));
+        }
+        if self.would_read_past_end(bits) {
+            return Err(self.error(BitErrorKind::Overflow, bits));
+        }
+
+        if bits <= 32 {
+            return Ok(self.read_bits(bits)? as u64);
+        }
+        let lo = self.read_bits(32)? as u64;
+        let hi = self.read_bits(bits - 32)? as u64;
+        Ok((hi << 32) | lo)
+    }
+
+    /** Reads up to 128 bits, written by BitWriter::write_bits_u128 */
+    pub fn read_bits_u128(&mut self, bits: u32) -> Result<u128, BitError> {
+        if bits == 0 || bits > 128 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, bits));
+        }
+        if self.would_read_past_end(bits) {
+            return Err(self.error(BitErrorKind::Overflow, bits));
+        }
+
+        if bits <= 64 {
+            return Ok(self.read_bits_u64(bits)? as u128);
+        }
+        let lo = self.read_bits_u64(64)? as u128;
+        let hi = self.read_bits_u64(bits - 64)? as u128;
+        Ok((hi << 64) | lo)
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8], num_bytes: u32) -> Result<(), BitError> {
+        let num_bits = num_bytes.saturating_mul(8);
+        if num_bytes == 0 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, 0));
+        }
+
         // Check we're aligned to byte
-        assert!(self.get_align_bits() == 0);
+        if self.get_align_bits() != 0 {
+            return Err(self.error(BitErrorKind::NotAligned, num_bits));
+        }
 
         // Check we have enough bits in buffer to actually read out num_bytes
-        assert!(self.num_bits_read + num_bytes * 8 <= self.num_bits);
+        if num_bits > self.get_bits_remaining() {
+            return Err(self.error(BitErrorKind::Overflow, num_bits));
+        }
 
-        // Double check we're byte aligned
-        assert!(
-            (self.num_bits_read % 32) == 0
-                || (self.num_bits_read % 32) == 8
-                || (self.num_bits_read % 32) == 16
-                || (self.num_bits_read % 32) == 24
-        );
+        // And somewhere to put them
+        if bytes.len() < num_bytes as usize {
+            return Err(self.error(BitErrorKind::BufferTooSmall, num_bits));
+        }
 
         // How many bytes avail in current word
         let mut head_bytes = (4 - (self.num_bits_read % 32) / 8) % 4;
@@ -131,24 +178,24 @@ impl BitReader<'_> {
             head_bytes = num_bytes;
         }
         for n in 0..head_bytes {
-            bytes[n as usize] = self.read_bits(8) as u8;
+            bytes[n as usize] = self.read_bits(8)? as u8;
         }
         if head_bytes == num_bytes {
-            return;
+            return Ok(());
         }
 
-        assert!(self.get_align_bits() == 0);
-
         // -- Reading words at a time --
 
         let num_words = (num_bytes - head_bytes) / 4;
         if num_words > 0 {
-            assert!((self.num_bits_read % 32) == 0);
-            unsafe {
-                let src_ptr = self.buffer.as_ptr().add((self.word_index * 4) as usize);
-                let dest_ptr = bytes.as_mut_ptr().add(head_bytes as usize);
-                std::ptr::copy_nonoverlapping(src_ptr, dest_ptr, (num_words * 4) as usize);
-            }
+            let src = (self.word_index * 4) as usize;
+            let dest = head_bytes as usize;
+            let len = (num_words * 4) as usize;
+            let words = match self.buffer.get(src..src + len) {
+                Some(words) => words,
+                None => return Err(self.error(BitErrorKind::Overflow, num_bytes * 8)),
+            };
+            bytes[dest..dest + len].copy_from_slice(words);
 
             self.num_bits_read += num_words * 32;
             self.word_index += num_words;
@@ -158,14 +205,12 @@ impl BitReader<'_> {
         // -- Reading tail --
         let tail_start = head_bytes + num_words * 4;
         let tail_bytes = num_bytes - tail_start;
-        assert!(tail_bytes < 4);
 
         for i in 0..tail_bytes {
-            bytes[(tail_start + i) as usize] = self.read_bits(8) as u8;
+            bytes[(tail_start + i) as usize] = self.read_bits(8)? as u8;
         }
 
-        assert!(self.get_align_bits() == 0);
-        assert!(head_bytes + num_words * 4 + tail_bytes == num_bytes);
+        Ok(())
     }
 
     pub fn get_bits_read(&self) -> u32 {
@@ -173,10 +218,10 @@ impl BitReader<'_> {
     }
 
     pub fn get_bits_remaining(&self) -> u32 {
-        return self.num_bits - self.num_bits_read;
+        self.num_bits - self.num_bits_read
     }
 
     pub fn get_align_bits(&self) -> u32 {
-        return (8 - (self.num_bits_read % 8)) % 8;
+        (8 - (self.num_bits_read % 8)) % 8
     }
 }
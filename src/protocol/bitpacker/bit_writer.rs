@@ -1,70 +1,84 @@
-use crate::protocol::constants::Buffer;
+use super::bit_error::{BitError, BitErrorKind};
 
 pub struct BitWriter<'a> {
-    buffer: &'a mut Buffer, // Pointer to buffer
-    scratch: u64,           // 64 bit scratch buffer
-    num_bits: u32,          // Bits in buffer
-    num_words: u32,         // Words in buffer
-    bits_written: u32,      // Number of bits written to buffer
-    word_index: u32,        // Current word index
-    scratch_bits: i32,      // Number of bits in scratch buffer
+    buffer: &'a mut [u8], // Buffer being written to
+    scratch: u64,         // 64 bit scratch buffer
+    num_bits: u32,        // Bits in buffer
+    bits_written: u32,    // Number of bits written to buffer
+    word_index: u32,      // Current word index
+    scratch_bits: i32,    // Number of bits in scratch buffer
 }
 
-impl BitWriter<'_> {
-    pub fn new(buffer: &mut Buffer, buffer_size: usize) -> BitWriter {
-        assert!(buffer_size % 4 == 0, "bytes must be a multiple of 4");
-        return BitWriter {
+impl<'a> BitWriter<'a> {
+    /** Writes into any length of buffer. A trailing partial word is only written as far as the buffer goes */
+    pub fn new(buffer: &'a mut [u8]) -> BitWriter<'a> {
+        BitWriter {
+            num_bits: (buffer.len() as u32) * 8,
             buffer,
             scratch: 0,
             scratch_bits: 0,
             word_index: 0,
             bits_written: 0,
-            num_words: (buffer_size as u32) / 4,
-            num_bits: (buffer_size as u32 / 4) * 32,
-        };
+        }
+    }
+
+    /** Builds an error describing a failed write of 'bits' bits at the current position */
+    fn error(&self, kind: BitErrorKind, bits: u32) -> BitError {
+        BitError::new(kind, self.bits_written, bits, self.get_bits_available())
     }
 
     /** Aligns bitwriter position to next byte */
-    pub fn write_align(&mut self) -> bool {
+    pub fn write_align(&mut self) -> Result<(), BitError> {
         let remainder_bits = self.bits_written % 8;
         if remainder_bits != 0 {
-            self.write_bits(0, 8 - remainder_bits);
-            return (self.bits_written % 8) == 0;
+            self.write_bits(0, 8 - remainder_bits)?;
+        }
+        Ok(())
+    }
+
+    /**
+        Copies the low 32 bits of scratch into the buffer at the current word.
+        The last word of a buffer that isn't a multiple of 4 only gets the bytes that fit,
+        the rest of the scratch is always zero there since we never write past num_bits.
+    */
+    fn write_word(&mut self) -> Result<(), BitError> {
+        let start = (self.word_index * 4) as usize;
+        if start >= self.buffer.len() {
+            return Err(self.error(BitErrorKind::Overflow, 32));
         }
-        return true;
+
+        let end = usize::min(start + 4, self.buffer.len());
+        let word = (self.scratch as u32).to_le_bytes();
+        self.buffer[start..end].copy_from_slice(&word[..end - start]);
+
+        self.scratch >>= 32;
+        self.scratch_bits -= 32;
+        self.word_index += 1;
+        Ok(())
     }
 
     /** Flush remaining bits in scratch into buffer */
-    pub fn flush(&mut self) {
+    pub fn flush(&mut self) -> Result<(), BitError> {
         if self.scratch_bits != 0 {
-            assert!(self.word_index < self.num_words);
-
-            // Copy scratch into buffer
-            let bytes = (self.scratch as u32).to_le_bytes();
-            for i in 0..4 {
-                self.buffer[((self.word_index * 4) as usize) + i] = bytes[i];
-            }
-
-            self.scratch >>= 32;
-            self.scratch_bits -= 32;
-            self.word_index += 1;
-        };
+            self.write_word()?;
+        }
+        Ok(())
     }
 
-    pub fn write_bits(&mut self, val_to_write: u32, num_bits: u32) {
-        assert!(num_bits > 0);
-        assert!(num_bits <= 32);
-        assert!(
-            self.bits_written + num_bits <= self.num_bits,
-            "Not enough space remaining in buffer."
-        );
+    pub fn write_bits(&mut self, val_to_write: u32, num_bits: u32) -> Result<(), BitError> {
+        if num_bits == 0 || num_bits > 32 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, num_bits));
+        }
+        if self.bits_written + num_bits > self.num_bits {
+            return Err(self.error(BitErrorKind::Overflow, num_bits));
+        }
 
         // Mask off value to specified precision
         let mask: u32 = (u64::pow(2, num_bits) - 1) as u32;
         let value = val_to_write & mask;
 
         // Add value to scratch, shifted left by amount of bits in scratch
-        self.scratch |= ((value as u64) << self.scratch_bits) as u64;
+        self.scratch |= (value as u64) << self.scratch_bits;
 
         // Increase scratch_bits by number of bits written
         self.scratch_bits += num_bits as i32;
@@ -77,117 +91,123 @@ impl BitWriter<'_> {
             - increment word index
         */
         if self.scratch_bits >= 32 {
-            assert!(self.word_index < self.num_words);
-            // println!("Scratch full: {:#066b}", self.scratch);
-            // println!("Adding to buffer: {:#034b}", self.scratch);
-            // println!("Buffer: {:#034b}", self.buffer[self.word_index as usize]);
-            // println!("Scratch bits: {:?}", self.scratch_bits);
+            self.write_word()?;
+        }
 
-            // Copy scratch into buffer
-            unsafe {
-                let src = (self.scratch as u32).to_le_bytes().as_ptr();
-                let dest = self.buffer.as_mut_ptr().add((self.word_index * 4) as usize);
-                std::ptr::copy_nonoverlapping(src, dest, 4);
-            }
+        self.bits_written += num_bits;
+        Ok(())
+    }
 
-            self.scratch >>= 32;
-            self.scratch_bits -= 32;
-            self.word_index += 1;
+    /** Writes up to 64 bits. Values wider than 32 bits go out as the low word followed by the high bits */
+    pub fn write_bits_u64(&mut self, val_to_write: u64, num_bits: u32) -> Result<(), BitError> {
+        if num_bits == 0 || num_bits > 64 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, num_bits));
+        }
+        if self.bits_written + num_bits > self.num_bits {
+            return Err(self.error(BitErrorKind::Overflow, num_bits));
         }
 
-        self.bits_written += num_bits;
+        if num_bits <= 32 {
+            return self.write_bits(val_to_write as u32, num_bits);
+        }
+        self.write_bits(val_to_write as u32, 32)?;
+        self.write_bits((val_to_write >> 32) as u32, num_bits - 32)
+    }
+
+    /** Writes up to 128 bits, low 64 bits first */
+    pub fn write_bits_u128(&mut self, val_to_write: u128, num_bits: u32) -> Result<(), BitError> {
+        if num_bits == 0 || num_bits > 128 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, num_bits));
+        }
+        if self.bits_written + num_bits > self.num_bits {
+            return Err(self.error(BitErrorKind::Overflow, num_bits));
+        }
+
+        if num_bits <= 64 {
+            return self.write_bits_u64(val_to_write as u64, num_bits);
+        }
+        self.write_bits_u64(val_to_write as u64, 64)?;
+        self.write_bits_u64((val_to_write >> 64) as u64, num_bits - 64)
     }
 
-    pub fn write_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool {
-        assert!(self.get_align_bits() == 0);
-        assert!(self.bits_written + num_bytes * 8 <= self.num_bits);
-        assert!(
-            (self.bits_written % 32) == 0
-                || (self.bits_written % 32) == 8
-                || (self.bits_written % 32) == 16
-                || (self.bits_written % 32) == 24
-        );
+    pub fn write_bytes(&mut self, bytes: &[u8], num_bytes: u32) -> Result<(), BitError> {
+        let num_bits = num_bytes.saturating_mul(8);
+        if num_bytes == 0 {
+            return Err(self.error(BitErrorKind::InvalidBitCount, 0));
+        }
+        if self.get_align_bits() != 0 {
+            return Err(self.error(BitErrorKind::NotAligned, num_bits));
+        }
+        if num_bits > self.get_bits_available() {
+            return Err(self.error(BitErrorKind::Overflow, num_bits));
+        }
+        if bytes.len() < num_bytes as usize {
+            return Err(self.error(BitErrorKind::BufferTooSmall, num_bits));
+        }
 
         // -- Writing leading word --
 
         // Number of bytes to fill word
         let mut head_bytes: u32 = (4 - (self.bits_written % 32) / 8) % 4;
 
-        // println!("HEAD BYTES {:?}", head_bytes);
-        // println!("{:#034b}", self.scratch);
-        // println!(".-----.");
-
         if head_bytes > num_bytes {
             head_bytes = num_bytes;
         }
         for i in 0..head_bytes {
-            self.write_bits(bytes[i as usize] as u32, 8);
+            self.write_bits(bytes[i as usize] as u32, 8)?;
         }
 
-        // println!(
-        //     "WROTE HEAD: {:#034b}",
-        //     self.buffer[(self.word_index - 1) as usize]
-        // );
-
         if head_bytes == num_bytes {
-            return true;
+            return Ok(());
         }
 
-        self.flush();
-        assert!(self.get_align_bits() == 0);
+        self.flush()?;
 
         // -- Writing in words at a time --
 
         let num_words: u32 = (num_bytes - head_bytes) / 4;
         if num_words > 0 {
-            assert!((self.bits_written % 32) == 0);
-            unsafe {
-                // COPY ALL THE WORDS at once into buffer.
-                let dest_ptr = self.buffer.as_mut_ptr().add((self.word_index * 4) as usize);
-                let src_ptr = bytes.as_ptr().add(head_bytes as usize);
-                std::ptr::copy_nonoverlapping(src_ptr, dest_ptr, (num_words * 4) as usize);
-            }
+            // COPY ALL THE WORDS at once into buffer.
+            let dest = (self.word_index * 4) as usize;
+            let src = head_bytes as usize;
+            let len = (num_words * 4) as usize;
+            self.buffer[dest..dest + len].copy_from_slice(&bytes[src..src + len]);
+
             self.bits_written += num_words * 32;
             self.word_index += num_words;
             self.scratch = 0;
         }
 
-        assert!(self.get_align_bits() == 0);
-
         // -- Writing tailing word --
 
         let tail_bytes_start = head_bytes + num_words * 4;
         let tail_bytes = num_bytes - tail_bytes_start;
 
-        assert!(tail_bytes < 4);
         for i in 0..tail_bytes {
-            self.write_bits(bytes[(tail_bytes_start + i) as usize] as u32, 8);
+            self.write_bits(bytes[(tail_bytes_start + i) as usize] as u32, 8)?;
         }
 
-        assert!(self.get_align_bits() == 0);
-        assert!(head_bytes + num_words * 4 + tail_bytes == num_bytes);
-
-        return true;
+        Ok(())
     }
 
     pub fn get_bits_written(&self) -> u32 {
-        return self.bits_written;
+        self.bits_written
     }
 
     pub fn get_bytes_written(&self) -> u32 {
         // Add the seven and divide to round up.
-        return (self.bits_written + 7) / 8;
+        self.bits_written.div_ceil(8)
     }
 
     pub fn get_total_bytes(&self) -> u32 {
-        self.num_words * 4
+        self.buffer.len() as u32
     }
 
     pub fn get_bits_available(&self) -> u32 {
-        return self.num_bits - self.bits_written;
+        self.num_bits - self.bits_written
     }
 
     pub fn get_align_bits(&self) -> u32 {
-        return (8 - (self.bits_written % 8)) % 8;
+        (8 - (self.bits_written % 8)) % 8
     }
 }
@@ -1,6 +1,9 @@
 use super::constants::Buffer;
-use crate::protocol::bitpacker::{bit_reader::BitReader, bit_writer::BitWriter};
+use crate::protocol::bitpacker::{
+    bit_error::BitErrorKind, bit_reader::BitReader, bit_writer::BitWriter,
+};
 
+pub mod bit_error;
 pub mod bit_reader;
 pub mod bit_writer;
 
@@ -12,24 +15,24 @@ fn test_bitpacker() {
     let bytes_written: u32;
 
     {
-        let mut writer = BitWriter::new(&mut buffer, buffer_size);
+        let mut writer = BitWriter::new(&mut buffer);
         assert!(writer.get_bits_written() == 0);
         assert!(writer.get_bytes_written() == 0);
         assert!(writer.get_total_bytes() == buffer_size as u32);
         assert!(writer.get_bits_available() == (buffer_size as u32) * 8);
 
-        writer.write_bits(0, 1);
-        writer.write_bits(1, 1);
-        writer.write_bits(10, 8);
-        writer.write_bits(255, 8);
-        writer.write_bits(1000, 10);
-        writer.write_bits(50000, 16);
-        writer.write_bits(9999999, 32);
-        writer.write_align(); // Write align before writing bytes
+        writer.write_bits(0, 1).unwrap();
+        writer.write_bits(1, 1).unwrap();
+        writer.write_bits(10, 8).unwrap();
+        writer.write_bits(255, 8).unwrap();
+        writer.write_bits(1000, 10).unwrap();
+        writer.write_bits(50000, 16).unwrap();
+        writer.write_bits(9999999, 32).unwrap();
+        writer.write_align().unwrap(); // Write align before writing bytes
 
-        let mut bytes: Vec<u8> = vec![5, 20, 255];
-        writer.write_bytes(&mut bytes, 3);
-        writer.flush();
+        let bytes: Vec<u8> = vec![5, 20, 255];
+        writer.write_bytes(&bytes, 3).unwrap();
+        writer.flush().unwrap();
 
         // All values + padding + bytes
         bits_written = 1 + 1 + 8 + 8 + 10 + 16 + 32 + 4 + (bytes.len() as u32 * 8); // 76
@@ -42,22 +45,22 @@ fn test_bitpacker() {
     }
 
     {
-        let mut reader = BitReader::new(&mut buffer, bytes_written as usize);
+        let mut reader = BitReader::new(&buffer[..bytes_written as usize]);
 
         assert_eq!(reader.get_bits_read(), 0);
         assert_eq!(reader.get_bits_remaining(), bytes_written * 8);
 
-        let a = reader.read_bits(1);
-        let b = reader.read_bits(1);
-        let c = reader.read_bits(8);
-        let d = reader.read_bits(8);
-        let e = reader.read_bits(10);
-        let f = reader.read_bits(16);
-        let g = reader.read_bits(32);
-        reader.read_align();
+        let a = reader.read_bits(1).unwrap();
+        let b = reader.read_bits(1).unwrap();
+        let c = reader.read_bits(8).unwrap();
+        let d = reader.read_bits(8).unwrap();
+        let e = reader.read_bits(10).unwrap();
+        let f = reader.read_bits(16).unwrap();
+        let g = reader.read_bits(32).unwrap();
+        reader.read_align().unwrap();
 
         let mut bytes: Vec<u8> = vec![0; 3];
-        reader.read_bytes(&mut bytes, 3);
+        reader.read_bytes(&mut bytes, 3).unwrap();
 
         assert_eq!(a, 0);
         assert_eq!(b, 1);
@@ -78,3 +81,163 @@ fn test_bitpacker() {
         );
     }
 }
+
+#[test]
+fn test_bitpacker_errors() {
+    let buffer_size: usize = 8;
+    let mut buffer: Buffer = vec![0; buffer_size];
+
+    {
+        let mut writer = BitWriter::new(&mut buffer);
+        assert_eq!(
+            writer.write_bits(0, 0).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        assert_eq!(
+            writer.write_bits(0, 33).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+
+        writer.write_bits(u32::MAX, 32).unwrap();
+        writer.write_bits(255, 30).unwrap();
+
+        let error = writer.write_bits(7, 3).unwrap_err();
+        assert_eq!(error.kind, BitErrorKind::Overflow);
+        assert_eq!(error.bit_offset, 62);
+        assert_eq!(error.requested_bits, 3);
+        assert_eq!(error.available_bits, 2);
+
+        // Failed writes leave the writer untouched
+        assert_eq!(writer.get_bits_written(), 62);
+        assert_eq!(
+            writer.write_bytes(&[1, 2], 2).unwrap_err().kind,
+            BitErrorKind::NotAligned
+        );
+        writer.flush().unwrap();
+    }
+
+    {
+        // Byte counts that don't match the caller's bytes
+        let mut bytes_buffer: Buffer = vec![0; buffer_size];
+        let mut writer = BitWriter::new(&mut bytes_buffer);
+        assert_eq!(
+            writer.write_bytes(&[], 0).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        assert_eq!(
+            writer.write_bytes(&[1, 2], 3).unwrap_err().kind,
+            BitErrorKind::BufferTooSmall
+        );
+        assert_eq!(writer.get_bits_written(), 0);
+
+        let mut reader = BitReader::new(&buffer);
+        let mut bytes = [0; 2];
+        assert_eq!(
+            reader.read_bytes(&mut bytes, 0).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        assert_eq!(
+            reader.read_bytes(&mut bytes, 3).unwrap_err().kind,
+            BitErrorKind::BufferTooSmall
+        );
+        assert_eq!(reader.get_bits_read(), 0);
+    }
+
+    {
+        let mut reader = BitReader::new(&buffer);
+        assert_eq!(reader.read_bits(32).unwrap(), u32::MAX);
+        assert_eq!(reader.read_bits(3).unwrap(), 7);
+        assert_eq!(
+            reader.read_align().unwrap_err().kind,
+            BitErrorKind::NonZeroPadding
+        );
+
+        let mut bytes: Vec<u8> = vec![0; 4];
+        let error = reader.read_bytes(&mut bytes, 4).unwrap_err();
+        assert_eq!(error.kind, BitErrorKind::Overflow);
+        assert_eq!(error.bit_offset, 40);
+        assert_eq!(error.requested_bits, 32);
+        assert_eq!(error.available_bits, 24);
+        assert_eq!(
+            reader.read_bits(25).unwrap_err().kind,
+            BitErrorKind::Overflow
+        );
+    }
+}
+
+#[test]
+fn test_bitpacker_unaligned_buffer() {
+    // 7 bytes, so the second word only partially exists in the buffer
+    let mut buffer = [0xAA_u8; 7];
+    let bytes: [u8; 5] = [1, 2, 3, 4, 5];
+
+    {
+        let mut writer = BitWriter::new(&mut buffer);
+        assert_eq!(writer.get_total_bytes(), 7);
+        assert_eq!(writer.get_bits_available(), 56);
+
+        writer.write_bits(3, 2).unwrap();
+        writer.write_align().unwrap();
+        writer.write_bytes(&bytes, 5).unwrap();
+        writer.write_bits(0xff, 8).unwrap();
+        assert_eq!(
+            writer.write_bits(1, 1).unwrap_err().kind,
+            BitErrorKind::Overflow
+        );
+        writer.flush().unwrap();
+        assert_eq!(writer.get_bits_written(), 56);
+    }
+
+    let reader_buffer: &[u8] = &buffer;
+    let mut reader = BitReader::new(reader_buffer);
+    assert_eq!(reader.get_bits_remaining(), 56);
+    assert_eq!(reader.read_bits(2).unwrap(), 3);
+    reader.read_align().unwrap();
+
+    let mut read_bytes = [0_u8; 5];
+    reader.read_bytes(&mut read_bytes, 5).unwrap();
+    assert_eq!(read_bytes, bytes);
+    assert_eq!(reader.read_bits(8).unwrap(), 0xff);
+    assert_eq!(
+        reader.read_bits(1).unwrap_err().kind,
+        BitErrorKind::Overflow
+    );
+}
+
+#[test]
+fn test_bitpacker_wide_values() {
+    // 333 bits of values, leaving 19 bits spare
+    let mut buffer: Buffer = vec![0; 44];
+
+    {
+        let mut writer = BitWriter::new(&mut buffer);
+        writer.write_bits(1, 3).unwrap();
+        writer.write_bits_u64(u64::MAX, 64).unwrap();
+        writer.write_bits_u64(0xFFFF_1234_5678_9ABC, 48).unwrap(); // Masked to 48 bits
+        writer.write_bits_u64(5, 20).unwrap();
+        writer.write_bits_u128(u128::MAX - 1, 128).unwrap();
+        writer.write_bits_u128((0xF << 68) | 1, 70).unwrap(); // Masked to 70 bits
+        assert_eq!(
+            writer.write_bits_u64(0, 65).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        assert_eq!(
+            writer.write_bits_u128(0, 129).unwrap_err().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        writer.flush().unwrap();
+    }
+
+    let mut reader = BitReader::new(&buffer);
+    assert_eq!(reader.read_bits(3).unwrap(), 1);
+    assert_eq!(reader.read_bits_u64(64).unwrap(), u64::MAX);
+    assert_eq!(reader.read_bits_u64(48).unwrap(), 0x1234_5678_9ABC);
+    assert_eq!(reader.read_bits_u64(20).unwrap(), 5);
+    assert_eq!(reader.read_bits_u128(128).unwrap(), u128::MAX - 1);
+    assert_eq!(reader.read_bits_u128(70).unwrap(), (0x3 << 68) | 1);
+
+    // Not enough bits left for another 128 bit value
+    let error = reader.read_bits_u128(128).unwrap_err();
+    assert_eq!(error.kind, BitErrorKind::Overflow);
+    assert_eq!(error.requested_bits, 128);
+}
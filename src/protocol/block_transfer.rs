@@ -0,0 +1,580 @@
+use std::collections::VecDeque;
+
+use super::{
+    packets::object::{Object, Packet},
+    streams::Stream,
+};
+use crate::{serialize_align, serialize_bits, serialize_bytes, serialize_int};
+
+/** Settings for sending blocks. Both ends must use the same slice size and slice limit. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BlockConfig {
+    pub slice_size: usize, // bytes in every slice but the last, at least 2
+    pub max_slices_per_block: usize, // at least 2. largest block is slice_size * max_slices_per_block bytes
+    pub slice_resend_time: f64,      // seconds before an unacked slice is sent again
+}
+
+impl BlockConfig {
+    /** Largest block that can be sent */
+    pub fn max_block_size(&self) -> usize {
+        self.slice_size * self.max_slices_per_block
+    }
+}
+
+impl Default for BlockConfig {
+    fn default() -> Self {
+        BlockConfig {
+            slice_size: 1024,
+            max_slices_per_block: 8192,
+            slice_resend_time: 0.1,
+        }
+    }
+}
+
+// slice packet on-the-wire format:
+// [block id] (16 bits) | [num slices - 1] | [slice id] (if more than one slice) | [slice bytes - 1]
+// (pad zero bits to nearest byte) | <slice data>
+pub struct SlicePacket {
+    // input
+    pub config: BlockConfig, // slice limits, must match the config of the other end
+
+    // serialized data
+    pub packet_type: u32,
+    pub block_id: u16,
+    pub slice_id: u32,
+    pub num_slices: u32, // 1 to config.max_slices_per_block
+    pub slice_data: Vec<u8>,
+}
+
+impl SlicePacket {
+    pub fn new(packet_type: u32, config: &BlockConfig) -> SlicePacket {
+        SlicePacket {
+            config: *config,
+            packet_type,
+            block_id: 0,
+            slice_id: 0,
+            num_slices: 0,
+            slice_data: vec![],
+        }
+    }
+}
+
+impl Packet for SlicePacket {
+    fn get_packet_type(&self) -> u32 {
+        self.packet_type
+    }
+}
+
+impl Object for SlicePacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        let mut block_id = self.block_id as u32;
+        serialize_bits!(stream, block_id, 16);
+        self.block_id = block_id as u16;
+
+        let mut num_slices = self.num_slices as i32;
+        serialize_int!(
+            stream,
+            num_slices,
+            1,
+            self.config.max_slices_per_block as i32
+        );
+        self.num_slices = num_slices as u32;
+
+        // a block with one slice doesn't need a slice id
+        let mut slice_id = self.slice_id as i32;
+        if num_slices > 1 {
+            serialize_int!(stream, slice_id, 0, num_slices - 1);
+        } else {
+            slice_id = 0;
+        }
+        self.slice_id = slice_id as u32;
+
+        let mut slice_bytes = self.slice_data.len() as i32;
+        serialize_int!(stream, slice_bytes, 1, self.config.slice_size as i32);
+
+        serialize_align!(stream);
+
+        if stream.is_reading() {
+            if slice_bytes as u32 * 8 > stream.get_bits_remaining() {
+                return false;
+            }
+            self.slice_data = vec![0; slice_bytes as usize];
+        }
+
+        serialize_bytes!(stream, self.slice_data, slice_bytes as u32);
+        true
+    }
+}
+
+// ack packet on-the-wire format:
+// [block id] (16 bits) | [num slices - 1] | [acked] (1 bit per slice)
+pub struct SliceAckPacket {
+    // input
+    pub config: BlockConfig, // slice limits, must match the config of the other end
+
+    // serialized data
+    pub packet_type: u32,
+    pub block_id: u16,
+    pub acked: Vec<bool>, // one entry per slice in the block, true if the slice was received
+}
+
+impl SliceAckPacket {
+    pub fn new(packet_type: u32, config: &BlockConfig) -> SliceAckPacket {
+        SliceAckPacket {
+            config: *config,
+            packet_type,
+            block_id: 0,
+            acked: vec![],
+        }
+    }
+}
+
+impl Packet for SliceAckPacket {
+    fn get_packet_type(&self) -> u32 {
+        self.packet_type
+    }
+}
+
+impl Object for SliceAckPacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        let mut block_id = self.block_id as u32;
+        serialize_bits!(stream, block_id, 16);
+        self.block_id = block_id as u16;
+
+        let mut num_slices = self.acked.len() as i32;
+        serialize_int!(
+            stream,
+            num_slices,
+            1,
+            self.config.max_slices_per_block as i32
+        );
+
+        if stream.is_reading() {
+            self.acked = vec![false; num_slices as usize];
+        }
+
+        // packed 32 slices at a time, bit n of each word is slice n within it
+        for word_slices in self.acked.chunks_mut(32) {
+            let mut word: u32 = 0;
+            for (i, acked) in word_slices.iter().enumerate() {
+                if *acked {
+                    word |= 1 << i;
+                }
+            }
+            serialize_bits!(stream, word, word_slices.len() as u32);
+            for (i, acked) in word_slices.iter_mut().enumerate() {
+                *acked = word & (1 << i) != 0;
+            }
+        }
+
+        true
+    }
+}
+
+struct SendBlock {
+    block_id: u16,
+    data: Vec<u8>,
+    acked: Vec<bool>,                 // one entry per slice
+    time_last_sent: Vec<Option<f64>>, // None until the slice is first sent
+    num_acked_slices: usize,
+}
+
+/**
+    Sends one large block at a time, split into slices.
+
+    Slices are sent with generate_slice_packets and resent every slice_resend_time until the
+    receiver acks them, so unlike fragments, losing a slice only costs that slice.
+    Once every slice is acked the next block can be sent.
+*/
+pub struct BlockSender {
+    config: BlockConfig,
+    time: f64,
+    block_id: u16, // id of the next block sent
+    block: Option<SendBlock>,
+
+    pub num_slices_sent: u64, // including resends
+    pub num_blocks_sent: u64, // blocks with every slice acked
+}
+
+impl BlockSender {
+    pub fn new(config: &BlockConfig) -> BlockSender {
+        // both are serialized with serialize_int, which needs a range
+        assert!(config.slice_size > 1 && config.max_slices_per_block > 1);
+
+        BlockSender {
+            config: *config,
+            time: 0.0,
+            block_id: 0,
+            block: None,
+            num_slices_sent: 0,
+            num_blocks_sent: 0,
+        }
+    }
+
+    /** True while a block has slices the receiver hasn't acked */
+    pub fn is_sending(&self) -> bool {
+        self.block.is_some()
+    }
+
+    /** Moves the sender's clock forward, in seconds */
+    pub fn advance_time(&mut self, time: f64) {
+        self.time = time;
+    }
+
+    /**
+        Starts sending a block. Returns false if a block is still being sent,
+        or the block is empty or bigger than config.max_block_size().
+    */
+    pub fn send_block(&mut self, data: Vec<u8>) -> bool {
+        if self.is_sending() || data.is_empty() || data.len() > self.config.max_block_size() {
+            return false;
+        }
+
+        let num_slices = data.len().div_ceil(self.config.slice_size);
+        self.block = Some(SendBlock {
+            block_id: self.block_id,
+            data,
+            acked: vec![false; num_slices],
+            time_last_sent: vec![None; num_slices],
+            num_acked_slices: 0,
+        });
+        self.block_id = self.block_id.wrapping_add(1);
+        true
+    }
+
+    /**
+        Slices to send now: unacked slices that were never sent, or not sent for slice_resend_time.
+        At most max_packets are returned, lowest slice id first.
+    */
+    pub fn generate_slice_packets(
+        &mut self,
+        packet_type: u32,
+        max_packets: usize,
+    ) -> Vec<SlicePacket> {
+        let Some(block) = self.block.as_mut() else {
+            return vec![];
+        };
+
+        let mut packets = vec![];
+        let num_slices = block.acked.len();
+        for slice_id in 0..num_slices {
+            if packets.len() >= max_packets {
+                break;
+            }
+
+            let resend = block.time_last_sent[slice_id].is_none_or(|time_last_sent| {
+                time_last_sent + self.config.slice_resend_time <= self.time
+            });
+            if block.acked[slice_id] || !resend {
+                continue;
+            }
+
+            let start = slice_id * self.config.slice_size;
+            let end = (start + self.config.slice_size).min(block.data.len());
+            let mut packet = SlicePacket::new(packet_type, &self.config);
+            packet.block_id = block.block_id;
+            packet.slice_id = slice_id as u32;
+            packet.num_slices = num_slices as u32;
+            packet.slice_data = block.data[start..end].to_vec();
+            packets.push(packet);
+
+            block.time_last_sent[slice_id] = Some(self.time);
+            self.num_slices_sent += 1;
+        }
+
+        packets
+    }
+
+    /** Marks the slices the receiver acked. When every slice is acked the block is done. */
+    pub fn process_ack_packet(&mut self, packet: &SliceAckPacket) {
+        let Some(block) = self.block.as_mut() else {
+            return;
+        };
+        if packet.block_id != block.block_id || packet.acked.len() != block.acked.len() {
+            return;
+        }
+
+        for (slice_id, acked) in packet.acked.iter().enumerate() {
+            if *acked && !block.acked[slice_id] {
+                block.acked[slice_id] = true;
+                block.num_acked_slices += 1;
+            }
+        }
+
+        if block.num_acked_slices == block.acked.len() {
+            self.block = None;
+            self.num_blocks_sent += 1;
+        }
+    }
+}
+
+struct ReceiveBlock {
+    num_slices: usize,
+    received: Vec<bool>, // one entry per slice
+    num_received_slices: usize,
+    data: Vec<u8>,
+    block_size: usize, // only known once the last slice arrives
+}
+
+/**
+    Reassembles blocks sent by a BlockSender, and acks the slices received.
+
+    Blocks are received in the order they were sent, each is delivered by receive_block exactly once.
+    Slices of the block just completed are still acked, in case the sender missed the final ack.
+*/
+pub struct BlockReceiver {
+    config: BlockConfig,
+    block_id: u16,                      // id of the block being received
+    block: Option<ReceiveBlock>,        // None until the first slice of the block arrives
+    previous_num_slices: Option<usize>, // slices in the last completed block, to keep acking it
+    received_blocks: VecDeque<Vec<u8>>,
+
+    pub num_slices_received: u64,
+    pub num_duplicate_slices: u64, // slices received again, ex. when an ack was lost
+    pub num_invalid_slices: u64,   // slices that don't match the block being received
+    pub num_blocks_received: u64,
+}
+
+impl BlockReceiver {
+    pub fn new(config: &BlockConfig) -> BlockReceiver {
+        // both are serialized with serialize_int, which needs a range
+        assert!(config.slice_size > 1 && config.max_slices_per_block > 1);
+
+        BlockReceiver {
+            config: *config,
+            block_id: 0,
+            block: None,
+            previous_num_slices: None,
+            received_blocks: VecDeque::new(),
+            num_slices_received: 0,
+            num_duplicate_slices: 0,
+            num_invalid_slices: 0,
+            num_blocks_received: 0,
+        }
+    }
+
+    /** Next completed block, if there is one */
+    pub fn receive_block(&mut self) -> Option<Vec<u8>> {
+        self.received_blocks.pop_front()
+    }
+
+    /**
+        Stores a slice, and returns the ack to send back for its block.
+        Returns None if the slice is from an unexpected block or doesn't fit the block being received.
+    */
+    pub fn process_slice_packet(
+        &mut self,
+        packet: &SlicePacket,
+        ack_packet_type: u32,
+    ) -> Option<SliceAckPacket> {
+        let num_slices = packet.num_slices as usize;
+        let slice_id = packet.slice_id as usize;
+
+        // slices of the last block received, the sender hasn't seen our ack yet
+        if packet.block_id == self.block_id.wrapping_sub(1)
+            && self.previous_num_slices == Some(num_slices)
+        {
+            self.num_duplicate_slices += 1;
+            let mut ack_packet = SliceAckPacket::new(ack_packet_type, &self.config);
+            ack_packet.block_id = packet.block_id;
+            ack_packet.acked = vec![true; num_slices];
+            return Some(ack_packet);
+        }
+
+        let is_last_slice = slice_id + 1 == num_slices;
+        if packet.block_id != self.block_id
+            || num_slices == 0
+            || num_slices > self.config.max_slices_per_block
+            || slice_id >= num_slices
+            || packet.slice_data.is_empty()
+            || packet.slice_data.len() > self.config.slice_size
+            || (!is_last_slice && packet.slice_data.len() != self.config.slice_size)
+        {
+            self.num_invalid_slices += 1;
+            return None;
+        }
+
+        let slice_size = self.config.slice_size;
+        let block = self.block.get_or_insert_with(|| ReceiveBlock {
+            num_slices,
+            received: vec![false; num_slices],
+            num_received_slices: 0,
+            data: vec![0; num_slices * slice_size],
+            block_size: 0,
+        });
+        if block.num_slices != num_slices {
+            self.num_invalid_slices += 1;
+            return None;
+        }
+
+        if block.received[slice_id] {
+            self.num_duplicate_slices += 1;
+        } else {
+            let start = slice_id * slice_size;
+            block.data[start..start + packet.slice_data.len()].copy_from_slice(&packet.slice_data);
+            block.received[slice_id] = true;
+            block.num_received_slices += 1;
+            if is_last_slice {
+                block.block_size = start + packet.slice_data.len();
+            }
+            self.num_slices_received += 1;
+        }
+
+        let mut ack_packet = SliceAckPacket::new(ack_packet_type, &self.config);
+        ack_packet.block_id = packet.block_id;
+        ack_packet.acked = block.received.clone();
+
+        if block.num_received_slices == block.num_slices {
+            let mut block = self.block.take().unwrap();
+            block.data.truncate(block.block_size);
+            self.received_blocks.push_back(block.data);
+            self.previous_num_slices = Some(num_slices);
+            self.block_id = self.block_id.wrapping_add(1);
+            self.num_blocks_received += 1;
+        }
+
+        Some(ack_packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::protocol::{
+        packets::{self, object::downcast_packet, packet_info::PacketInfo},
+        test_helpers::TestPacketFactory,
+    };
+
+    const SLICE_PACKET: u32 = 0;
+    const SLICE_ACK_PACKET: u32 = 1;
+
+    /** Writes and reads a packet, so every slice and ack goes through the wire format */
+    fn send_packet(info: &PacketInfo, packet: &mut dyn Packet) -> Box<dyn Packet> {
+        let mut buffer = vec![0; 2048];
+        let bytes_written = packets::write_packet(info, packet, &mut buffer, 2048, None);
+        assert!(bytes_written > 0);
+        packets::read_packet(info, &buffer[..bytes_written as usize], None).unwrap()
+    }
+
+    #[test]
+    fn test_block_transfer() {
+        let config = BlockConfig::default();
+        let packet_factory = TestPacketFactory::new(2, move |packet_type| match packet_type {
+            SLICE_PACKET => Box::new(SlicePacket::new(packet_type, &config)),
+            _ => Box::new(SliceAckPacket::new(packet_type, &config)),
+        });
+        let mut info = PacketInfo::new(&packet_factory);
+        info.allowed_packet_types = vec![SLICE_PACKET, SLICE_ACK_PACKET];
+
+        let mut sender = BlockSender::new(&config);
+        let mut receiver = BlockReceiver::new(&config);
+        let mut rng = StdRng::seed_from_u64(19);
+
+        // A few megabytes, and a small block that fits in one slice
+        let blocks: Vec<Vec<u8>> = vec![
+            (0..3 * 1024 * 1024 + 100)
+                .map(|i| (i % 251) as u8)
+                .collect(),
+            vec![7; 10],
+        ];
+
+        let mut time = 0.0;
+        for block in blocks.iter() {
+            assert!(sender.send_block(block.clone()));
+            assert!(!sender.send_block(vec![1]));
+
+            // 30% of slices and acks are lost
+            while sender.is_sending() {
+                time += 0.01;
+                sender.advance_time(time);
+
+                for mut slice_packet in sender.generate_slice_packets(SLICE_PACKET, 256) {
+                    if rng.gen_range(0..100) < 30 {
+                        continue;
+                    }
+                    let packet = send_packet(&info, &mut slice_packet);
+                    let slice_packet = downcast_packet::<SlicePacket>(packet.as_ref()).unwrap();
+
+                    let Some(mut ack_packet) =
+                        receiver.process_slice_packet(slice_packet, SLICE_ACK_PACKET)
+                    else {
+                        continue;
+                    };
+                    if rng.gen_range(0..100) < 30 {
+                        continue;
+                    }
+                    let packet = send_packet(&info, &mut ack_packet);
+                    sender.process_ack_packet(
+                        downcast_packet::<SliceAckPacket>(packet.as_ref()).unwrap(),
+                    );
+                }
+            }
+
+            // Delivered once
+            assert_eq!(receiver.receive_block().as_ref(), Some(block));
+            assert!(receiver.receive_block().is_none());
+        }
+
+        assert_eq!(sender.num_blocks_sent, 2);
+        assert_eq!(receiver.num_blocks_received, 2);
+        assert!(sender.num_slices_sent > receiver.num_slices_received);
+        assert!(receiver.receive_block().is_none());
+    }
+
+    #[test]
+    fn test_block_transfer_limits() {
+        let config = BlockConfig {
+            slice_size: 4,
+            max_slices_per_block: 4,
+            slice_resend_time: 1.0,
+        };
+        let mut sender = BlockSender::new(&config);
+        let mut receiver = BlockReceiver::new(&config);
+
+        assert!(!sender.send_block(vec![]));
+        assert!(!sender.send_block(vec![0; 17]));
+        assert!(sender.send_block(vec![1, 2, 3, 4, 5, 6]));
+
+        // Unacked slices are only resent after slice_resend_time
+        let slice_packets = sender.generate_slice_packets(SLICE_PACKET, 16);
+        assert_eq!(slice_packets.len(), 2);
+        assert!(sender.generate_slice_packets(SLICE_PACKET, 16).is_empty());
+        sender.advance_time(1.0);
+        assert_eq!(sender.generate_slice_packets(SLICE_PACKET, 1).len(), 1);
+
+        // Slices that don't match the block are rejected
+        let mut bad_slice = SlicePacket::new(SLICE_PACKET, &config);
+        bad_slice.block_id = 5;
+        bad_slice.num_slices = 2;
+        bad_slice.slice_data = vec![0; 4];
+        assert!(receiver
+            .process_slice_packet(&bad_slice, SLICE_ACK_PACKET)
+            .is_none());
+        assert_eq!(receiver.num_invalid_slices, 1);
+
+        // The block completes once, a late slice is acked but not delivered again
+        let ack_packet = receiver
+            .process_slice_packet(&slice_packets[1], SLICE_ACK_PACKET)
+            .unwrap();
+        assert_eq!(ack_packet.acked, vec![false, true]);
+        sender.process_ack_packet(&ack_packet);
+        assert!(sender.is_sending());
+
+        let ack_packet = receiver
+            .process_slice_packet(&slice_packets[0], SLICE_ACK_PACKET)
+            .unwrap();
+        assert_eq!(receiver.receive_block(), Some(vec![1, 2, 3, 4, 5, 6]));
+
+        let late_ack_packet = receiver
+            .process_slice_packet(&slice_packets[0], SLICE_ACK_PACKET)
+            .unwrap();
+        assert_eq!(late_ack_packet.acked, vec![true, true]);
+        assert!(receiver.receive_block().is_none());
+
+        sender.process_ack_packet(&ack_packet);
+        assert!(!sender.is_sending());
+        assert_eq!(sender.num_blocks_sent, 1);
+    }
+}
@@ -0,0 +1,1478 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    net::SocketAddr,
+};
+
+use bitpacker_derive::Serialize;
+
+use super::{
+    connect_token::{
+        read_connect_token, unix_timestamp, validate_connect_token, ConnectToken,
+        ConnectTokenError, CONNECT_TOKEN_BYTES, CONNECT_TOKEN_KEY_BYTES,
+    },
+    packets::{
+        object::{downcast_packet, Object, Packet},
+        packet_info::PacketInfo,
+        read_packet, write_packet,
+    },
+    streams::Stream,
+    transport::Transport,
+};
+use crate::{serialize_align, serialize_bool, serialize_bytes, serialize_u64};
+
+/*
+    Packet types used by the connection handshake. 0 is left for fragments.
+    Game packet types start at FIRST_GAME_PACKET_TYPE, the game's PacketFactory
+    creates connection packets with create_connection_packet.
+*/
+pub const CONNECTION_REQUEST_PACKET: u32 = 1;
+pub const CONNECTION_DENIED_PACKET: u32 = 2;
+pub const CONNECTION_CHALLENGE_PACKET: u32 = 3;
+pub const CONNECTION_RESPONSE_PACKET: u32 = 4;
+pub const CONNECTION_KEEP_ALIVE_PACKET: u32 = 5;
+pub const CONNECTION_DISCONNECT_PACKET: u32 = 6;
+pub const FIRST_GAME_PACKET_TYPE: u32 = 7;
+
+/** Creates the connection packet for a packet type, None if it isn't one. Call it from your PacketFactory. */
+pub fn create_connection_packet(packet_type: u32) -> Option<Box<dyn Packet>> {
+    match packet_type {
+        CONNECTION_REQUEST_PACKET => Some(Box::new(ConnectionRequestPacket::default())),
+        CONNECTION_DENIED_PACKET => Some(Box::new(ConnectionDeniedPacket::default())),
+        CONNECTION_CHALLENGE_PACKET => Some(Box::new(ConnectionChallengePacket::default())),
+        CONNECTION_RESPONSE_PACKET => Some(Box::new(ConnectionResponsePacket::default())),
+        CONNECTION_KEEP_ALIVE_PACKET => Some(Box::new(ConnectionKeepAlivePacket::default())),
+        CONNECTION_DISCONNECT_PACKET => Some(Box::new(ConnectionDisconnectPacket::default())),
+        _ => None,
+    }
+}
+
+/** Connection packet types, to add to PacketInfo::allowed_packet_types */
+pub fn connection_packet_types() -> Vec<u32> {
+    (CONNECTION_REQUEST_PACKET..FIRST_GAME_PACKET_TYPE).collect()
+}
+
+/**
+    Client to server: please let me connect. client_salt is random, so replies can be matched to this request.
+
+    The connect token is always CONNECT_TOKEN_BYTES, zeros if the client has none. Requests are
+    much bigger than the challenge sent back, so a spoofed address gains nothing.
+*/
+#[derive(Default)]
+pub struct ConnectionRequestPacket {
+    pub client_salt: u64,
+    pub connect_token: Vec<u8>, // empty is written as zeros
+}
+
+impl Packet for ConnectionRequestPacket {
+    fn get_packet_type(&self) -> u32 {
+        CONNECTION_REQUEST_PACKET
+    }
+}
+
+impl Object for ConnectionRequestPacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        serialize_u64!(stream, self.client_salt);
+
+        if stream.is_reading() || self.connect_token.is_empty() {
+            self.connect_token = vec![0; CONNECT_TOKEN_BYTES];
+        }
+        if self.connect_token.len() != CONNECT_TOKEN_BYTES {
+            return false;
+        }
+        serialize_align!(stream);
+        serialize_bytes!(stream, self.connect_token, CONNECT_TOKEN_BYTES as u32);
+
+        true
+    }
+}
+
+/** Server to client: the server is full, in reply to a request or a challenge response */
+#[derive(Serialize, Default)]
+#[packet_type(CONNECTION_DENIED_PACKET)]
+pub struct ConnectionDeniedPacket {
+    pub client_salt: u64,
+}
+
+/** Server to client: prove you own your address by sending challenge_salt back */
+#[derive(Serialize, Default)]
+#[packet_type(CONNECTION_CHALLENGE_PACKET)]
+pub struct ConnectionChallengePacket {
+    pub client_salt: u64,
+    pub challenge_salt: u64,
+}
+
+/** Client to server: the challenge, answered from the client's address, with the connect token again */
+#[derive(Default)]
+pub struct ConnectionResponsePacket {
+    pub client_salt: u64,
+    pub challenge_salt: u64,
+    pub connect_token: Vec<u8>, // empty if the client has no token
+}
+
+impl Packet for ConnectionResponsePacket {
+    fn get_packet_type(&self) -> u32 {
+        CONNECTION_RESPONSE_PACKET
+    }
+}
+
+impl Object for ConnectionResponsePacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        serialize_u64!(stream, self.client_salt);
+        serialize_u64!(stream, self.challenge_salt);
+
+        let mut has_connect_token = !self.connect_token.is_empty();
+        serialize_bool!(stream, has_connect_token);
+        if !has_connect_token {
+            self.connect_token.clear();
+            return true;
+        }
+
+        if stream.is_reading() {
+            self.connect_token = vec![0; CONNECT_TOKEN_BYTES];
+        }
+        if self.connect_token.len() != CONNECT_TOKEN_BYTES {
+            return false;
+        }
+        serialize_align!(stream);
+        serialize_bytes!(stream, self.connect_token, CONNECT_TOKEN_BYTES as u32);
+
+        true
+    }
+}
+
+/** Both ways once connected, so the other end knows we are still here. The first one confirms the connection. */
+#[derive(Serialize, Default)]
+#[packet_type(CONNECTION_KEEP_ALIVE_PACKET)]
+pub struct ConnectionKeepAlivePacket {
+    pub connection_salt: u64, // client_salt ^ challenge_salt
+}
+
+/** Both ways, the connection is closed. Sent several times, in case some are lost. */
+#[derive(Serialize, Default)]
+#[packet_type(CONNECTION_DISCONNECT_PACKET)]
+pub struct ConnectionDisconnectPacket {
+    pub connection_salt: u64, // client_salt ^ challenge_salt
+}
+
+/**
+    Written before every packet, connection packets included. Game packets are only accepted when
+    it carries the connection salt, so a packet from a spoofed address isn't taken for the client's.
+    Zero for packets sent before the connection is made.
+*/
+#[derive(Serialize, Default)]
+pub struct ConnectionHeader {
+    pub connection_salt: u64, // client_salt ^ challenge_salt
+}
+
+/** Timings for clients and servers, in seconds */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClientServerConfig {
+    pub max_clients: usize,           // client slots on the server
+    pub connection_request_rate: f64, // seconds between connection requests, and between challenge responses
+    pub keep_alive_rate: f64, // seconds without sending anything before a keep-alive is sent
+    pub timeout: f64,         // seconds without hearing from the other end before giving up
+    pub num_disconnect_packets: u32, // disconnect packets sent when disconnecting
+}
+
+impl Default for ClientServerConfig {
+    fn default() -> Self {
+        ClientServerConfig {
+            max_clients: 32,
+            connection_request_rate: 0.1,
+            keep_alive_rate: 1.0,
+            timeout: 5.0,
+            num_disconnect_packets: 10,
+        }
+    }
+}
+
+/** Where a client is in the handshake. Everything before Disconnected is a way the last connection ended. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClientState {
+    ConnectionDenied,          // the server is full
+    ConnectionRequestTimedOut, // no challenge came back, or the server rejected the connect token
+    ChallengeResponseTimedOut, // the server never confirmed the connection
+    ConnectionTimedOut,        // the server went quiet while connected
+    Disconnected,
+    SendingConnectionRequest,
+    SendingChallengeResponse,
+    Connected,
+}
+
+/**
+    Connects to a Server over a Transport.
+
+    connect starts the handshake: connection requests are sent until the server answers with a
+    challenge, then the challenge is sent back until the server confirms with a keep-alive.
+    Call advance_time, send_packets and receive_packets every frame to move the handshake along.
+    Game packets can be sent with send_packet once connected.
+
+    connect_with_token connects to a server that requires connect tokens, trying each server in
+    the token in turn until one lets the client in.
+*/
+pub struct Client<'a, T: Transport> {
+    config: ClientServerConfig,
+    info: PacketInfo<'a>, // packet types must include connection_packet_types()
+    transport: T,
+    time: f64,
+    state: ClientState,
+    server_address: Option<SocketAddr>,
+    server_addresses: Vec<SocketAddr>, // servers to try, in order
+    server_address_index: usize,       // the server being tried
+    connect_token: Vec<u8>,            // empty when connecting without a token
+    client_salt: u64,
+    challenge_salt: u64,
+    last_packet_send_time: f64,
+    last_packet_receive_time: f64,
+    buffer: Vec<u8>,
+}
+
+impl<'a, T: Transport> Client<'a, T> {
+    pub fn new(config: &ClientServerConfig, info: PacketInfo<'a>, transport: T) -> Client<'a, T> {
+        let buffer = vec![0; info.config.max_packet_fragment_size()];
+
+        Client {
+            config: *config,
+            info,
+            transport,
+            time: 0.0,
+            state: ClientState::Disconnected,
+            server_address: None,
+            server_addresses: vec![],
+            server_address_index: 0,
+            connect_token: vec![],
+            client_salt: 0,
+            challenge_salt: 0,
+            last_packet_send_time: f64::NEG_INFINITY,
+            last_packet_receive_time: 0.0,
+            buffer,
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == ClientState::Connected
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.state == ClientState::SendingConnectionRequest
+            || self.state == ClientState::SendingChallengeResponse
+    }
+
+    pub fn server_address(&self) -> Option<SocketAddr> {
+        self.server_address
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /** Starts connecting to a server, disconnecting first if needed */
+    pub fn connect(&mut self, server_address: SocketAddr) {
+        self.disconnect();
+
+        self.server_addresses = vec![server_address];
+        self.connect_token.clear();
+        self.connect_to_server(0);
+    }
+
+    /**
+        Starts connecting to the first server in a connect token from the backend, disconnecting first if needed.
+        The token isn't checked here, the client can't without the private key.
+    */
+    pub fn connect_with_token(&mut self, connect_token: &[u8]) -> Result<(), ConnectTokenError> {
+        let token = read_connect_token(connect_token)?;
+        self.disconnect();
+
+        self.server_addresses = token.server_addresses;
+        self.connect_token = connect_token.to_vec();
+        self.connect_to_server(0);
+        Ok(())
+    }
+
+    fn connect_to_server(&mut self, server_address_index: usize) {
+        self.state = ClientState::SendingConnectionRequest;
+        self.server_address = Some(self.server_addresses[server_address_index]);
+        self.server_address_index = server_address_index;
+        self.client_salt = rand::random();
+        self.challenge_salt = 0;
+        self.last_packet_send_time = f64::NEG_INFINITY;
+        self.last_packet_receive_time = self.time;
+    }
+
+    /**
+        Moves on to the next server in the connect token after one failed while connecting.
+        Returns false if there are none left, or the client isn't connecting.
+    */
+    fn connect_to_next_server(&mut self) -> bool {
+        if !self.is_connecting() || self.server_address_index + 1 >= self.server_addresses.len() {
+            return false;
+        }
+        self.connect_to_server(self.server_address_index + 1);
+        true
+    }
+
+    /** Disconnects, telling the server if the connection was made */
+    pub fn disconnect(&mut self) {
+        if self.is_connected() {
+            for _ in 0..self.config.num_disconnect_packets {
+                let mut packet = ConnectionDisconnectPacket {
+                    connection_salt: self.connection_salt(),
+                };
+                self.send_packet(&mut packet);
+            }
+        }
+
+        if self.is_connecting() || self.is_connected() {
+            self.state = ClientState::Disconnected;
+        }
+        self.server_address = None;
+        self.server_addresses.clear();
+        self.server_address_index = 0;
+        self.client_salt = 0;
+        self.challenge_salt = 0;
+    }
+
+    /** Moves the client's clock forward, in seconds, and gives up if the server has gone quiet */
+    pub fn advance_time(&mut self, time: f64) {
+        self.time = time;
+
+        if self.last_packet_receive_time + self.config.timeout >= time {
+            return;
+        }
+
+        if self.connect_to_next_server() {
+            return;
+        }
+        let timed_out_state = match self.state {
+            ClientState::SendingConnectionRequest => ClientState::ConnectionRequestTimedOut,
+            ClientState::SendingChallengeResponse => ClientState::ChallengeResponseTimedOut,
+            ClientState::Connected => ClientState::ConnectionTimedOut,
+            _ => return,
+        };
+        self.disconnect();
+        self.state = timed_out_state;
+    }
+
+    /** Sends whatever the handshake needs right now: requests, responses or keep-alives */
+    pub fn send_packets(&mut self) {
+        let send_rate = match self.state {
+            ClientState::SendingConnectionRequest | ClientState::SendingChallengeResponse => {
+                self.config.connection_request_rate
+            }
+            ClientState::Connected => self.config.keep_alive_rate,
+            _ => return,
+        };
+        if self.last_packet_send_time + send_rate > self.time {
+            return;
+        }
+
+        match self.state {
+            ClientState::SendingConnectionRequest => {
+                let mut packet = ConnectionRequestPacket {
+                    client_salt: self.client_salt,
+                    connect_token: self.connect_token.clone(),
+                };
+                self.send_packet(&mut packet);
+            }
+            ClientState::SendingChallengeResponse => {
+                let mut packet = ConnectionResponsePacket {
+                    client_salt: self.client_salt,
+                    challenge_salt: self.challenge_salt,
+                    connect_token: self.connect_token.clone(),
+                };
+                self.send_packet(&mut packet);
+            }
+            _ => {
+                let mut packet = ConnectionKeepAlivePacket {
+                    connection_salt: self.connection_salt(),
+                };
+                self.send_packet(&mut packet);
+            }
+        }
+    }
+
+    /**
+        Sends a packet to the server. Returns false if there is no server,
+        or the packet could not be written or sent.
+    */
+    pub fn send_packet(&mut self, packet: &mut dyn Packet) -> bool {
+        let Some(server_address) = self.server_address else {
+            return false;
+        };
+
+        let mut header = ConnectionHeader {
+            connection_salt: if self.is_connected() {
+                self.connection_salt()
+            } else {
+                0
+            },
+        };
+        let buffer_length = self.buffer.len();
+        let bytes_written = write_packet(
+            &self.info,
+            packet,
+            &mut self.buffer,
+            buffer_length,
+            Some(&mut header),
+        );
+        if bytes_written == 0 {
+            return false;
+        }
+
+        if self
+            .transport
+            .send_to(server_address, &self.buffer[..bytes_written as usize])
+            .is_err()
+        {
+            return false;
+        }
+        self.last_packet_send_time = self.time;
+        true
+    }
+
+    /**
+        Reads every datagram waiting on the transport. Connection packets move the handshake along,
+        game packets from the server are returned once connected, if they carry the connection salt.
+        Anything else is dropped.
+    */
+    pub fn receive_packets(&mut self) -> Vec<Box<dyn Packet>> {
+        let mut packets = vec![];
+
+        while let Ok(Some((size, from))) = self.transport.recv_from(&mut self.buffer) {
+            if Some(from) != self.server_address || size == 0 {
+                continue;
+            }
+            let mut header = ConnectionHeader::default();
+            let Ok(packet) = read_packet(&self.info, &self.buffer[..size], Some(&mut header))
+            else {
+                continue;
+            };
+
+            if let Some(packet) = self.process_connection_packet(packet) {
+                if self.is_connected() && header.connection_salt == self.connection_salt() {
+                    self.last_packet_receive_time = self.time;
+                    packets.push(packet);
+                }
+            }
+        }
+
+        packets
+    }
+
+    /** Handles connection packets, returns anything else */
+    fn process_connection_packet(&mut self, packet: Box<dyn Packet>) -> Option<Box<dyn Packet>> {
+        let packet_type = packet.get_packet_type();
+
+        match (packet_type, self.state) {
+            (
+                CONNECTION_DENIED_PACKET,
+                ClientState::SendingConnectionRequest | ClientState::SendingChallengeResponse,
+            ) => {
+                let packet = downcast_packet::<ConnectionDeniedPacket>(packet.as_ref())?;
+                if packet.client_salt == self.client_salt && !self.connect_to_next_server() {
+                    self.disconnect();
+                    self.state = ClientState::ConnectionDenied;
+                }
+            }
+            (CONNECTION_CHALLENGE_PACKET, ClientState::SendingConnectionRequest) => {
+                let packet = downcast_packet::<ConnectionChallengePacket>(packet.as_ref())?;
+                if packet.client_salt == self.client_salt {
+                    self.challenge_salt = packet.challenge_salt;
+                    self.state = ClientState::SendingChallengeResponse;
+                    self.last_packet_send_time = f64::NEG_INFINITY;
+                    self.last_packet_receive_time = self.time;
+                }
+            }
+            (
+                CONNECTION_KEEP_ALIVE_PACKET,
+                ClientState::SendingChallengeResponse | ClientState::Connected,
+            ) => {
+                let packet = downcast_packet::<ConnectionKeepAlivePacket>(packet.as_ref())?;
+                if packet.connection_salt == self.connection_salt() {
+                    self.state = ClientState::Connected;
+                    self.last_packet_receive_time = self.time;
+                }
+            }
+            (CONNECTION_DISCONNECT_PACKET, ClientState::Connected) => {
+                let packet = downcast_packet::<ConnectionDisconnectPacket>(packet.as_ref())?;
+                if packet.connection_salt == self.connection_salt() {
+                    self.state = ClientState::Disconnected;
+                    self.server_address = None;
+                }
+            }
+            _ if packet_type >= FIRST_GAME_PACKET_TYPE => return Some(packet),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn connection_salt(&self) -> u64 {
+        self.client_salt ^ self.challenge_salt
+    }
+}
+
+struct ClientSlot {
+    address: SocketAddr,
+    connection_salt: u64, // client_salt ^ challenge_salt, in every keep-alive, disconnect and ConnectionHeader
+    client_id: u64,       // from the connect token, 0 without one
+    user_data: Vec<u8>,   // from the connect token, empty without one
+    last_packet_send_time: f64,
+    last_packet_receive_time: f64,
+}
+
+/**
+    Accepts up to config.max_clients connections over a Transport.
+
+    The server keeps nothing for clients still in the handshake. The challenge salt is a keyed hash
+    of the client's address and salt, so a response can be checked without remembering the challenge,
+    and only a client that can receive packets at its address can answer it.
+    The server time is hashed in too, so a captured challenge stops working after about config.timeout.
+
+    After require_connect_tokens, only clients with a valid token listing this server's
+    local_address get a challenge, and each client id can only be connected once.
+*/
+pub struct Server<'a, T: Transport> {
+    config: ClientServerConfig,
+    info: PacketInfo<'a>, // packet types must include connection_packet_types()
+    transport: T,
+    time: f64,
+    challenge_key: RandomState, // random key for challenge salts, different for every server
+    connect_token_key: Option<[u8; CONNECT_TOKEN_KEY_BYTES]>, // private key tokens are checked with, None if not required
+    clients: Vec<Option<ClientSlot>>,
+    buffer: Vec<u8>,
+
+    pub num_denied_connections: u64, // requests and responses turned away because the server was full
+    pub num_invalid_responses: u64,  // challenge responses with the wrong or a stale challenge salt
+    pub num_invalid_connect_tokens: u64, // requests and responses ignored for a bad or reused connect token
+    pub num_invalid_connection_salts: u64, // game packets from a client's address without its connection salt, ex. spoofed
+}
+
+impl<'a, T: Transport> Server<'a, T> {
+    pub fn new(config: &ClientServerConfig, info: PacketInfo<'a>, transport: T) -> Server<'a, T> {
+        let buffer = vec![0; info.config.max_packet_fragment_size()];
+
+        Server {
+            config: *config,
+            info,
+            transport,
+            time: 0.0,
+            challenge_key: RandomState::new(),
+            connect_token_key: None,
+            clients: (0..config.max_clients).map(|_| None).collect(),
+            buffer,
+            num_denied_connections: 0,
+            num_invalid_responses: 0,
+            num_invalid_connect_tokens: 0,
+            num_invalid_connection_salts: 0,
+        }
+    }
+
+    /** Only lets in clients with a connect token signed with private_key */
+    pub fn require_connect_tokens(&mut self, private_key: &[u8; CONNECT_TOKEN_KEY_BYTES]) {
+        self.connect_token_key = Some(*private_key);
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn num_connected_clients(&self) -> usize {
+        self.clients
+            .iter()
+            .filter(|client| client.is_some())
+            .count()
+    }
+
+    pub fn is_client_connected(&self, client_index: usize) -> bool {
+        self.clients
+            .get(client_index)
+            .is_some_and(|client| client.is_some())
+    }
+
+    pub fn client_address(&self, client_index: usize) -> Option<SocketAddr> {
+        self.clients
+            .get(client_index)?
+            .as_ref()
+            .map(|client| client.address)
+    }
+
+    /** Client id from the client's connect token, 0 if connect tokens aren't required */
+    pub fn client_id(&self, client_index: usize) -> Option<u64> {
+        self.clients
+            .get(client_index)?
+            .as_ref()
+            .map(|client| client.client_id)
+    }
+
+    /** User data from the client's connect token, empty if connect tokens aren't required */
+    pub fn client_user_data(&self, client_index: usize) -> Option<&[u8]> {
+        self.clients
+            .get(client_index)?
+            .as_ref()
+            .map(|client| client.user_data.as_slice())
+    }
+
+    pub fn find_client_index(&self, address: SocketAddr) -> Option<usize> {
+        self.clients.iter().position(|client| {
+            client
+                .as_ref()
+                .is_some_and(|client| client.address == address)
+        })
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /** Moves the server's clock forward, in seconds, and drops clients that have gone quiet */
+    pub fn advance_time(&mut self, time: f64) {
+        self.time = time;
+
+        for client in self.clients.iter_mut() {
+            if client
+                .as_ref()
+                .is_some_and(|client| client.last_packet_receive_time + self.config.timeout < time)
+            {
+                *client = None;
+            }
+        }
+    }
+
+    /** Sends keep-alives to clients that haven't been sent anything for a while */
+    pub fn send_packets(&mut self) {
+        for client_index in 0..self.clients.len() {
+            let Some(client) = &self.clients[client_index] else {
+                continue;
+            };
+            if client.last_packet_send_time + self.config.keep_alive_rate <= self.time {
+                let mut packet = ConnectionKeepAlivePacket {
+                    connection_salt: client.connection_salt,
+                };
+                self.send_packet(client_index, &mut packet);
+            }
+        }
+    }
+
+    /** Sends a packet to a connected client. Returns false if the client isn't connected, or the send failed. */
+    pub fn send_packet(&mut self, client_index: usize, packet: &mut dyn Packet) -> bool {
+        let Some(client) = self.clients.get(client_index).and_then(Option::as_ref) else {
+            return false;
+        };
+        if !self.send_packet_to(client.address, client.connection_salt, packet) {
+            return false;
+        }
+
+        if let Some(client) = self.clients[client_index].as_mut() {
+            client.last_packet_send_time = self.time;
+        }
+        true
+    }
+
+    /** Disconnects a client, telling it so */
+    pub fn disconnect_client(&mut self, client_index: usize) {
+        let Some(client) = &self.clients[client_index] else {
+            return;
+        };
+
+        let connection_salt = client.connection_salt;
+        for _ in 0..self.config.num_disconnect_packets {
+            let mut packet = ConnectionDisconnectPacket { connection_salt };
+            self.send_packet(client_index, &mut packet);
+        }
+        self.clients[client_index] = None;
+    }
+
+    /**
+        Reads every datagram waiting on the transport. Connection packets are handled here,
+        game packets from connected clients are returned with the index of the client that sent them.
+        Game packets without the client's connection salt are dropped and counted in num_invalid_connection_salts.
+    */
+    pub fn receive_packets(&mut self) -> Vec<(usize, Box<dyn Packet>)> {
+        let mut packets = vec![];
+
+        while let Ok(Some((size, from))) = self.transport.recv_from(&mut self.buffer) {
+            if size == 0 {
+                continue;
+            }
+            let mut header = ConnectionHeader::default();
+            let Ok(packet) = read_packet(&self.info, &self.buffer[..size], Some(&mut header))
+            else {
+                continue;
+            };
+
+            if let Some(packet) = self.process_connection_packet(from, packet) {
+                if let Some(client_index) = self.find_client_index(from) {
+                    let client = self.clients[client_index].as_mut().unwrap();
+                    if client.connection_salt != header.connection_salt {
+                        self.num_invalid_connection_salts += 1;
+                        continue;
+                    }
+                    client.last_packet_receive_time = self.time;
+                    packets.push((client_index, packet));
+                }
+            }
+        }
+
+        packets
+    }
+
+    /** Handles connection packets, returns anything else */
+    fn process_connection_packet(
+        &mut self,
+        from: SocketAddr,
+        packet: Box<dyn Packet>,
+    ) -> Option<Box<dyn Packet>> {
+        match packet.get_packet_type() {
+            CONNECTION_REQUEST_PACKET => {
+                let packet = downcast_packet::<ConnectionRequestPacket>(packet.as_ref())?;
+                self.process_connection_request(from, packet.client_salt, &packet.connect_token);
+            }
+            CONNECTION_RESPONSE_PACKET => {
+                let packet = downcast_packet::<ConnectionResponsePacket>(packet.as_ref())?;
+                self.process_challenge_response(
+                    from,
+                    packet.client_salt,
+                    packet.challenge_salt,
+                    &packet.connect_token,
+                );
+            }
+            CONNECTION_KEEP_ALIVE_PACKET => {
+                let packet = downcast_packet::<ConnectionKeepAlivePacket>(packet.as_ref())?;
+                let client_index = self.find_client_index(from)?;
+                let client = self.clients[client_index].as_mut().unwrap();
+                if client.connection_salt == packet.connection_salt {
+                    client.last_packet_receive_time = self.time;
+                }
+            }
+            CONNECTION_DISCONNECT_PACKET => {
+                let packet = downcast_packet::<ConnectionDisconnectPacket>(packet.as_ref())?;
+                let client_index = self.find_client_index(from)?;
+                let client = self.clients[client_index].as_ref().unwrap();
+                if client.connection_salt == packet.connection_salt {
+                    self.clients[client_index] = None;
+                }
+            }
+            packet_type if packet_type >= FIRST_GAME_PACKET_TYPE => return Some(packet),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn process_connection_request(
+        &mut self,
+        from: SocketAddr,
+        client_salt: u64,
+        connect_token: &[u8],
+    ) {
+        // already connected, the client will hear our keep-alives
+        if self.find_client_index(from).is_some() {
+            return;
+        }
+        let Ok(token) = self.validate_connect_token(from, connect_token) else {
+            return;
+        };
+
+        if self.num_connected_clients() == self.max_clients() {
+            self.num_denied_connections += 1;
+            self.send_packet_to(from, 0, &mut ConnectionDeniedPacket { client_salt });
+            return;
+        }
+
+        let challenge_salt = self.challenge_salt(
+            from,
+            client_salt,
+            token.as_ref(),
+            self.challenge_time_bucket(),
+        );
+        self.send_packet_to(
+            from,
+            0,
+            &mut ConnectionChallengePacket {
+                client_salt,
+                challenge_salt,
+            },
+        );
+    }
+
+    fn process_challenge_response(
+        &mut self,
+        from: SocketAddr,
+        client_salt: u64,
+        challenge_salt: u64,
+        connect_token: &[u8],
+    ) {
+        let Ok(token) = self.validate_connect_token(from, connect_token) else {
+            return;
+        };
+        // challenges from this time bucket or the one before, so a response is at most config.timeout old
+        let time_bucket = self.challenge_time_bucket();
+        if ![time_bucket, time_bucket.saturating_sub(1)]
+            .iter()
+            .any(|&time_bucket| {
+                challenge_salt
+                    == self.challenge_salt(from, client_salt, token.as_ref(), time_bucket)
+            })
+        {
+            self.num_invalid_responses += 1;
+            return;
+        }
+        let connection_salt = client_salt ^ challenge_salt;
+
+        // already connected, the keep-alive confirming it must have been lost
+        if let Some(client_index) = self.find_client_index(from) {
+            if self.clients[client_index].as_ref().unwrap().connection_salt == connection_salt {
+                self.send_packet(
+                    client_index,
+                    &mut ConnectionKeepAlivePacket { connection_salt },
+                );
+            }
+            return;
+        }
+
+        let Some(client_index) = self.clients.iter().position(|client| client.is_none()) else {
+            self.num_denied_connections += 1;
+            self.send_packet_to(from, 0, &mut ConnectionDeniedPacket { client_salt });
+            return;
+        };
+
+        let (client_id, user_data) = token
+            .map(|token| (token.client_id, token.user_data))
+            .unwrap_or_default();
+        self.clients[client_index] = Some(ClientSlot {
+            address: from,
+            connection_salt,
+            client_id,
+            user_data,
+            last_packet_send_time: f64::NEG_INFINITY,
+            last_packet_receive_time: self.time,
+        });
+        self.send_packet(
+            client_index,
+            &mut ConnectionKeepAlivePacket { connection_salt },
+        );
+    }
+
+    /**
+        Checks the connect token in a request or response, if tokens are required.
+        A token is rejected if it doesn't list this server, or its client id is connected from another address.
+    */
+    fn validate_connect_token(
+        &mut self,
+        from: SocketAddr,
+        connect_token: &[u8],
+    ) -> Result<Option<ConnectToken>, ConnectTokenError> {
+        let Some(private_key) = &self.connect_token_key else {
+            return Ok(None);
+        };
+
+        let result = validate_connect_token(
+            connect_token,
+            self.info.config.protocol_id(),
+            private_key,
+            unix_timestamp(),
+        )
+        .and_then(|token| {
+            if !token
+                .server_addresses
+                .contains(&self.transport.local_address())
+            {
+                return Err(ConnectTokenError::WrongServer);
+            }
+            if self
+                .clients
+                .iter()
+                .flatten()
+                .any(|client| client.client_id == token.client_id && client.address != from)
+            {
+                return Err(ConnectTokenError::ClientIdInUse);
+            }
+            Ok(Some(token))
+        });
+
+        if result.is_err() {
+            self.num_invalid_connect_tokens += 1;
+        }
+        result
+    }
+
+    /**
+        Challenge salt for a client. Only the server knows the key, so it can't be guessed.
+        The connect token is hashed in too, so the response can't swap it for another,
+        and the time bucket, so the challenge expires.
+    */
+    fn challenge_salt(
+        &self,
+        address: SocketAddr,
+        client_salt: u64,
+        connect_token: Option<&ConnectToken>,
+        time_bucket: u64,
+    ) -> u64 {
+        let mut hasher = self.challenge_key.build_hasher();
+        address.hash(&mut hasher);
+        client_salt.hash(&mut hasher);
+        connect_token.hash(&mut hasher);
+        time_bucket.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /** Server time in steps of half the timeout, challenges are accepted for this step and the one before */
+    fn challenge_time_bucket(&self) -> u64 {
+        (self.time / (self.config.timeout / 2.0)).floor() as u64
+    }
+
+    /** connection_salt goes in the ConnectionHeader, 0 for addresses that aren't connected */
+    fn send_packet_to(
+        &mut self,
+        address: SocketAddr,
+        connection_salt: u64,
+        packet: &mut dyn Packet,
+    ) -> bool {
+        let mut header = ConnectionHeader { connection_salt };
+        let buffer_length = self.buffer.len();
+        let bytes_written = write_packet(
+            &self.info,
+            packet,
+            &mut self.buffer,
+            buffer_length,
+            Some(&mut header),
+        );
+        if bytes_written == 0 {
+            return false;
+        }
+
+        self.transport
+            .send_to(address, &self.buffer[..bytes_written as usize])
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        network_simulator::{NetworkSimulator, NetworkSimulatorConfig},
+        test_helpers::TestPacketFactory,
+        transport::{LoopbackNetwork, LoopbackTransport},
+    };
+
+    const TEST_PACKET: u32 = FIRST_GAME_PACKET_TYPE;
+
+    #[derive(Serialize, Default)]
+    #[packet_type(TEST_PACKET)]
+    struct TestPacket {
+        #[range(0, 1000)]
+        value: i32,
+    }
+
+    fn packet_factory() -> TestPacketFactory {
+        TestPacketFactory::new(TEST_PACKET + 1, |packet_type| {
+            create_connection_packet(packet_type).unwrap_or_else(|| Box::new(TestPacket::default()))
+        })
+    }
+
+    fn packet_info(packet_factory: &TestPacketFactory) -> PacketInfo<'_> {
+        let mut info = PacketInfo::new(packet_factory);
+        info.allowed_packet_types = connection_packet_types();
+        info.allowed_packet_types.push(TEST_PACKET);
+        info
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn update<T: Transport>(time: f64, server: &mut Server<T>, clients: &mut [&mut Client<T>]) {
+        server.advance_time(time);
+        for client in clients.iter_mut() {
+            client.advance_time(time);
+            client.send_packets();
+        }
+        server.receive_packets();
+        server.send_packets();
+        for client in clients.iter_mut() {
+            client.receive_packets();
+        }
+    }
+
+    #[test]
+    fn test_client_server() {
+        let packet_factory = packet_factory();
+        let config = ClientServerConfig {
+            max_clients: 2,
+            ..ClientServerConfig::default()
+        };
+        let network = LoopbackNetwork::new();
+        let mut server = Server::new(
+            &config,
+            packet_info(&packet_factory),
+            network.bind(address(40000)).unwrap(),
+        );
+        let mut clients: Vec<Client<LoopbackTransport>> = (0..3)
+            .map(|i| {
+                Client::new(
+                    &config,
+                    packet_info(&packet_factory),
+                    network.bind(address(50000 + i)).unwrap(),
+                )
+            })
+            .collect();
+
+        // Two clients fit, the third is turned away
+        let mut time = 0.0;
+        for client in clients.iter_mut() {
+            client.connect(address(40000));
+            assert_eq!(client.state(), ClientState::SendingConnectionRequest);
+        }
+        for _ in 0..10 {
+            time += 0.1;
+            let [a, b, c] = &mut clients[..] else {
+                unreachable!()
+            };
+            update(time, &mut server, &mut [a, b, c]);
+        }
+        assert_eq!(clients[0].state(), ClientState::Connected);
+        assert_eq!(clients[1].state(), ClientState::Connected);
+        assert_eq!(clients[2].state(), ClientState::ConnectionDenied);
+        assert_eq!(server.num_connected_clients(), 2);
+        assert_eq!(server.find_client_index(address(50001)), Some(1));
+
+        // Game packets both ways
+        assert!(clients[1].send_packet(&mut TestPacket { value: 42 }));
+        let packets = server.receive_packets();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].0, 1);
+        assert_eq!(
+            downcast_packet::<TestPacket>(packets[0].1.as_ref())
+                .unwrap()
+                .value,
+            42
+        );
+        assert!(server.send_packet(0, &mut TestPacket { value: 7 }));
+        let packets = clients[0].receive_packets();
+        assert_eq!(packets.len(), 1);
+        assert!(!server.send_packet(1 + 1, &mut TestPacket { value: 7 }));
+
+        // Game packets from a connected address without its connection salt are dropped both ways,
+        // as if an attacker spoofed the address. The transports stand in for the attacker here.
+        let info = packet_info(&packet_factory);
+        let mut buffer = vec![0; 1024];
+        let bytes_written = write_packet(
+            &info,
+            &mut TestPacket { value: 13 },
+            &mut buffer,
+            1024,
+            Some(&mut ConnectionHeader { connection_salt: 0 }),
+        ) as usize;
+        clients[1]
+            .transport()
+            .send_to(address(40000), &buffer[..bytes_written])
+            .unwrap();
+        assert!(server.receive_packets().is_empty());
+        assert_eq!(server.num_invalid_connection_salts, 1);
+        server
+            .transport()
+            .send_to(address(50000), &buffer[..bytes_written])
+            .unwrap();
+        assert!(clients[0].receive_packets().is_empty());
+
+        // Keep-alives hold the connection open while nothing else is sent
+        for _ in 0..100 {
+            time += 0.1;
+            let [a, b, c] = &mut clients[..] else {
+                unreachable!()
+            };
+            update(time, &mut server, &mut [a, b, c]);
+        }
+        assert!(clients[0].is_connected() && clients[1].is_connected());
+
+        // A client that disconnects frees its slot for the denied client
+        clients[0].disconnect();
+        assert_eq!(clients[0].state(), ClientState::Disconnected);
+        server.receive_packets();
+        assert_eq!(server.num_connected_clients(), 1);
+        clients[2].connect(address(40000));
+        for _ in 0..10 {
+            time += 0.1;
+            let [_, b, c] = &mut clients[..] else {
+                unreachable!()
+            };
+            update(time, &mut server, &mut [b, c]);
+        }
+        assert!(clients[2].is_connected());
+        assert_eq!(server.find_client_index(address(50002)), Some(0));
+
+        // A client that goes quiet times out on the server, and the server going quiet times out the client
+        for _ in 0..60 {
+            time += 0.1;
+            update(time, &mut server, &mut [&mut clients[2]]);
+            clients[1].advance_time(time);
+        }
+        assert!(!server.is_client_connected(1));
+        assert!(server.is_client_connected(0));
+        assert_eq!(clients[1].state(), ClientState::ConnectionTimedOut);
+
+        // The server disconnecting a client tells it
+        server.disconnect_client(0);
+        clients[2].receive_packets();
+        assert_eq!(clients[2].state(), ClientState::Disconnected);
+        assert_eq!(server.num_connected_clients(), 0);
+    }
+
+    #[test]
+    fn test_client_server_spoofing() {
+        let packet_factory = packet_factory();
+        let info = packet_info(&packet_factory);
+        let config = ClientServerConfig::default();
+        let network = LoopbackNetwork::new();
+        let mut server = Server::new(
+            &config,
+            packet_info(&packet_factory),
+            network.bind(address(40000)).unwrap(),
+        );
+        let mut attacker = network.bind(address(666)).unwrap();
+        let mut buffer = vec![0; 1024];
+
+        // A response without the challenge salt doesn't connect
+        let bytes_written = write_packet(
+            &info,
+            &mut ConnectionResponsePacket {
+                client_salt: 1,
+                challenge_salt: 2,
+                ..ConnectionResponsePacket::default()
+            },
+            &mut buffer,
+            1024,
+            Some(&mut ConnectionHeader::default()),
+        );
+        attacker
+            .send_to(address(40000), &buffer[..bytes_written as usize])
+            .unwrap();
+        server.receive_packets();
+        assert_eq!(server.num_connected_clients(), 0);
+        assert_eq!(server.num_invalid_responses, 1);
+
+        // The challenge is smaller than the request that asked for it
+        let request_bytes = write_packet(
+            &info,
+            &mut ConnectionRequestPacket {
+                client_salt: 1,
+                ..ConnectionRequestPacket::default()
+            },
+            &mut buffer,
+            1024,
+            Some(&mut ConnectionHeader::default()),
+        );
+        attacker
+            .send_to(address(40000), &buffer[..request_bytes as usize])
+            .unwrap();
+        server.receive_packets();
+        let (challenge_bytes, _) = attacker.recv_from(&mut buffer).unwrap().unwrap();
+        assert!(challenge_bytes * 4 < request_bytes as usize);
+
+        // Game packets from addresses that aren't connected are dropped
+        let bytes_written = write_packet(
+            &info,
+            &mut TestPacket { value: 1 },
+            &mut buffer,
+            1024,
+            Some(&mut ConnectionHeader::default()),
+        );
+        attacker
+            .send_to(address(40000), &buffer[..bytes_written as usize])
+            .unwrap();
+        assert!(server.receive_packets().is_empty());
+    }
+
+    #[test]
+    fn test_client_server_stale_challenge() {
+        let packet_factory = packet_factory();
+        let info = packet_info(&packet_factory);
+        let config = ClientServerConfig::default();
+        let network = LoopbackNetwork::new();
+        let mut server = Server::new(
+            &config,
+            packet_info(&packet_factory),
+            network.bind(address(40000)).unwrap(),
+        );
+        let mut attacker = network.bind(address(666)).unwrap();
+        let mut buffer = vec![0; 1024];
+
+        let mut send = |server: &mut Server<LoopbackTransport>, packet: &mut dyn Packet| {
+            let bytes_written = write_packet(
+                &info,
+                packet,
+                &mut buffer,
+                1024,
+                Some(&mut ConnectionHeader::default()),
+            );
+            attacker
+                .send_to(address(40000), &buffer[..bytes_written as usize])
+                .unwrap();
+            server.receive_packets();
+            let (bytes_read, _) = attacker.recv_from(&mut buffer).ok()??;
+            let packet = read_packet(
+                &info,
+                &buffer[..bytes_read],
+                Some(&mut ConnectionHeader::default()),
+            )
+            .unwrap();
+            downcast_packet::<ConnectionChallengePacket>(packet.as_ref())
+                .map(|challenge| challenge.challenge_salt)
+        };
+
+        // A challenge captured now...
+        let challenge_salt = send(
+            &mut server,
+            &mut ConnectionRequestPacket {
+                client_salt: 1,
+                ..ConnectionRequestPacket::default()
+            },
+        )
+        .unwrap();
+
+        // ...can't be replayed once it is older than the timeout
+        server.advance_time(config.timeout + 0.1);
+        send(
+            &mut server,
+            &mut ConnectionResponsePacket {
+                client_salt: 1,
+                challenge_salt,
+                ..ConnectionResponsePacket::default()
+            },
+        );
+        assert_eq!(server.num_connected_clients(), 0);
+        assert_eq!(server.num_invalid_responses, 1);
+
+        // A challenge from the server's previous time bucket still connects
+        let challenge_salt = send(
+            &mut server,
+            &mut ConnectionRequestPacket {
+                client_salt: 2,
+                ..ConnectionRequestPacket::default()
+            },
+        )
+        .unwrap();
+        server.advance_time(config.timeout + config.timeout * 0.5);
+        send(
+            &mut server,
+            &mut ConnectionResponsePacket {
+                client_salt: 2,
+                challenge_salt,
+                ..ConnectionResponsePacket::default()
+            },
+        );
+        assert_eq!(server.num_connected_clients(), 1);
+        assert_eq!(server.num_invalid_responses, 1);
+    }
+
+    #[test]
+    fn test_client_server_packet_loss() {
+        let packet_factory = packet_factory();
+        let config = ClientServerConfig::default();
+        let simulator = NetworkSimulator::new(
+            &NetworkSimulatorConfig {
+                latency: 0.05,
+                jitter: 0.02,
+                packet_loss: 30.0,
+                duplicates: 10.0,
+                ..NetworkSimulatorConfig::default()
+            },
+            23,
+        );
+        let mut server = Server::new(
+            &config,
+            packet_info(&packet_factory),
+            simulator.bind(address(40000)).unwrap(),
+        );
+        let mut client = Client::new(
+            &config,
+            packet_info(&packet_factory),
+            simulator.bind(address(50000)).unwrap(),
+        );
+
+        client.connect(address(40000));
+        let mut time = 0.0;
+        for _ in 0..300 {
+            time += 0.02;
+            simulator.advance_time(time);
+            update(time, &mut server, &mut [&mut client]);
+        }
+        assert!(client.is_connected());
+        assert_eq!(server.num_connected_clients(), 1);
+    }
+
+    #[test]
+    fn test_client_server_connect_tokens() {
+        use crate::protocol::connect_token::{generate_private_key, ConnectTokenGenerator};
+
+        let packet_factory = packet_factory();
+        let config = ClientServerConfig {
+            keep_alive_rate: 0.1,
+            timeout: 1.0,
+            ..ClientServerConfig::default()
+        };
+        let info = packet_info(&packet_factory);
+        let private_key = generate_private_key();
+        let network = LoopbackNetwork::new();
+        let mut server = Server::new(
+            &config,
+            packet_info(&packet_factory),
+            network.bind(address(40001)).unwrap(),
+        );
+        server.require_connect_tokens(&private_key);
+
+        // The first server in the token is down, so the client moves on to the second
+        let generator = ConnectTokenGenerator::new(
+            info.config.protocol_id(),
+            &private_key,
+            30,
+            &[address(40000), address(40001)],
+        );
+        let token = generator.generate(1234, b"player one");
+        let mut clients: Vec<Client<LoopbackTransport>> = (0..5)
+            .map(|i| {
+                Client::new(
+                    &config,
+                    packet_info(&packet_factory),
+                    network.bind(address(50000 + i)).unwrap(),
+                )
+            })
+            .collect();
+        clients[0].connect_with_token(&token).unwrap();
+        assert_eq!(clients[0].server_address(), Some(address(40000)));
+
+        // Turned away: no token, a reused token, a token from another backend and an expired token
+        clients[1].connect(address(40001));
+        clients[2].connect_with_token(&token).unwrap();
+        clients[3]
+            .connect_with_token(
+                &ConnectTokenGenerator::new(
+                    info.config.protocol_id(),
+                    &generate_private_key(),
+                    30,
+                    &[address(40001)],
+                )
+                .generate(1, &[]),
+            )
+            .unwrap();
+        clients[4]
+            .connect_with_token(&generator.generate_at(1, &[], unix_timestamp() - 60))
+            .unwrap();
+
+        let mut time = 0.0;
+        for _ in 0..30 {
+            time += 0.1;
+            let [a, b, c, d, e] = &mut clients[..] else {
+                unreachable!()
+            };
+            update(time, &mut server, &mut [a, b, c, d, e]);
+        }
+        assert!(clients[0].is_connected());
+        assert_eq!(clients[0].server_address(), Some(address(40001)));
+        assert_eq!(server.client_id(0), Some(1234));
+        assert_eq!(&server.client_user_data(0).unwrap()[..10], b"player one");
+        assert_eq!(clients[1].state(), ClientState::ConnectionRequestTimedOut);
+        for client in &clients[1..] {
+            assert!(!client.is_connected() && !client.is_connecting());
+        }
+        assert_eq!(server.num_connected_clients(), 1);
+        assert!(server.num_invalid_connect_tokens > 0);
+
+        assert_eq!(
+            clients[0].connect_with_token(&token[1..]),
+            Err(ConnectTokenError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn test_client_server_no_failover_after_connecting() {
+        use crate::protocol::connect_token::{generate_private_key, ConnectTokenGenerator};
+
+        let packet_factory = packet_factory();
+        let config = ClientServerConfig {
+            keep_alive_rate: 0.1,
+            timeout: 1.0,
+            ..ClientServerConfig::default()
+        };
+        let info = packet_info(&packet_factory);
+        let private_key = generate_private_key();
+        let network = LoopbackNetwork::new();
+        let mut servers: Vec<Server<LoopbackTransport>> = (0..2)
+            .map(|i| {
+                let mut server = Server::new(
+                    &config,
+                    packet_info(&packet_factory),
+                    network.bind(address(40000 + i)).unwrap(),
+                );
+                server.require_connect_tokens(&private_key);
+                server
+            })
+            .collect();
+        let mut client = Client::new(
+            &config,
+            packet_info(&packet_factory),
+            network.bind(address(50000)).unwrap(),
+        );
+        let generator = ConnectTokenGenerator::new(
+            info.config.protocol_id(),
+            &private_key,
+            30,
+            &[address(40000), address(40001)],
+        );
+
+        // Servers that aren't running are left alone, as if they had gone quiet
+        let mut time = 0.0;
+        let mut run = |client: &mut Client<LoopbackTransport>,
+                       servers: &mut [Server<LoopbackTransport>],
+                       running: &[bool],
+                       steps: usize| {
+            for _ in 0..steps {
+                time += 0.1;
+                for (server, _) in servers.iter_mut().zip(running).filter(|(_, &r)| r) {
+                    server.advance_time(time);
+                }
+                client.advance_time(time);
+                client.send_packets();
+                for (server, _) in servers.iter_mut().zip(running).filter(|(_, &r)| r) {
+                    server.receive_packets();
+                    server.send_packets();
+                }
+                client.receive_packets();
+            }
+        };
+
+        // Disconnecting from the first server doesn't move on to the second
+        client
+            .connect_with_token(&generator.generate(1, &[]))
+            .unwrap();
+        run(&mut client, &mut servers, &[true, true], 10);
+        assert!(client.is_connected());
+        assert_eq!(client.server_address(), Some(address(40000)));
+
+        client.disconnect();
+        run(&mut client, &mut servers, &[true, true], 30);
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(client.server_address(), None);
+        assert_eq!(servers[1].num_connected_clients(), 0);
+
+        // Neither does timing out on it
+        client
+            .connect_with_token(&generator.generate(2, &[]))
+            .unwrap();
+        run(&mut client, &mut servers, &[true, true], 10);
+        assert!(client.is_connected());
+        assert_eq!(client.server_address(), Some(address(40000)));
+
+        run(&mut client, &mut servers, &[false, true], 30);
+        assert_eq!(client.state(), ClientState::ConnectionTimedOut);
+        assert_eq!(client.server_address(), None);
+        assert_eq!(servers[1].num_connected_clients(), 0);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{
+    packets::object::Object,
+    streams::{read_stream::ReadStream, write_stream::WriteStream, Stream},
+};
+use crate::{
+    serialize_bits, serialize_bool, serialize_bytes, serialize_int, serialize_u128, serialize_u64,
+};
+
+pub const CONNECT_TOKEN_BYTES: usize = 512; // every token is padded to this size, signature included
+pub const CONNECT_TOKEN_KEY_BYTES: usize = 32;
+pub const CONNECT_TOKEN_SIGNATURE_BYTES: usize = 32; // HMAC-SHA256, the last bytes of the token
+pub const CONNECT_TOKEN_USER_DATA_BYTES: usize = 256;
+pub const MAX_SERVERS_PER_CONNECT: usize = 8;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/** Why a connect token was rejected */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectTokenError {
+    InvalidSize,
+    InvalidSignature, // signed with another key, or changed after signing
+    SerializeFailed,
+    WrongProtocolId,
+    Expired,
+    WrongServer,   // the token doesn't list the server it was sent to
+    ClientIdInUse, // a client with the token's client id is already connected from another address
+}
+
+impl fmt::Display for ConnectTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectTokenError::InvalidSize => "Invalid connect token size",
+            ConnectTokenError::InvalidSignature => "Invalid connect token signature",
+            ConnectTokenError::SerializeFailed => "Failed to serialize connect token",
+            ConnectTokenError::WrongProtocolId => "Connect token is for another protocol",
+            ConnectTokenError::Expired => "Connect token expired",
+            ConnectTokenError::WrongServer => "Connect token is for other servers",
+            ConnectTokenError::ClientIdInUse => "Connect token client id already connected",
+        })
+    }
+}
+
+impl std::error::Error for ConnectTokenError {}
+
+/**
+    Permission for one client to connect to a set of servers, issued by a backend that shares a
+    private key with the servers.
+
+    Tokens are signed, not encrypted: the client reads the server addresses out of its token,
+    so don't put anything in user_data the client shouldn't see.
+*/
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub struct ConnectToken {
+    pub protocol_id: u32,
+    pub client_id: u64, // unique per client, a server only lets one connection use it
+    pub create_timestamp: u64, // unix seconds
+    pub expire_timestamp: u64, // unix seconds, servers reject the token from then on
+    pub server_addresses: Vec<SocketAddr>, // servers the client may connect to, tried in order
+    pub user_data: Vec<u8>, // CONNECT_TOKEN_USER_DATA_BYTES, passed on to the server
+}
+
+impl Default for ConnectToken {
+    fn default() -> Self {
+        ConnectToken {
+            protocol_id: 0,
+            client_id: 0,
+            create_timestamp: 0,
+            expire_timestamp: 0,
+            server_addresses: vec![],
+            user_data: vec![0; CONNECT_TOKEN_USER_DATA_BYTES],
+        }
+    }
+}
+
+impl Object for ConnectToken {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        serialize_bits!(stream, self.protocol_id, 32);
+        serialize_u64!(stream, self.client_id);
+        serialize_u64!(stream, self.create_timestamp);
+        serialize_u64!(stream, self.expire_timestamp);
+
+        let mut num_server_addresses = self.server_addresses.len() as i32;
+        serialize_int!(
+            stream,
+            num_server_addresses,
+            1,
+            MAX_SERVERS_PER_CONNECT as i32
+        );
+        if stream.is_reading() {
+            self.server_addresses =
+                vec![SocketAddr::from(([0, 0, 0, 0], 0)); num_server_addresses as usize];
+        }
+        for address in self.server_addresses.iter_mut() {
+            if !serialize_address(stream, address) {
+                return false;
+            }
+        }
+
+        if stream.is_writing() && self.user_data.len() != CONNECT_TOKEN_USER_DATA_BYTES {
+            return false;
+        }
+        if stream.is_reading() {
+            self.user_data = vec![0; CONNECT_TOKEN_USER_DATA_BYTES];
+        }
+        serialize_bytes!(stream, self.user_data, CONNECT_TOKEN_USER_DATA_BYTES as u32);
+
+        true
+    }
+}
+
+fn serialize_address<S: Stream>(stream: &mut S, address: &mut SocketAddr) -> bool {
+    let mut is_ipv6 = address.is_ipv6();
+    serialize_bool!(stream, is_ipv6);
+
+    let mut port = address.port() as u32;
+    if is_ipv6 {
+        let mut ip = match address.ip() {
+            IpAddr::V6(ip) => u128::from(ip),
+            IpAddr::V4(_) => 0,
+        };
+        serialize_u128!(stream, ip);
+        serialize_bits!(stream, port, 16);
+        *address = SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port as u16);
+    } else {
+        let mut ip = match address.ip() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        };
+        serialize_bits!(stream, ip, 32);
+        serialize_bits!(stream, port, 16);
+        *address = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port as u16);
+    }
+
+    true
+}
+
+/** Seconds since the unix epoch, the clock connect token timestamps use */
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/** Random private key, to share between the backend generating tokens and the servers checking them */
+pub fn generate_private_key() -> [u8; CONNECT_TOKEN_KEY_BYTES] {
+    rand::random()
+}
+
+/** Serializes a token into CONNECT_TOKEN_BYTES and signs it */
+pub fn write_connect_token(
+    token: &mut ConnectToken,
+    private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+) -> Result<Vec<u8>, ConnectTokenError> {
+    let mut data = vec![0; CONNECT_TOKEN_BYTES];
+    let (body, signature) = data.split_at_mut(CONNECT_TOKEN_BYTES - CONNECT_TOKEN_SIGNATURE_BYTES);
+
+    {
+        let mut stream = WriteStream::new(body);
+        if !token.serialize(&mut stream) || !stream.flush() {
+            return Err(ConnectTokenError::SerializeFailed);
+        }
+    }
+
+    let mut mac = HmacSha256::new_from_slice(private_key).expect("hmac takes keys of any size");
+    mac.update(body);
+    signature.copy_from_slice(&mac.finalize().into_bytes());
+
+    Ok(data)
+}
+
+/**
+    Reads a token without checking the signature or expiry.
+    For clients, which don't have the private key but need the server addresses.
+*/
+pub fn read_connect_token(data: &[u8]) -> Result<ConnectToken, ConnectTokenError> {
+    if data.len() != CONNECT_TOKEN_BYTES {
+        return Err(ConnectTokenError::InvalidSize);
+    }
+
+    let mut token = ConnectToken::default();
+    let mut stream = ReadStream::new(&data[..CONNECT_TOKEN_BYTES - CONNECT_TOKEN_SIGNATURE_BYTES]);
+    if !token.serialize(&mut stream) {
+        return Err(ConnectTokenError::SerializeFailed);
+    }
+
+    Ok(token)
+}
+
+/** Reads a token, checking it was signed with private_key for this protocol and hasn't expired at timestamp */
+pub fn validate_connect_token(
+    data: &[u8],
+    protocol_id: u32,
+    private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    timestamp: u64,
+) -> Result<ConnectToken, ConnectTokenError> {
+    if data.len() != CONNECT_TOKEN_BYTES {
+        return Err(ConnectTokenError::InvalidSize);
+    }
+
+    let (body, signature) = data.split_at(CONNECT_TOKEN_BYTES - CONNECT_TOKEN_SIGNATURE_BYTES);
+    let mut mac = HmacSha256::new_from_slice(private_key).expect("hmac takes keys of any size");
+    mac.update(body);
+    if mac.verify_slice(signature).is_err() {
+        return Err(ConnectTokenError::InvalidSignature);
+    }
+
+    let token = read_connect_token(data)?;
+    if token.protocol_id != protocol_id {
+        return Err(ConnectTokenError::WrongProtocolId);
+    }
+    if token.expire_timestamp <= timestamp {
+        return Err(ConnectTokenError::Expired);
+    }
+
+    Ok(token)
+}
+
+/**
+    Issues connect tokens for a set of servers. This is the backend's half of the private key,
+    usable stand-alone in tests and tools.
+*/
+pub struct ConnectTokenGenerator {
+    protocol_id: u32,
+    private_key: [u8; CONNECT_TOKEN_KEY_BYTES],
+    expire_seconds: u64, // how long a token is valid for after it is generated
+    server_addresses: Vec<SocketAddr>,
+}
+
+impl ConnectTokenGenerator {
+    pub fn new(
+        protocol_id: u32,
+        private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+        expire_seconds: u64,
+        server_addresses: &[SocketAddr],
+    ) -> ConnectTokenGenerator {
+        assert!(!server_addresses.is_empty() && server_addresses.len() <= MAX_SERVERS_PER_CONNECT);
+
+        ConnectTokenGenerator {
+            protocol_id,
+            private_key: *private_key,
+            expire_seconds,
+            server_addresses: server_addresses.to_vec(),
+        }
+    }
+
+    /** Signed token for client_id, valid from now. user_data is zero padded to CONNECT_TOKEN_USER_DATA_BYTES. */
+    pub fn generate(&self, client_id: u64, user_data: &[u8]) -> Vec<u8> {
+        self.generate_at(client_id, user_data, unix_timestamp())
+    }
+
+    /** Signed token for client_id, valid from timestamp */
+    pub fn generate_at(&self, client_id: u64, user_data: &[u8], timestamp: u64) -> Vec<u8> {
+        assert!(user_data.len() <= CONNECT_TOKEN_USER_DATA_BYTES);
+
+        let mut token = ConnectToken {
+            protocol_id: self.protocol_id,
+            client_id,
+            create_timestamp: timestamp,
+            expire_timestamp: timestamp + self.expire_seconds,
+            server_addresses: self.server_addresses.clone(),
+            user_data: user_data.to_vec(),
+        };
+        token.user_data.resize(CONNECT_TOKEN_USER_DATA_BYTES, 0);
+
+        write_connect_token(&mut token, &self.private_key)
+            .expect("MAX_SERVERS_PER_CONNECT addresses fit in a connect token")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_token() {
+        let private_key = generate_private_key();
+        let server_addresses = [
+            "10.0.0.1:40000".parse().unwrap(),
+            "[::1]:40001".parse().unwrap(),
+        ];
+        let generator = ConnectTokenGenerator::new(7, &private_key, 30, &server_addresses);
+
+        let data = generator.generate_at(1234, b"hello", 1000);
+        assert_eq!(data.len(), CONNECT_TOKEN_BYTES);
+        let token = validate_connect_token(&data, 7, &private_key, 1010).unwrap();
+        assert_eq!(token.client_id, 1234);
+        assert_eq!(token.create_timestamp, 1000);
+        assert_eq!(token.expire_timestamp, 1030);
+        assert_eq!(token.server_addresses, server_addresses);
+        assert_eq!(&token.user_data[..5], b"hello");
+        assert_eq!(read_connect_token(&data).unwrap(), token);
+
+        assert_eq!(
+            validate_connect_token(&data, 7, &private_key, 1030),
+            Err(ConnectTokenError::Expired)
+        );
+        assert_eq!(
+            validate_connect_token(&data, 8, &private_key, 1010),
+            Err(ConnectTokenError::WrongProtocolId)
+        );
+        assert_eq!(
+            validate_connect_token(&data, 7, &generate_private_key(), 1010),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+        assert_eq!(
+            validate_connect_token(&data[1..], 7, &private_key, 1010),
+            Err(ConnectTokenError::InvalidSize)
+        );
+
+        // Changing any byte breaks the signature
+        let mut forged = data.clone();
+        forged[5] ^= 1;
+        assert_eq!(
+            validate_connect_token(&forged, 7, &private_key, 1010),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+    }
+}
@@ -1,17 +1,23 @@
-use super::constants::{Buffer, ProtocolError};
+use super::protocol_error::ProtocolErrorKind;
 use std::hash::{Hash, Hasher};
 
-/** Prints out text representation of ProtocolError enum */
-pub fn get_error_string(error: ProtocolError) -> &'static str {
+/** Prints out text representation of ProtocolErrorKind enum */
+pub fn get_error_string(error: ProtocolErrorKind) -> &'static str {
     match error {
-        ProtocolError::None => return "No error",
-        ProtocolError::StreamOverflow => return "Stream overflow",
-        ProtocolError::SerializeHeaderFailed => return "Failed to serialize header",
-        ProtocolError::CreatePacketFailed => return "Failed to create packet",
-        ProtocolError::InvalidPacketType => return "Invalid packet type",
-        ProtocolError::PacketTypeNotAllowed => return "Packet type not allowed",
-        ProtocolError::SerializeCheckFailed => return "Serialize check failed",
-        ProtocolError::SerializePacketFailed => return "Serialize packet failed",
+        ProtocolErrorKind::None => "No error",
+        ProtocolErrorKind::StreamOverflow => "Stream overflow",
+        ProtocolErrorKind::SerializeHeaderFailed => "Failed to serialize header",
+        ProtocolErrorKind::CreatePacketFailed => "Failed to create packet",
+        ProtocolErrorKind::InvalidPacketType => "Invalid packet type",
+        ProtocolErrorKind::PacketTypeNotAllowed => "Packet type not allowed",
+        ProtocolErrorKind::SerializeCheckFailed => "Serialize check failed",
+        ProtocolErrorKind::SerializePacketFailed => "Serialize packet failed",
+        ProtocolErrorKind::InvalidCrc32 => "Invalid crc32",
+        ProtocolErrorKind::StalePacket => "Stale packet",
+        ProtocolErrorKind::DecryptFailed => "Failed to decrypt packet",
+        ProtocolErrorKind::ReplayedPacket => "Replayed packet",
+        ProtocolErrorKind::ValueOutOfRange => "Value out of range",
+        ProtocolErrorKind::InvalidPacketInfo => "Invalid packet info",
     }
 }
 
@@ -20,21 +26,25 @@ pub fn hash_string(input: &mut String) -> u32 {
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
     input.hash(&mut hasher);
     let hash = hasher.finish();
-    return hash as u32;
+    hash as u32
 }
 
-/** TODO */
-pub fn calc_packet_crc32(buffer: &Buffer, protocol_id: u32) -> u32 {
-    let protocol_bytes_temp = protocol_id.to_le_bytes();
-    let protocol_bytes = protocol_bytes_temp.as_slice();
-    let mut crc_bytes: Vec<u8> = vec![];
-    crc_bytes.extend_from_slice(protocol_bytes);
-    crc_bytes.extend_from_slice(buffer);
-    return crc32fast::hash(&crc_bytes);
+/**
+    crc32 of a packet, salted with the protocol id so packets from another protocol fail the check.
+    The buffer starts at the crc32 field (after any prefix bytes) and ends at the last byte written.
+    The crc32 field itself is hashed as zeros, so the same function works when writing and reading.
+*/
+pub fn calc_packet_crc32(buffer: &[u8], protocol_id: u32) -> u32 {
+    assert!(buffer.len() >= 4);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&protocol_id.to_le_bytes());
+    hasher.update(&[0, 0, 0, 0]);
+    hasher.update(&buffer[4..]);
+    hasher.finalize()
 }
 
 /** TODO */
-pub fn print_word(bytes: &Vec<u8>, idx: usize) {
+pub fn print_word(bytes: &[u8], idx: usize) {
     println!(
         "BUFFER: {:#010b} {:#010b} {:#010b} {:#010b}",
         bytes[idx],
@@ -1,19 +1,7 @@
-#[macro_export]
-macro_rules! impl_object_for_packet {
-    ($t:ident) => {
-        impl Object for $t {
-            fn serialize_internal_r(&mut self, stream: &mut ReadStream) -> bool {
-                // println!("MACRO: READING PACKET");
-                self.serialize(stream)
-            }
-            fn serialize_internal_w(&mut self, stream: &mut WriteStream) -> bool {
-                // println!("MACRO: WRITING PACKET");
-                self.serialize(stream)
-            }
-        }
-    };
-}
-
+/**
+    Boilerplate PacketFactory methods for a factory with `num_packet_types: u32` and
+    `num_allocated_packets: Cell<u32>` fields. create_packet still has to count its allocations.
+*/
 #[macro_export]
 macro_rules! packet_factory_methods {
     () => {
@@ -22,11 +10,13 @@ macro_rules! packet_factory_methods {
         }
 
         fn destroy_packet(&self) {
-            todo!()
+            let num_allocated_packets = self.num_allocated_packets.get();
+            self.num_allocated_packets
+                .set(num_allocated_packets.saturating_sub(1));
         }
 
         fn get_num_allocated_packets(&self) -> u32 {
-            self.num_allocated_packets
+            self.num_allocated_packets.get()
         }
     };
 }
@@ -111,39 +101,207 @@ Macro for calculating number of bits required for a 32 bit value.
 
     */
 
-// #[macro_export]
-// macro_rules! serialise_int {
-//     ($stream:expr,$value:expr,$min:expr,$max:expr) => {
-//         if $min > $max {
-//             println!("Min greater than max");
-//             return false;
-//         }
+/*
+    serialize_* macros.
 
-//         let mut val: i32;
-
-//         if $stream.is_writing() {
-//             if ($value as i32) < ($min as i32) {
-//                 println!("Val less than min");
-//                 return false;
-//             }
-//             if ($value as i32) > ($max as i32) {
-//                 println!("Val greater than max");
-//                 return false;
-//             }
-//             val = $value;
-//         }
+    Each one serializes a single value through the stream and returns false from the
+    enclosing serialize function as soon as the stream fails or the value is out of range,
+    so truncated or hostile packets stop decoding immediately.
 
-//         if !($stream.serialise_int(&mut val, $min, $max)) {
-//             return false;
-//         }
+    Values are passed as places (ex. self.health, values[0]) like the c++ version,
+    the stream is anything that coerces to &mut dyn Stream.
+*/
 
-//         if $stream.is_reading() {
-//             println!("READING");
-//             println!("Read: {:?}", val);
-//             $value = val as i32;
-//             if (val as i32) < ($min as i32) || (val as i32) > ($max as i32) {
-//                 return false;
-//             }
-//         }
-//     };
-// }
+#[macro_export]
+macro_rules! serialize_int {
+    ($stream:expr, $value:expr, $min:expr, $max:expr) => {
+        if !$crate::protocol::serialization::serialize_int_internal(
+            $stream,
+            &mut $value,
+            $min,
+            $max,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_bits {
+    ($stream:expr, $value:expr, $bits:expr) => {
+        if !$crate::protocol::serialization::serialize_bits_internal($stream, &mut $value, $bits) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_bits_u64 {
+    ($stream:expr, $value:expr, $bits:expr) => {
+        if !$crate::protocol::serialization::serialize_bits_u64_internal(
+            $stream,
+            &mut $value,
+            $bits,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_u64 {
+    ($stream:expr, $value:expr) => {
+        $crate::serialize_bits_u64!($stream, $value, 64)
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_u128 {
+    ($stream:expr, $value:expr) => {
+        if !$crate::protocol::serialization::serialize_u128_internal($stream, &mut $value) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_bool {
+    ($stream:expr, $value:expr) => {
+        if !$crate::protocol::serialization::serialize_bool_internal($stream, &mut $value) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_float {
+    ($stream:expr, $value:expr) => {
+        if !$crate::protocol::serialization::serialize_float_internal($stream, &mut $value) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_compressed_float {
+    ($stream:expr, $value:expr, $min:expr, $max:expr, $precision:expr) => {
+        if !$crate::protocol::serialization::serialize_compressed_float_internal(
+            $stream,
+            &mut $value,
+            $min,
+            $max,
+            $precision,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_vector {
+    ($stream:expr, $value:expr) => {
+        if !$crate::protocol::serialization::serialize_vector_internal($stream, &mut $value) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_compressed_vector {
+    ($stream:expr, $value:expr, $min:expr, $max:expr, $precision:expr) => {
+        if !$crate::protocol::serialization::serialize_compressed_vector_internal(
+            $stream,
+            &mut $value,
+            $min,
+            $max,
+            $precision,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_bytes {
+    ($stream:expr, $bytes:expr, $num_bytes:expr) => {
+        if !$crate::protocol::serialization::serialize_bytes_internal(
+            $stream,
+            &mut $bytes,
+            $num_bytes,
+        ) {
+            return false;
+        }
+    };
+}
+
+/** buffer_size includes room for a terminator, so the longest string is buffer_size - 2 bytes */
+#[macro_export]
+macro_rules! serialize_string {
+    ($stream:expr, $string:expr, $buffer_size:expr) => {
+        if !$crate::protocol::serialization::serialize_string_internal(
+            $stream,
+            &mut $string,
+            $buffer_size,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_align {
+    ($stream:expr) => {
+        if !$crate::protocol::serialization::serialize_align_internal($stream) {
+            return false;
+        }
+    };
+}
+
+/** Serializes the hash of a string, reading back a different hash means the streams are out of sync */
+#[macro_export]
+macro_rules! serialize_check {
+    ($stream:expr, $string:expr) => {
+        if !$crate::protocol::serialization::serialize_check_internal(
+            $stream,
+            &mut ::std::string::String::from($string),
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! serialize_object {
+    ($stream:expr, $object:expr) => {
+        if !$crate::protocol::serialization::serialize_object_internal($stream, &mut $object) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! read_object_index {
+    ($stream:expr, $previous:expr, $current:expr) => {
+        if !$crate::protocol::serialization::serialize_object_index_internal(
+            $stream,
+            &mut $previous,
+            &mut $current,
+        ) {
+            return false;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! write_object_index {
+    ($stream:expr, $previous:expr, $current:expr) => {{
+        let mut current: i32 = $current;
+        if !$crate::protocol::serialization::serialize_object_index_internal(
+            $stream,
+            &mut $previous,
+            &mut current,
+        ) {
+            return false;
+        }
+    }};
+}
@@ -0,0 +1,445 @@
+use crate::bits_required;
+use crate::protocol::{
+    constants::MAX_CHANNELS,
+    packets::object::{Object, Packet},
+    streams::Stream,
+};
+use crate::serialize_int;
+
+use super::{
+    reliable_ordered_channel::ReliableOrderedChannel, unreliable_channel::UnreliableChannel,
+    Channel, ChannelConfig, ChannelPacketData, ChannelType, Message, MessageFactory,
+    CHANNEL_PACKET_DATA_OVERHEAD_BITS,
+};
+
+/**
+    Packet carrying the channel data of every channel that had something to send.
+    The packet type is up to the game, so it fits in with its other packet types.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionPacket {
+    pub packet_type: u32,
+    pub channel_entries: Vec<ChannelPacketData>, // at most one entry per channel
+}
+
+impl ConnectionPacket {
+    pub fn new(packet_type: u32) -> ConnectionPacket {
+        ConnectionPacket {
+            packet_type,
+            channel_entries: vec![],
+        }
+    }
+}
+
+impl Object for ConnectionPacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        let mut num_entries = self.channel_entries.len() as i32;
+        serialize_int!(stream, num_entries, 0, MAX_CHANNELS as i32);
+
+        if stream.is_reading() {
+            self.channel_entries = vec![ChannelPacketData::default(); num_entries as usize];
+        }
+
+        // A channel has at most one entry, a packet repeating one didn't come from generate_packet
+        let mut channels_seen = [false; MAX_CHANNELS];
+        for entry in self.channel_entries.iter_mut() {
+            if !entry.serialize(stream) {
+                return false;
+            }
+            if channels_seen[entry.channel_index as usize] {
+                return false;
+            }
+            channels_seen[entry.channel_index as usize] = true;
+        }
+
+        true
+    }
+}
+
+impl Packet for ConnectionPacket {
+    fn get_packet_type(&self) -> u32 {
+        self.packet_type
+    }
+}
+
+/** Bits ConnectionPacket takes before any channel data */
+const CONNECTION_PACKET_OVERHEAD_BITS: u32 = bits_required!(0u32, MAX_CHANNELS as u32);
+
+/**
+    A set of channels sharing one connection, each with its own delivery guarantee,
+    message factory, bit budget and queue limits. ex. unreliable sequenced for movement,
+    unreliable unordered for voice and reliable ordered for game events.
+
+    Channels are filled in index order, so earlier channels get first pick of the packet.
+    Like the channels themselves, a Connection doesn't send packets. Wrap the ConnectionPacket
+    from generate_packet in your packets (ex. with ReliableEndpoint), pass received ones to
+    process_packet, and acks to process_acks.
+*/
+pub struct Connection<'a> {
+    channels: Vec<Box<dyn Channel + 'a>>,
+    channel_configs: Vec<ChannelConfig>,
+}
+
+impl<'a> Connection<'a> {
+    /** One channel per entry, the channel index is the position in the slice */
+    pub fn new(channels: &[(ChannelConfig, &'a dyn MessageFactory)]) -> Connection<'a> {
+        assert!(!channels.is_empty() && channels.len() <= MAX_CHANNELS);
+
+        Connection {
+            channels: channels
+                .iter()
+                .map(|(config, message_factory)| -> Box<dyn Channel + 'a> {
+                    match config.channel_type {
+                        ChannelType::ReliableOrdered => {
+                            Box::new(ReliableOrderedChannel::new(config, *message_factory))
+                        }
+                        ChannelType::UnreliableUnordered | ChannelType::UnreliableSequenced => {
+                            Box::new(UnreliableChannel::new(config, *message_factory))
+                        }
+                    }
+                })
+                .collect(),
+            channel_configs: channels.iter().map(|(config, _)| *config).collect(),
+        }
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn channel_config(&self, channel_index: usize) -> &ChannelConfig {
+        &self.channel_configs[channel_index]
+    }
+
+    pub fn channel(&mut self, channel_index: usize) -> &mut (dyn Channel + 'a) {
+        self.channels[channel_index].as_mut()
+    }
+
+    /** Queues a message on a channel. Returns false if the channel's send queue is full. */
+    pub fn send_message(&mut self, channel_index: usize, message: Box<dyn Message>) -> bool {
+        self.channels[channel_index].send_message(message)
+    }
+
+    pub fn receive_message(&mut self, channel_index: usize) -> Option<Box<dyn Message>> {
+        self.channels[channel_index].receive_message()
+    }
+
+    pub fn advance_time(&mut self, time: f64) {
+        for channel in self.channels.iter_mut() {
+            channel.advance_time(time);
+        }
+    }
+
+    /**
+        Fills a packet with data from each channel, within available_bits and each channel's budget.
+        packet_sequence is the sequence the packet will be sent with, so acks can be matched up.
+    */
+    pub fn generate_packet(
+        &mut self,
+        packet_type: u32,
+        packet_sequence: u16,
+        available_bits: u32,
+    ) -> ConnectionPacket {
+        let mut packet = ConnectionPacket::new(packet_type);
+        let mut available_bits = available_bits.saturating_sub(CONNECTION_PACKET_OVERHEAD_BITS);
+
+        for (channel_index, channel) in self.channels.iter_mut().enumerate() {
+            let channel_bits = match self.channel_configs[channel_index].packet_budget_bits {
+                Some(budget_bits) => budget_bits.min(available_bits),
+                None => available_bits,
+            };
+            if channel_bits <= CHANNEL_PACKET_DATA_OVERHEAD_BITS {
+                continue;
+            }
+
+            let Some(mut packet_data) = channel.get_packet_data(
+                packet_sequence,
+                channel_bits - CHANNEL_PACKET_DATA_OVERHEAD_BITS,
+            ) else {
+                continue;
+            };
+
+            // The overhead allows for rounding the data up to whole bytes, which data.len() already includes,
+            // so this can charge a few bits more than channel_bits. Never less than the entry takes.
+            packet_data.channel_index = channel_index as u32;
+            available_bits = available_bits.saturating_sub(
+                packet_data.data.len() as u32 * 8 + CHANNEL_PACKET_DATA_OVERHEAD_BITS,
+            );
+            packet.channel_entries.push(packet_data);
+
+            if available_bits == 0 {
+                break;
+            }
+        }
+
+        packet
+    }
+
+    /**
+        Hands each channel its data from a received packet.
+        Returns false without processing anything if the packet has more entries than there are channels,
+        refers to a channel that doesn't exist or has two entries for one channel.
+        Also returns false if a channel could not read its data.
+    */
+    pub fn process_packet(&mut self, packet: &ConnectionPacket) -> bool {
+        if packet.channel_entries.len() > self.channels.len() {
+            return false;
+        }
+
+        let mut channels_seen = vec![false; self.channels.len()];
+        for entry in packet.channel_entries.iter() {
+            let channel_index = entry.channel_index as usize;
+            if channel_index >= self.channels.len() || channels_seen[channel_index] {
+                return false;
+            }
+            channels_seen[channel_index] = true;
+        }
+
+        let mut result = true;
+        for entry in packet.channel_entries.iter() {
+            if !self.channels[entry.channel_index as usize].process_packet_data(entry) {
+                result = false;
+            }
+        }
+        result
+    }
+
+    /** Passes acked packet sequences to every channel, ex. from ReliableEndpoint::receive_acks */
+    pub fn process_acks(&mut self, acks: &[u16]) {
+        for &sequence in acks {
+            for channel in self.channels.iter_mut() {
+                channel.process_ack(sequence);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::protocol::{
+        constants::MAX_PACKET_SIZE,
+        messages::downcast_message,
+        packets::{object::downcast_packet, packet_info::PacketInfo},
+        protocol_config::ProtocolConfig,
+        reliable_endpoint::ReliableEndpoint,
+        streams::{
+            measure_stream::MeasureStream, read_stream::ReadStream, write_stream::WriteStream,
+        },
+        test_helpers::{TestMessage, TestMessageFactory, TestPacketFactory},
+    };
+
+    fn receive_values(connection: &mut Connection, channel_index: usize) -> Vec<i32> {
+        let mut values = vec![];
+        while let Some(message) = connection.receive_message(channel_index) {
+            values.push(
+                downcast_message::<TestMessage>(message.as_ref())
+                    .unwrap()
+                    .value,
+            );
+        }
+        values
+    }
+
+    #[test]
+    fn test_connection() {
+        const RELIABLE: usize = 0;
+        const UNRELIABLE: usize = 1;
+        const SEQUENCED: usize = 2;
+
+        let message_factory = TestMessageFactory::new(1);
+        let channels: [(ChannelConfig, &dyn MessageFactory); 3] = [
+            (ChannelConfig::default(), &message_factory),
+            (
+                ChannelConfig {
+                    channel_type: ChannelType::UnreliableUnordered,
+                    packet_budget_bits: Some(200),
+                    ..ChannelConfig::default()
+                },
+                &message_factory,
+            ),
+            (
+                ChannelConfig {
+                    channel_type: ChannelType::UnreliableSequenced,
+                    ..ChannelConfig::default()
+                },
+                &message_factory,
+            ),
+        ];
+
+        let config = ProtocolConfig::default();
+        let packet_factory = TestPacketFactory::new(1, |packet_type| {
+            Box::new(ConnectionPacket::new(packet_type))
+        });
+        let mut info = PacketInfo::new(&packet_factory);
+        info.allowed_packet_types = vec![0];
+
+        let mut sender = Connection::new(&channels);
+        let mut receiver = Connection::new(&channels);
+        let mut sender_endpoint = ReliableEndpoint::new(&config);
+        let mut receiver_endpoint = ReliableEndpoint::new(&config);
+        let mut rng = StdRng::seed_from_u64(18);
+
+        let mut reliable_values = vec![];
+        let mut unreliable_values = vec![];
+        let mut sequenced_values = vec![];
+        let mut time = 0.0;
+
+        for value in 0..1000 {
+            time += 0.05;
+            sender.advance_time(time);
+            receiver.advance_time(time);
+
+            if value < 200 {
+                assert!(sender.send_message(RELIABLE, Box::new(TestMessage { value })));
+            }
+            assert!(sender.send_message(UNRELIABLE, Box::new(TestMessage { value })));
+            assert!(sender.send_message(SEQUENCED, Box::new(TestMessage { value })));
+
+            // Sender to receiver, 20% of packets lost
+            let mut packet = sender.generate_packet(0, sender_endpoint.next_sequence(), 8 * 1000);
+            let mut buffer = vec![0; 1200];
+            let bytes_written = sender_endpoint.write_packet(&info, &mut packet, &mut buffer, 1200);
+            assert!(bytes_written > 0);
+
+            if rng.gen_range(0..100) >= 20 {
+                let packet = receiver_endpoint
+                    .read_packet(&info, &buffer[..bytes_written as usize])
+                    .unwrap();
+                let packet = downcast_packet::<ConnectionPacket>(packet.as_ref()).unwrap();
+                assert!(receiver.process_packet(packet));
+            }
+
+            reliable_values.extend(receive_values(&mut receiver, RELIABLE));
+            unreliable_values.extend(receive_values(&mut receiver, UNRELIABLE));
+            sequenced_values.extend(receive_values(&mut receiver, SEQUENCED));
+
+            // Receiver acks back, 20% of acks lost
+            let mut ack_packet = ConnectionPacket::new(0);
+            let mut buffer = vec![0; 64];
+            let bytes_written =
+                receiver_endpoint.write_packet(&info, &mut ack_packet, &mut buffer, 64);
+            if rng.gen_range(0..100) >= 20 {
+                sender_endpoint
+                    .read_packet(&info, &buffer[..bytes_written as usize])
+                    .unwrap();
+                sender.process_acks(&sender_endpoint.receive_acks());
+            }
+        }
+
+        // Reliable messages all arrive, in order
+        assert_eq!(reliable_values, (0..200).collect::<Vec<i32>>());
+
+        // Unreliable messages arrive at most once, some are lost
+        assert!(!unreliable_values.is_empty() && unreliable_values.len() < 1000);
+        let mut deduplicated = unreliable_values.clone();
+        deduplicated.dedup();
+        assert_eq!(deduplicated, unreliable_values);
+
+        // Sequenced messages only ever move forward
+        assert!(!sequenced_values.is_empty());
+        assert!(sequenced_values.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_generate_packet_fills_budget() {
+        let message_factory = TestMessageFactory::new(1);
+        let channel_config = ChannelConfig {
+            channel_type: ChannelType::UnreliableUnordered,
+            ..ChannelConfig::default()
+        };
+        let channels = [(channel_config, &message_factory as &dyn MessageFactory); 3];
+
+        // Budgets from too small for anything, through ones that run out part way into a channel,
+        // to more than every channel needs
+        for extra_bits in 0..200 {
+            let available_bits =
+                extra_bits + CHANNEL_PACKET_DATA_OVERHEAD_BITS + CONNECTION_PACKET_OVERHEAD_BITS;
+
+            let mut connection = Connection::new(&channels);
+            for channel_index in 0..channels.len() {
+                for value in 0..2 {
+                    assert!(connection.send_message(channel_index, Box::new(TestMessage { value })));
+                }
+            }
+
+            let mut packet = connection.generate_packet(0, 0, available_bits);
+            let mut stream = MeasureStream::new(MAX_PACKET_SIZE);
+            assert!(packet.serialize(&mut stream));
+            assert!(stream.get_worst_case_bits() <= available_bits);
+        }
+
+        // The case that used to underflow: the first channel uses all but a few bits of the budget
+        let mut connection = Connection::new(&channels[..2]);
+        for value in 0..2 {
+            assert!(connection.send_message(0, Box::new(TestMessage { value })));
+        }
+        assert!(connection.send_message(1, Box::new(TestMessage { value: 2 })));
+        let packet = connection.generate_packet(
+            0,
+            0,
+            41 + CHANNEL_PACKET_DATA_OVERHEAD_BITS + CONNECTION_PACKET_OVERHEAD_BITS,
+        );
+        assert_eq!(packet.channel_entries.len(), 1);
+    }
+
+    #[test]
+    fn test_connection_bad_channel_index() {
+        let message_factory = TestMessageFactory::new(1);
+        let mut connection = Connection::new(&[(ChannelConfig::default(), &message_factory)]);
+
+        let mut packet = ConnectionPacket::new(0);
+        packet.channel_entries.push(ChannelPacketData {
+            channel_index: 1,
+            data: vec![0],
+        });
+        assert!(!connection.process_packet(&packet));
+    }
+
+    #[test]
+    fn test_connection_duplicate_channel_index() {
+        let message_factory = TestMessageFactory::new(1);
+        let mut connection = Connection::new(&[
+            (ChannelConfig::default(), &message_factory),
+            (ChannelConfig::default(), &message_factory),
+        ]);
+
+        let entry = ChannelPacketData {
+            channel_index: 1,
+            data: vec![0],
+        };
+        let mut packet = ConnectionPacket::new(0);
+        packet.channel_entries = vec![entry.clone(), entry.clone()];
+        assert!(!connection.process_packet(&packet));
+
+        // More entries than channels
+        packet.channel_entries = (0..3)
+            .map(|channel_index| ChannelPacketData {
+                channel_index,
+                data: vec![0],
+            })
+            .collect();
+        assert!(!connection.process_packet(&packet));
+
+        // Repeated entries are refused on the wire as well, in both directions
+        packet.channel_entries = vec![entry.clone(), entry.clone()];
+        let mut buffer = vec![0; 64];
+        assert!(!packet.serialize(&mut WriteStream::new(&mut buffer)));
+
+        let bytes_written = {
+            let mut stream = WriteStream::new(&mut buffer);
+            let mut num_entries = 2;
+            assert!(stream.serialise_int(&mut num_entries, 0, MAX_CHANNELS as i32));
+            for mut entry in [entry.clone(), entry] {
+                assert!(entry.serialize(&mut stream));
+            }
+            assert!(stream.flush());
+            stream.get_bytes_processed() as usize
+        };
+        let mut read_packet = ConnectionPacket::new(0);
+        let mut stream = ReadStream::new(&buffer[..bytes_written]);
+        assert!(!read_packet.serialize(&mut stream));
+    }
+}
@@ -0,0 +1,193 @@
+use std::any::Any;
+
+use crate::bits_required;
+use crate::protocol::{
+    constants::{MAX_CHANNELS, MAX_PACKET_SIZE},
+    packets::object::{DynObject, Object},
+    streams::{measure_stream::MeasureStream, Stream},
+};
+use crate::{serialize_bytes, serialize_int};
+
+pub mod connection;
+pub mod reliable_ordered_channel;
+pub mod unreliable_channel;
+
+/**
+    Messages are small objects sent over a channel, many of them packed into each packet.
+    Like packets, each message type has its own serialize function and a type number
+    that is sent with it, so the receiver knows which message to create.
+*/
+pub trait Message: DynObject + Any {
+    fn get_message_type(&self) -> u32;
+}
+
+/** Looks at a received message as its concrete type, if it is a T */
+pub fn downcast_message<T: Message>(message: &dyn Message) -> Option<&T> {
+    (message as &dyn Any).downcast_ref::<T>()
+}
+
+/** Creates messages by type when reading them, the message equivalent of PacketFactory */
+pub trait MessageFactory {
+    fn get_num_message_types(&self) -> u32;
+    fn create_message(&self, message_type: u32) -> Box<dyn Message>;
+}
+
+/** Delivery guarantee of a channel */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChannelType {
+    ReliableOrdered,     // resent until acked, delivered exactly once and in order
+    UnreliableUnordered, // sent once, delivered if it arrives, in any order
+    UnreliableSequenced, // sent once, anything older than the newest message received is dropped
+}
+
+/** Settings for a channel. Queue and buffer sizes must be powers of two, they are SequenceBuffers. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelConfig {
+    pub channel_type: ChannelType,
+    pub packet_budget_bits: Option<u32>, // most bits the channel can take in a packet. None to use whatever is left
+    pub max_messages_per_packet: u32,    // most messages the channel will put in one packet
+    pub message_send_queue_size: usize, // messages queued to send (and not yet acked) before send_message fails
+    pub message_receive_queue_size: usize, // messages buffered before they are received, the rest are dropped
+    pub sent_packet_buffer_size: usize, // sent packets remembered, to know which messages an ack covers
+    pub message_resend_time: f64,       // seconds before an unacked message is sent again
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            channel_type: ChannelType::ReliableOrdered,
+            packet_budget_bits: None,
+            max_messages_per_packet: 64,
+            message_send_queue_size: 1024,
+            message_receive_queue_size: 1024,
+            sent_packet_buffer_size: 1024,
+            message_resend_time: 0.1,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /**
+        Most bits of channel data one packet can carry for this channel.
+        Without a packet_budget_bits, the channel could have all of a MAX_PACKET_SIZE packet.
+    */
+    pub fn max_packet_data_bits(&self) -> u32 {
+        self.packet_budget_bits
+            .unwrap_or(MAX_PACKET_SIZE as u32 * 8)
+            .saturating_sub(CHANNEL_PACKET_DATA_OVERHEAD_BITS)
+    }
+}
+
+/**
+    A stream of messages with one delivery guarantee.
+
+    Channels don't send packets themselves. get_packet_data is called for each packet written,
+    process_packet_data with the channel's data from each packet received,
+    and process_ack for each of our packets the other end acked.
+*/
+pub trait Channel {
+    /** Moves the channel's clock forward, in seconds */
+    fn advance_time(&mut self, time: f64);
+
+    /** True if another message can be queued with send_message */
+    fn can_send_message(&self) -> bool;
+
+    /** True if there are queued messages still to be sent (or acked, for reliable channels) */
+    fn has_messages_to_send(&self) -> bool;
+
+    /** Queues a message to be sent. Returns false if the send queue is full, or the message fails to serialize. */
+    fn send_message(&mut self, message: Box<dyn Message>) -> bool;
+
+    /** Next message received, if there is one */
+    fn receive_message(&mut self) -> Option<Box<dyn Message>>;
+
+    /**
+        Picks the messages to put in the packet with this sequence, within available_bits.
+        Returns None if there is nothing to send right now.
+    */
+    fn get_packet_data(
+        &mut self,
+        packet_sequence: u16,
+        available_bits: u32,
+    ) -> Option<ChannelPacketData>;
+
+    /** Reads the messages in channel data received from the other end. Returns false if the data could not be read. */
+    fn process_packet_data(&mut self, packet_data: &ChannelPacketData) -> bool;
+
+    /** The packet with this sequence was acked by the other end */
+    fn process_ack(&mut self, packet_sequence: u16);
+}
+
+/**
+    The messages a channel put in one packet, already serialized by the channel.
+
+    Channels serialize their own messages with their own MessageFactory, so this only carries bytes.
+    That way the packet carrying it can be created by a PacketFactory like any other packet.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelPacketData {
+    pub channel_index: u32, // channel the data belongs to, set by Connection
+    pub data: Vec<u8>,
+}
+
+impl Object for ChannelPacketData {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        let mut channel_index = self.channel_index as i32;
+        serialize_int!(stream, channel_index, 0, MAX_CHANNELS as i32 - 1);
+        self.channel_index = channel_index as u32;
+
+        let mut num_bytes = self.data.len() as i32;
+        serialize_int!(stream, num_bytes, 1, MAX_PACKET_SIZE as i32);
+
+        if stream.is_reading() {
+            if num_bytes as u32 * 8 > stream.get_bits_remaining() {
+                return false;
+            }
+            self.data = vec![0; num_bytes as usize];
+        }
+
+        serialize_bytes!(stream, self.data, num_bytes as u32);
+        true
+    }
+}
+
+/** Bits ChannelPacketData adds around the channel's data, worst case */
+pub const CHANNEL_PACKET_DATA_OVERHEAD_BITS: u32 = bits_required!(0u32, MAX_CHANNELS as u32 - 1)
+    + bits_required!(1u32, MAX_PACKET_SIZE as u32)
+    + 7 // align before the data
+    + 7; // data is rounded up to whole bytes
+
+/** Writes or reads a message type. Nothing is sent when the factory only has one message type. */
+pub fn serialize_message_type(
+    stream: &mut dyn Stream,
+    message_type: &mut u32,
+    num_message_types: u32,
+) -> bool {
+    if num_message_types > 1 {
+        let mut value = *message_type as i32;
+        serialize_int!(stream, value, 0, num_message_types as i32 - 1);
+        *message_type = value as u32;
+    } else {
+        *message_type = 0;
+    }
+    true
+}
+
+/** Bits serialize_message_type takes for this factory */
+pub fn message_type_bits(message_factory: &dyn MessageFactory) -> u32 {
+    let num_message_types = message_factory.get_num_message_types();
+    if num_message_types > 1 {
+        bits_required!(0u32, num_message_types - 1)
+    } else {
+        0
+    }
+}
+
+/** Worst case bits a message takes to serialize, None if it fails to serialize */
+pub fn measure_message(message: &mut dyn Message) -> Option<u32> {
+    let mut measure_stream = MeasureStream::new(MAX_PACKET_SIZE);
+    if !message.serialize_dyn(&mut measure_stream) {
+        return None;
+    }
+    Some(measure_stream.get_worst_case_bits())
+}
@@ -0,0 +1,479 @@
+use crate::bits_required;
+use crate::protocol::{
+    sequence::Sequence16,
+    sequence_buffer::SequenceBuffer,
+    streams::Stream,
+    streams::{read_stream::ReadStream, write_stream::WriteStream},
+};
+
+use super::{
+    measure_message, message_type_bits, serialize_message_type, Channel, ChannelConfig,
+    ChannelPacketData, Message, MessageFactory,
+};
+
+struct MessageSendQueueEntry {
+    message: Box<dyn Message>,
+    measured_bits: u32,          // bits the message takes to serialize, worst case
+    time_last_sent: Option<f64>, // None until the message is first put in a packet
+}
+
+struct SentPacketEntry {
+    acked: bool,
+    message_ids: Vec<u16>, // messages included in this packet
+}
+
+/**
+    Messages are delivered exactly once and in the order they were sent.
+
+    Each queued message gets a 16 bit message id. Unacked messages are put in every outgoing packet
+    (at most once per message_resend_time) until a packet carrying them is acked, and the receiver
+    holds on to messages that arrive early until every message before them has been delivered.
+
+    The channel does not send or ack packets itself. Call get_packet_data with the sequence of the
+    packet being written, process_packet_data for the channel data in each packet received,
+    and process_ack for each acked packet sequence, ex. from ReliableEndpoint::receive_acks.
+*/
+pub struct ReliableOrderedChannel<'a> {
+    config: ChannelConfig,
+    message_factory: &'a dyn MessageFactory, // creates messages by type when reading packets
+    time: f64,
+
+    send_message_id: u16,    // id of the next message queued with send_message
+    receive_message_id: u16, // id of the next message to deliver with receive_message
+    oldest_unacked_message_id: u16, // messages before this one have all been acked
+    message_send_queue: SequenceBuffer<MessageSendQueueEntry>,
+    message_receive_queue: SequenceBuffer<Box<dyn Message>>,
+    sent_packets: SequenceBuffer<SentPacketEntry>,
+}
+
+impl<'a> ReliableOrderedChannel<'a> {
+    pub fn new(
+        config: &ChannelConfig,
+        message_factory: &'a dyn MessageFactory,
+    ) -> ReliableOrderedChannel<'a> {
+        assert!(config.max_messages_per_packet > 0);
+
+        ReliableOrderedChannel {
+            config: *config,
+            message_factory,
+            time: 0.0,
+            send_message_id: 0,
+            receive_message_id: 0,
+            oldest_unacked_message_id: 0,
+            message_send_queue: SequenceBuffer::new(config.message_send_queue_size),
+            message_receive_queue: SequenceBuffer::new(config.message_receive_queue_size),
+            sent_packets: SequenceBuffer::new(config.sent_packet_buffer_size),
+        }
+    }
+
+    fn write_messages(&mut self, message_ids: &[u16], bits: u32) -> Option<ChannelPacketData> {
+        let num_message_types = self.message_factory.get_num_message_types();
+        let mut data = vec![0; bits.div_ceil(32) as usize * 4];
+        let bytes_written = {
+            let mut stream = WriteStream::new(&mut data);
+
+            let mut num_messages = message_ids.len() as i32;
+            if !stream.serialise_int(
+                &mut num_messages,
+                0,
+                self.config.max_messages_per_packet as i32,
+            ) {
+                return None;
+            }
+
+            for message_id in message_ids {
+                let entry = self.message_send_queue.find_mut(*message_id).unwrap();
+
+                let mut id = *message_id as u32;
+                let mut message_type = entry.message.get_message_type();
+                if !stream.serialize_bits(&mut id, 16)
+                    || !serialize_message_type(&mut stream, &mut message_type, num_message_types)
+                    || !entry.message.serialize_dyn(&mut stream)
+                {
+                    return None;
+                }
+            }
+
+            if !stream.flush() {
+                return None;
+            }
+            stream.get_bytes_processed()
+        };
+
+        data.truncate(bytes_written as usize);
+        Some(ChannelPacketData {
+            channel_index: 0,
+            data,
+        })
+    }
+}
+
+impl<'a> Channel for ReliableOrderedChannel<'a> {
+    /** Moves the channel's clock forward, in seconds. Used to decide when to resend messages. */
+    fn advance_time(&mut self, time: f64) {
+        self.time = time;
+    }
+
+    /** True if another message can be queued with send_message */
+    fn can_send_message(&self) -> bool {
+        (Sequence16(self.send_message_id).difference(Sequence16(self.oldest_unacked_message_id))
+            as usize)
+            < self.config.message_send_queue_size
+    }
+
+    /** True if there are queued messages the other end has not acked yet */
+    fn has_messages_to_send(&self) -> bool {
+        self.oldest_unacked_message_id != self.send_message_id
+    }
+
+    /**
+        Queues a message to be sent. Returns false if the send queue is full, the message fails to serialize,
+        or the message is too big to ever fit in a packet (see ChannelConfig::max_packet_data_bits).
+    */
+    fn send_message(&mut self, mut message: Box<dyn Message>) -> bool {
+        if !self.can_send_message() {
+            return false;
+        }
+
+        let Some(measured_bits) = measure_message(message.as_mut()) else {
+            return false;
+        };
+
+        // a message that never fits would hold back every message sent after it
+        let packet_bits = bits_required!(0u32, self.config.max_messages_per_packet)
+            + 16
+            + message_type_bits(self.message_factory)
+            + measured_bits;
+        if packet_bits > self.config.max_packet_data_bits() {
+            return false;
+        }
+
+        self.message_send_queue.insert(
+            self.send_message_id,
+            MessageSendQueueEntry {
+                message,
+                measured_bits,
+                time_last_sent: None,
+            },
+        );
+        self.send_message_id = self.send_message_id.wrapping_add(1);
+        true
+    }
+
+    /** Next message in order, if it has arrived */
+    fn receive_message(&mut self) -> Option<Box<dyn Message>> {
+        let message = self.message_receive_queue.remove(self.receive_message_id)?;
+        self.receive_message_id = self.receive_message_id.wrapping_add(1);
+        Some(message)
+    }
+
+    /**
+        Picks the messages to put in the packet with this sequence, within available_bits.
+        Returns None if there is nothing to send right now.
+    */
+    fn get_packet_data(
+        &mut self,
+        packet_sequence: u16,
+        available_bits: u32,
+    ) -> Option<ChannelPacketData> {
+        if !self.has_messages_to_send() {
+            return None;
+        }
+
+        let message_type_bits = message_type_bits(self.message_factory);
+        let mut used_bits = bits_required!(0u32, self.config.max_messages_per_packet);
+        let mut message_ids: Vec<u16> = vec![];
+
+        for message_id in Sequence16::range(
+            Sequence16(self.oldest_unacked_message_id),
+            Sequence16(self.send_message_id),
+        ) {
+            if message_ids.len() as u32 == self.config.max_messages_per_packet {
+                break;
+            }
+
+            // the receiver can't buffer messages this far ahead of the ones it is waiting for
+            if message_id.difference(Sequence16(self.oldest_unacked_message_id)) as usize
+                >= self.config.message_receive_queue_size
+            {
+                break;
+            }
+
+            let time = self.time;
+            let resend_time = self.config.message_resend_time;
+            let Some(entry) = self.message_send_queue.find_mut(message_id.0) else {
+                continue; // already acked
+            };
+
+            if entry
+                .time_last_sent
+                .is_some_and(|time_last_sent| time_last_sent + resend_time > time)
+            {
+                continue;
+            }
+
+            // a smaller message further on may still fit
+            let message_bits = 16 + message_type_bits + entry.measured_bits;
+            if used_bits + message_bits > available_bits {
+                continue;
+            }
+
+            used_bits += message_bits;
+            message_ids.push(message_id.0);
+        }
+
+        if message_ids.is_empty() {
+            return None;
+        }
+
+        // only counts as sent once it is actually written
+        let data = self.write_messages(&message_ids, used_bits)?;
+        for message_id in message_ids.iter() {
+            self.message_send_queue
+                .find_mut(*message_id)
+                .unwrap()
+                .time_last_sent = Some(self.time);
+        }
+
+        self.sent_packets.insert(
+            packet_sequence,
+            SentPacketEntry {
+                acked: false,
+                message_ids,
+            },
+        );
+
+        Some(data)
+    }
+
+    /**
+        Reads the messages in channel data received from the other end, and buffers them for receive_message.
+        Messages already delivered are ignored. Returns false if the data could not be read.
+    */
+    fn process_packet_data(&mut self, packet_data: &ChannelPacketData) -> bool {
+        let mut stream = ReadStream::new(&packet_data.data);
+        let num_message_types = self.message_factory.get_num_message_types();
+
+        let mut num_messages: i32 = 0;
+        if !stream.serialise_int(
+            &mut num_messages,
+            0,
+            self.config.max_messages_per_packet as i32,
+        ) || num_messages == 0
+            || num_messages as u32 > self.config.max_messages_per_packet
+        {
+            return false;
+        }
+
+        // read every message before buffering any, so a bad packet is dropped as a whole
+        let mut messages: Vec<(u16, Box<dyn Message>)> = vec![];
+        for _ in 0..num_messages {
+            let mut message_id: u32 = 0;
+            let mut message_type: u32 = 0;
+            if !stream.serialize_bits(&mut message_id, 16)
+                || !serialize_message_type(&mut stream, &mut message_type, num_message_types)
+            {
+                return false;
+            }
+
+            let mut message = self.message_factory.create_message(message_type);
+            if !message.serialize_dyn(&mut stream) {
+                return false;
+            }
+            messages.push((message_id as u16, message));
+        }
+
+        for (message_id, message) in messages {
+            let message_id = Sequence16(message_id);
+            let receive_message_id = Sequence16(self.receive_message_id);
+
+            // already delivered, this is a resend
+            if message_id.sequence_less_than(receive_message_id) {
+                continue;
+            }
+
+            // too far ahead to buffer
+            if message_id.difference(receive_message_id) as usize
+                >= self.config.message_receive_queue_size
+            {
+                continue;
+            }
+
+            if !self.message_receive_queue.exists(message_id.0) {
+                self.message_receive_queue.insert(message_id.0, message);
+            }
+        }
+
+        true
+    }
+
+    /** The packet with this sequence was acked, so the messages it carried don't need sending again */
+    fn process_ack(&mut self, packet_sequence: u16) {
+        let Some(sent_packet) = self.sent_packets.find_mut(packet_sequence) else {
+            return;
+        };
+        if sent_packet.acked {
+            return;
+        }
+        sent_packet.acked = true;
+
+        for message_id in sent_packet.message_ids.iter() {
+            self.message_send_queue.remove(*message_id);
+        }
+
+        while self.has_messages_to_send()
+            && !self
+                .message_send_queue
+                .exists(self.oldest_unacked_message_id)
+        {
+            self.oldest_unacked_message_id = self.oldest_unacked_message_id.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::protocol::messages::downcast_message;
+    use crate::protocol::test_helpers::{TestMessage, TestMessageFactory, TestPayloadMessage};
+
+    fn message_value(message: &dyn Message) -> i32 {
+        match downcast_message::<TestMessage>(message) {
+            Some(message) => message.value,
+            None => {
+                downcast_message::<TestPayloadMessage>(message)
+                    .unwrap()
+                    .value
+            }
+        }
+    }
+
+    #[test]
+    fn test_reliable_ordered_channel() {
+        const NUM_MESSAGES: i32 = 1000;
+
+        let message_factory = TestMessageFactory::new(2);
+        let config = ChannelConfig::default();
+        let mut sender = ReliableOrderedChannel::new(&config, &message_factory);
+        let mut receiver = ReliableOrderedChannel::new(&config, &message_factory);
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let mut next_value_to_send = 0;
+        let mut received_values: Vec<i32> = vec![];
+        let mut packet_sequence: u16 = 0;
+        let mut time = 0.0;
+
+        // 30% of packets are lost, and 30% of the acks for the ones that arrive
+        for _ in 0..10000 {
+            while next_value_to_send < NUM_MESSAGES && sender.can_send_message() {
+                let message: Box<dyn Message> = if next_value_to_send % 10 == 0 {
+                    Box::new(TestPayloadMessage {
+                        value: next_value_to_send,
+                        payload: vec![next_value_to_send as u8; 200],
+                    })
+                } else {
+                    Box::new(TestMessage {
+                        value: next_value_to_send,
+                    })
+                };
+                assert!(sender.send_message(message));
+                next_value_to_send += 1;
+            }
+
+            time += 0.05;
+            sender.advance_time(time);
+
+            if let Some(packet_data) = sender.get_packet_data(packet_sequence, 2000) {
+                assert!(packet_data.data.len() * 8 <= 2000 + 32);
+                if rng.gen_range(0..100) >= 30 {
+                    assert!(receiver.process_packet_data(&packet_data));
+                    if rng.gen_range(0..100) >= 30 {
+                        sender.process_ack(packet_sequence);
+                    }
+                }
+            }
+            packet_sequence = packet_sequence.wrapping_add(1);
+
+            while let Some(message) = receiver.receive_message() {
+                received_values.push(message_value(message.as_ref()));
+            }
+
+            if received_values.len() as i32 == NUM_MESSAGES {
+                break;
+            }
+        }
+
+        // every message arrives exactly once, in order
+        assert_eq!(received_values, (0..NUM_MESSAGES).collect::<Vec<i32>>());
+        assert!(packet_sequence > 100);
+    }
+
+    #[test]
+    fn test_reliable_ordered_channel_limits() {
+        let message_factory = TestMessageFactory::new(2);
+        let config = ChannelConfig {
+            message_send_queue_size: 4,
+            ..ChannelConfig::default()
+        };
+        let mut sender = ReliableOrderedChannel::new(&config, &message_factory);
+
+        for value in 0..4 {
+            assert!(sender.send_message(Box::new(TestMessage { value })));
+        }
+        assert!(!sender.can_send_message());
+        assert!(!sender.send_message(Box::new(TestMessage { value: 4 })));
+
+        // Not resent until the resend time has passed
+        assert!(sender.get_packet_data(0, 1000).is_some());
+        assert!(sender.get_packet_data(1, 1000).is_none());
+        sender.advance_time(1.0);
+        assert!(sender.get_packet_data(2, 1000).is_some());
+
+        // Acking any packet that carried the messages frees up the queue
+        sender.process_ack(2);
+        assert!(!sender.has_messages_to_send());
+        assert!(sender.send_message(Box::new(TestMessage { value: 4 })));
+
+        // Garbage is rejected
+        let mut receiver = ReliableOrderedChannel::new(&config, &message_factory);
+        assert!(!receiver.process_packet_data(&ChannelPacketData {
+            channel_index: 0,
+            data: vec![0xFF; 3],
+        }));
+        assert!(receiver.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_reliable_ordered_channel_oversize_message() {
+        let message_factory = TestMessageFactory::new(2);
+        let config = ChannelConfig {
+            packet_budget_bits: Some(400),
+            ..ChannelConfig::default()
+        };
+        let mut sender = ReliableOrderedChannel::new(&config, &message_factory);
+        let mut receiver = ReliableOrderedChannel::new(&config, &message_factory);
+
+        // Too big for the budget, it is turned away instead of stalling the channel
+        assert!(sender.send_message(Box::new(TestMessage { value: 0 })));
+        assert!(!sender.send_message(Box::new(TestPayloadMessage {
+            value: 1,
+            payload: vec![0; 100],
+        })));
+        assert!(sender.send_message(Box::new(TestMessage { value: 2 })));
+
+        let packet_data = sender
+            .get_packet_data(0, config.max_packet_data_bits())
+            .unwrap();
+        assert!(receiver.process_packet_data(&packet_data));
+        sender.process_ack(0);
+        assert!(!sender.has_messages_to_send());
+
+        let mut received_values = vec![];
+        while let Some(message) = receiver.receive_message() {
+            received_values.push(message_value(message.as_ref()));
+        }
+        assert_eq!(received_values, vec![0, 2]);
+    }
+}
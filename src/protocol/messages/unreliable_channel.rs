@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+
+use crate::bits_required;
+use crate::protocol::{
+    sequence::Sequence16,
+    streams::Stream,
+    streams::{read_stream::ReadStream, write_stream::WriteStream},
+};
+
+use super::{
+    measure_message, message_type_bits, serialize_message_type, Channel, ChannelConfig,
+    ChannelPacketData, ChannelType, Message, MessageFactory,
+};
+
+struct MessageSendQueueEntry {
+    message_id: u16, // only sent by sequenced channels
+    message: Box<dyn Message>,
+    measured_bits: u32, // bits the message takes to serialize, worst case
+}
+
+/**
+    Messages are sent once, in the next packet written, and never resent.
+
+    - UnreliableUnordered: every message that arrives is delivered, in whatever order it arrives.
+    - UnreliableSequenced: messages carry a 16 bit id, and anything not newer than the newest
+      message received is dropped. Good for state where only the latest value matters, ex. movement.
+
+    Messages that don't fit in the packet's budget are dropped instead of waiting for the next packet.
+*/
+pub struct UnreliableChannel<'a> {
+    config: ChannelConfig,
+    message_factory: &'a dyn MessageFactory, // creates messages by type when reading packets
+
+    send_message_id: u16, // id of the next message queued with send_message
+    newest_received_message_id: Option<u16>, // sequenced channels drop anything not newer than this
+    message_send_queue: VecDeque<MessageSendQueueEntry>,
+    message_receive_queue: VecDeque<Box<dyn Message>>,
+
+    pub num_messages_dropped: u64, // didn't fit in a packet, arrived out of sequence or the receive queue was full
+}
+
+impl<'a> UnreliableChannel<'a> {
+    pub fn new(
+        config: &ChannelConfig,
+        message_factory: &'a dyn MessageFactory,
+    ) -> UnreliableChannel<'a> {
+        assert!(config.channel_type != ChannelType::ReliableOrdered);
+        assert!(config.max_messages_per_packet > 0);
+
+        UnreliableChannel {
+            config: *config,
+            message_factory,
+            send_message_id: 0,
+            newest_received_message_id: None,
+            message_send_queue: VecDeque::new(),
+            message_receive_queue: VecDeque::new(),
+            num_messages_dropped: 0,
+        }
+    }
+
+    fn is_sequenced(&self) -> bool {
+        self.config.channel_type == ChannelType::UnreliableSequenced
+    }
+}
+
+impl<'a> Channel for UnreliableChannel<'a> {
+    fn advance_time(&mut self, _time: f64) {}
+
+    fn can_send_message(&self) -> bool {
+        self.message_send_queue.len() < self.config.message_send_queue_size
+    }
+
+    fn has_messages_to_send(&self) -> bool {
+        !self.message_send_queue.is_empty()
+    }
+
+    fn send_message(&mut self, mut message: Box<dyn Message>) -> bool {
+        if !self.can_send_message() {
+            return false;
+        }
+
+        let Some(measured_bits) = measure_message(message.as_mut()) else {
+            return false;
+        };
+
+        self.message_send_queue.push_back(MessageSendQueueEntry {
+            message_id: self.send_message_id,
+            message,
+            measured_bits,
+        });
+        self.send_message_id = self.send_message_id.wrapping_add(1);
+        true
+    }
+
+    fn receive_message(&mut self) -> Option<Box<dyn Message>> {
+        self.message_receive_queue.pop_front()
+    }
+
+    fn get_packet_data(
+        &mut self,
+        _packet_sequence: u16,
+        available_bits: u32,
+    ) -> Option<ChannelPacketData> {
+        if !self.has_messages_to_send() {
+            return None;
+        }
+
+        let message_id_bits = if self.is_sequenced() { 16 } else { 0 };
+        let message_type_bits = message_type_bits(self.message_factory);
+        let mut used_bits = bits_required!(0u32, self.config.max_messages_per_packet);
+        let mut messages: Vec<MessageSendQueueEntry> = vec![];
+
+        while messages.len() < self.config.max_messages_per_packet as usize {
+            let Some(entry) = self.message_send_queue.pop_front() else {
+                break;
+            };
+
+            let message_bits = message_id_bits + message_type_bits + entry.measured_bits;
+            if used_bits + message_bits > available_bits {
+                self.num_messages_dropped += 1;
+                continue;
+            }
+
+            used_bits += message_bits;
+            messages.push(entry);
+        }
+
+        if messages.is_empty() {
+            return None;
+        }
+
+        let num_message_types = self.message_factory.get_num_message_types();
+        let mut data = vec![0; used_bits.div_ceil(32) as usize * 4];
+        let bytes_written = {
+            let mut stream = WriteStream::new(&mut data);
+
+            let mut num_messages = messages.len() as i32;
+            if !stream.serialise_int(
+                &mut num_messages,
+                0,
+                self.config.max_messages_per_packet as i32,
+            ) {
+                return None;
+            }
+
+            for entry in messages.iter_mut() {
+                let mut message_id = entry.message_id as u32;
+                let mut message_type = entry.message.get_message_type();
+                if (self.is_sequenced() && !stream.serialize_bits(&mut message_id, 16))
+                    || !serialize_message_type(&mut stream, &mut message_type, num_message_types)
+                    || !entry.message.serialize_dyn(&mut stream)
+                {
+                    return None;
+                }
+            }
+
+            if !stream.flush() {
+                return None;
+            }
+            stream.get_bytes_processed()
+        };
+
+        data.truncate(bytes_written as usize);
+        Some(ChannelPacketData {
+            channel_index: 0,
+            data,
+        })
+    }
+
+    fn process_packet_data(&mut self, packet_data: &ChannelPacketData) -> bool {
+        let mut stream = ReadStream::new(&packet_data.data);
+        let num_message_types = self.message_factory.get_num_message_types();
+
+        let mut num_messages: i32 = 0;
+        if !stream.serialise_int(
+            &mut num_messages,
+            0,
+            self.config.max_messages_per_packet as i32,
+        ) || num_messages == 0
+            || num_messages as u32 > self.config.max_messages_per_packet
+        {
+            return false;
+        }
+
+        // read every message before delivering any, so a bad packet is dropped as a whole
+        let mut messages: Vec<(u16, Box<dyn Message>)> = vec![];
+        for _ in 0..num_messages {
+            let mut message_id: u32 = 0;
+            let mut message_type: u32 = 0;
+            if (self.is_sequenced() && !stream.serialize_bits(&mut message_id, 16))
+                || !serialize_message_type(&mut stream, &mut message_type, num_message_types)
+            {
+                return false;
+            }
+
+            let mut message = self.message_factory.create_message(message_type);
+            if !message.serialize_dyn(&mut stream) {
+                return false;
+            }
+            messages.push((message_id as u16, message));
+        }
+
+        for (message_id, message) in messages {
+            if self.is_sequenced() {
+                if let Some(newest_received_message_id) = self.newest_received_message_id {
+                    if !Sequence16(message_id)
+                        .sequence_greater_than(Sequence16(newest_received_message_id))
+                    {
+                        self.num_messages_dropped += 1;
+                        continue;
+                    }
+                }
+                self.newest_received_message_id = Some(message_id);
+            }
+
+            if self.message_receive_queue.len() >= self.config.message_receive_queue_size {
+                self.num_messages_dropped += 1;
+                continue;
+            }
+            self.message_receive_queue.push_back(message);
+        }
+
+        true
+    }
+
+    fn process_ack(&mut self, _packet_sequence: u16) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::messages::downcast_message;
+    use crate::protocol::test_helpers::{TestMessage, TestMessageFactory};
+
+    fn receive_values(channel: &mut UnreliableChannel) -> Vec<i32> {
+        let mut values = vec![];
+        while let Some(message) = channel.receive_message() {
+            values.push(
+                downcast_message::<TestMessage>(message.as_ref())
+                    .unwrap()
+                    .value,
+            );
+        }
+        values
+    }
+
+    #[test]
+    fn test_unreliable_channels() {
+        let message_factory = TestMessageFactory::new(1);
+        let unordered_config = ChannelConfig {
+            channel_type: ChannelType::UnreliableUnordered,
+            message_send_queue_size: 8,
+            ..ChannelConfig::default()
+        };
+        let sequenced_config = ChannelConfig {
+            channel_type: ChannelType::UnreliableSequenced,
+            ..unordered_config
+        };
+
+        for config in [unordered_config, sequenced_config] {
+            let mut sender = UnreliableChannel::new(&config, &message_factory);
+            let mut receiver = UnreliableChannel::new(&config, &message_factory);
+
+            // Send three packets, and deliver them out of order
+            let mut packets = vec![];
+            for value in 0..6 {
+                assert!(sender.send_message(Box::new(TestMessage { value })));
+                if value % 2 == 1 {
+                    packets.push(sender.get_packet_data(0, 1000).unwrap());
+                }
+            }
+            assert!(!sender.has_messages_to_send());
+            assert!(sender.get_packet_data(0, 1000).is_none());
+
+            for packet_index in [0, 2, 1] {
+                assert!(receiver.process_packet_data(&packets[packet_index]));
+            }
+
+            if config.channel_type == ChannelType::UnreliableUnordered {
+                assert_eq!(receive_values(&mut receiver), vec![0, 1, 4, 5, 2, 3]);
+            } else {
+                assert_eq!(receive_values(&mut receiver), vec![0, 1, 4, 5]);
+                assert_eq!(receiver.num_messages_dropped, 2);
+            }
+
+            // Send queue limit
+            for value in 0..8 {
+                assert!(sender.send_message(Box::new(TestMessage { value })));
+            }
+            assert!(!sender.send_message(Box::new(TestMessage { value: 8 })));
+
+            // Messages that don't fit in the budget are dropped, not resent
+            let packet_data = sender.get_packet_data(0, 40).unwrap();
+            assert!(!sender.has_messages_to_send());
+            assert!(sender.num_messages_dropped > 0);
+            assert!(receiver.process_packet_data(&packet_data));
+            assert!(!receive_values(&mut receiver).is_empty());
+        }
+    }
+}
@@ -1,7 +1,21 @@
 pub mod bitpacker;
+pub mod block_transfer;
+pub mod client_server;
+pub mod connect_token;
 pub mod constants;
 pub mod helpers;
 pub mod macros;
+pub mod messages;
+pub mod network_simulator;
+pub mod packet_encryption;
 pub mod packets;
+pub mod protocol_config;
+pub mod protocol_error;
+pub mod reliable_endpoint;
+pub mod sequence;
+pub mod sequence_buffer;
 pub mod serialization;
 pub mod streams;
+#[cfg(test)]
+pub mod test_helpers;
+pub mod transport;
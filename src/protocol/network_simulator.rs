@@ -0,0 +1,409 @@
+use std::{cell::RefCell, collections::HashSet, io, net::SocketAddr, rc::Rc};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::transport::Transport;
+
+/** Bad network conditions to simulate. The default is a perfect network. */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NetworkSimulatorConfig {
+    pub latency: f64,       // seconds added to every datagram
+    pub jitter: f64, // up to this many seconds added or taken off the latency, at random per datagram
+    pub packet_loss: f32, // percent of datagrams lost, 0 to 100
+    pub duplicates: f32, // percent of datagrams delivered twice, each copy with its own latency
+    pub reorder: f32, // percent of datagrams held back by an extra reorder_delay, so later datagrams overtake them
+    pub reorder_delay: f64, // seconds a reordered datagram is held back
+}
+
+struct PendingDatagram {
+    delivery_time: f64,
+    order: u64, // datagrams due at the same time arrive in the order they were sent
+    from: SocketAddr,
+    to: SocketAddr,
+    data: Vec<u8>,
+}
+
+struct SimulatorState {
+    config: NetworkSimulatorConfig,
+    rng: StdRng,
+    time: f64,
+    next_order: u64,
+    bound_addresses: HashSet<SocketAddr>,
+    pending: Vec<PendingDatagram>, // datagrams in flight, delivered once time reaches their delivery_time
+
+    num_datagrams_sent: u64,
+    num_datagrams_lost: u64,
+    num_datagrams_duplicated: u64,
+    num_datagrams_reordered: u64,
+}
+
+impl SimulatorState {
+    fn queue(&mut self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        let mut delay = self.config.latency;
+        if self.config.jitter > 0.0 {
+            delay += self.rng.gen_range(-self.config.jitter..=self.config.jitter);
+        }
+        if self.rng.gen_range(0.0..100.0) < self.config.reorder {
+            delay += self.config.reorder_delay;
+            self.num_datagrams_reordered += 1;
+        }
+
+        self.pending.push(PendingDatagram {
+            delivery_time: self.time + delay.max(0.0),
+            order: self.next_order,
+            from,
+            to,
+            data: data.to_vec(),
+        });
+        self.next_order += 1;
+    }
+}
+
+/**
+    A network between SimulatedTransports with latency, jitter, loss, duplication and reordering.
+
+    Time only moves with advance_time, and every random choice comes from the seeded rng,
+    so the same seed and the same calls always deliver the same datagrams at the same times.
+    Clones share the same network.
+*/
+#[derive(Clone)]
+pub struct NetworkSimulator {
+    state: Rc<RefCell<SimulatorState>>,
+}
+
+impl NetworkSimulator {
+    pub fn new(config: &NetworkSimulatorConfig, seed: u64) -> NetworkSimulator {
+        NetworkSimulator {
+            state: Rc::new(RefCell::new(SimulatorState {
+                config: *config,
+                rng: StdRng::seed_from_u64(seed),
+                time: 0.0,
+                next_order: 0,
+                bound_addresses: HashSet::new(),
+                pending: vec![],
+                num_datagrams_sent: 0,
+                num_datagrams_lost: 0,
+                num_datagrams_duplicated: 0,
+                num_datagrams_reordered: 0,
+            })),
+        }
+    }
+
+    /** Binds a transport to address. Fails with AddrInUse if another transport has it. */
+    pub fn bind(&self, address: SocketAddr) -> io::Result<SimulatedTransport> {
+        if !self.state.borrow_mut().bound_addresses.insert(address) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        Ok(SimulatedTransport {
+            simulator: self.clone(),
+            address,
+        })
+    }
+
+    /** Changes the conditions for datagrams sent from now on, ex. to simulate a spike of loss */
+    pub fn set_config(&self, config: &NetworkSimulatorConfig) {
+        self.state.borrow_mut().config = *config;
+    }
+
+    /** Moves the virtual clock forward, in seconds. Datagrams due by then can be received. */
+    pub fn advance_time(&self, time: f64) {
+        self.state.borrow_mut().time = time;
+    }
+
+    pub fn time(&self) -> f64 {
+        self.state.borrow().time
+    }
+
+    /** Datagrams sent but not received yet */
+    pub fn num_pending_datagrams(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    /** Drops every datagram in flight */
+    pub fn discard_pending_datagrams(&self) {
+        self.state.borrow_mut().pending.clear();
+    }
+
+    pub fn num_datagrams_sent(&self) -> u64 {
+        self.state.borrow().num_datagrams_sent
+    }
+
+    pub fn num_datagrams_lost(&self) -> u64 {
+        self.state.borrow().num_datagrams_lost
+    }
+
+    pub fn num_datagrams_duplicated(&self) -> u64 {
+        self.state.borrow().num_datagrams_duplicated
+    }
+
+    pub fn num_datagrams_reordered(&self) -> u64 {
+        self.state.borrow().num_datagrams_reordered
+    }
+}
+
+/** Transport on a NetworkSimulator. The address is unbound when the transport is dropped. */
+pub struct SimulatedTransport {
+    simulator: NetworkSimulator,
+    address: SocketAddr,
+}
+
+impl Transport for SimulatedTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn send_to(&mut self, address: SocketAddr, data: &[u8]) -> io::Result<usize> {
+        let mut state = self.simulator.state.borrow_mut();
+        state.num_datagrams_sent += 1;
+
+        // nobody bound to the address, dropped like UDP
+        if !state.bound_addresses.contains(&address) {
+            return Ok(data.len());
+        }
+
+        if state.rng.gen_range(0.0..100.0) < state.config.packet_loss {
+            state.num_datagrams_lost += 1;
+            return Ok(data.len());
+        }
+
+        state.queue(self.address, address, data);
+
+        if state.rng.gen_range(0.0..100.0) < state.config.duplicates {
+            state.queue(self.address, address, data);
+            state.num_datagrams_duplicated += 1;
+        }
+
+        Ok(data.len())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut state = self.simulator.state.borrow_mut();
+        let time = state.time;
+
+        // earliest datagram due for this address
+        let Some(index) = state
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, datagram)| datagram.to == self.address && datagram.delivery_time <= time)
+            .min_by(|(_, a), (_, b)| {
+                a.delivery_time
+                    .total_cmp(&b.delivery_time)
+                    .then(a.order.cmp(&b.order))
+            })
+            .map(|(index, _)| index)
+        else {
+            return Ok(None);
+        };
+
+        let datagram = state.pending.swap_remove(index);
+        let size = datagram.data.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&datagram.data[..size]);
+        Ok(Some((size, datagram.from)))
+    }
+}
+
+impl Drop for SimulatedTransport {
+    fn drop(&mut self) {
+        let mut state = self.simulator.state.borrow_mut();
+        state.bound_addresses.remove(&self.address);
+        let address = self.address;
+        state.pending.retain(|datagram| datagram.to != address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        packets::{
+            fragment_packet::split_packet_into_fragments_with_parity, packet_buffer::PacketBuffer,
+        },
+        protocol_config::ProtocolConfig,
+    };
+
+    fn bad_network() -> NetworkSimulatorConfig {
+        NetworkSimulatorConfig {
+            latency: 0.1,
+            jitter: 0.05,
+            packet_loss: 10.0,
+            duplicates: 10.0,
+            reorder: 10.0,
+            reorder_delay: 0.2,
+        }
+    }
+
+    /** Sends numbered datagrams for a few seconds, returns the numbers received in order */
+    fn run(config: &NetworkSimulatorConfig, seed: u64) -> Vec<u32> {
+        let simulator = NetworkSimulator::new(config, seed);
+        let mut a = simulator.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+        let mut b = simulator.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+
+        let mut received = vec![];
+        let mut buffer = [0; 4];
+        for i in 0..300u32 {
+            simulator.advance_time(i as f64 * 0.01);
+            a.send_to(b.local_address(), &i.to_le_bytes()).unwrap();
+            while let Some((size, from)) = b.recv_from(&mut buffer).unwrap() {
+                assert_eq!((size, from), (4, a.local_address()));
+                received.push(u32::from_le_bytes(buffer));
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn test_network_simulator() {
+        // A perfect network delivers everything, in order
+        let perfect = NetworkSimulatorConfig::default();
+        assert_eq!(run(&perfect, 1), (0..300).collect::<Vec<u32>>());
+
+        // Latency holds datagrams back until the clock reaches them
+        let config = NetworkSimulatorConfig {
+            latency: 0.1,
+            ..perfect
+        };
+        let received = run(&config, 1);
+        assert_eq!(received, (0..290).collect::<Vec<u32>>());
+
+        // Bad conditions lose, duplicate and reorder datagrams
+        let received = run(&bad_network(), 22);
+        let mut unique = received.clone();
+        unique.sort();
+        unique.dedup();
+        assert!(unique.len() < 290 && unique.len() > 200);
+        assert!(received.len() > unique.len());
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+
+        // The same seed gives the same results, a different seed doesn't
+        assert_eq!(run(&bad_network(), 22), received);
+        assert_ne!(run(&bad_network(), 23), received);
+    }
+
+    #[test]
+    fn test_fragmentation_on_a_bad_network() {
+        let config = ProtocolConfig::default();
+        let simulator = NetworkSimulator::new(&bad_network(), 2022);
+        let mut sender = simulator.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+        let mut receiver = simulator.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+        let mut packet_buffer = PacketBuffer::new(&config);
+
+        let packet_data: Vec<u8> = (0..5000).map(|i| (i % 241) as u8).collect();
+        let mut buffer = vec![0; config.max_packet_fragment_size()];
+        let mut num_received_packets = 0;
+
+        for sequence in 0..200u16 {
+            simulator.advance_time(sequence as f64 * 0.02);
+
+            let fragments =
+                split_packet_into_fragments_with_parity(&config, sequence, &packet_data, 0.4)
+                    .unwrap();
+            for fragment in fragments.iter() {
+                sender
+                    .send_to(receiver.local_address(), &fragment.data)
+                    .unwrap();
+            }
+
+            while let Some((size, _)) = receiver.recv_from(&mut buffer).unwrap() {
+                packet_buffer.process_packet(&buffer, size as u32);
+            }
+            for packet in packet_buffer.receive_packets() {
+                assert_eq!(packet.data, packet_data);
+                num_received_packets += 1;
+            }
+        }
+
+        // Packets that lost a fragment are mostly rebuilt from parity
+        assert!(packet_buffer.num_recovered_fragments > 0);
+        assert!(num_received_packets > 150);
+        assert_eq!(packet_buffer.num_invalid_crc32_packets, 0);
+    }
+
+    #[test]
+    fn test_reliable_messages_on_a_bad_network() {
+        use crate::protocol::{
+            messages::{
+                connection::{Connection, ConnectionPacket},
+                downcast_message, ChannelConfig, MessageFactory,
+            },
+            packets::{object::downcast_packet, packet_info::PacketInfo},
+            reliable_endpoint::ReliableEndpoint,
+            test_helpers::{TestMessage, TestMessageFactory, TestPacketFactory},
+        };
+
+        let config = ProtocolConfig::default();
+        let packet_factory = TestPacketFactory::new(1, |packet_type| {
+            Box::new(ConnectionPacket::new(packet_type))
+        });
+        let mut info = PacketInfo::new(&packet_factory);
+        info.allowed_packet_types = vec![0];
+
+        let message_factory = TestMessageFactory::new(1);
+        let channels: [(ChannelConfig, &dyn MessageFactory); 1] =
+            [(ChannelConfig::default(), &message_factory)];
+
+        let simulator = NetworkSimulator::new(&bad_network(), 7);
+        let mut transports = [
+            simulator.bind("10.0.0.1:1000".parse().unwrap()).unwrap(),
+            simulator.bind("10.0.0.2:1000".parse().unwrap()).unwrap(),
+        ];
+        let addresses = [transports[0].local_address(), transports[1].local_address()];
+        let mut connections = [Connection::new(&channels), Connection::new(&channels)];
+        let mut endpoints = [
+            ReliableEndpoint::new(&config),
+            ReliableEndpoint::new(&config),
+        ];
+
+        let mut received_values = vec![];
+        let mut buffer = vec![0; 1200];
+
+        for tick in 0..1000 {
+            let time = tick as f64 * 0.02;
+            simulator.advance_time(time);
+
+            if tick < 500 {
+                assert!(connections[0].send_message(0, Box::new(TestMessage { value: tick })));
+            }
+
+            // Both ends send a packet every tick, the second end only to carry acks back
+            for (index, other) in [(0, 1), (1, 0)] {
+                connections[index].advance_time(time);
+                let mut packet =
+                    connections[index].generate_packet(0, endpoints[index].next_sequence(), 8000);
+                let bytes_written =
+                    endpoints[index].write_packet(&info, &mut packet, &mut buffer, 1200);
+                assert!(bytes_written > 0);
+                transports[index]
+                    .send_to(addresses[other], &buffer[..bytes_written as usize])
+                    .unwrap();
+            }
+
+            for index in 0..2 {
+                while let Some((size, _)) = transports[index].recv_from(&mut buffer).unwrap() {
+                    // packets too old to ack are dropped by the endpoint, duplicate messages by the channel
+                    let Ok(packet) = endpoints[index].read_packet(&info, &buffer[..size]) else {
+                        continue;
+                    };
+                    let packet = downcast_packet::<ConnectionPacket>(packet.as_ref()).unwrap();
+                    assert!(connections[index].process_packet(packet));
+                }
+                let acks = endpoints[index].receive_acks();
+                connections[index].process_acks(&acks);
+            }
+
+            while let Some(message) = connections[1].receive_message(0) {
+                received_values.push(
+                    downcast_message::<TestMessage>(message.as_ref())
+                        .unwrap()
+                        .value,
+                );
+            }
+        }
+
+        assert!(simulator.num_datagrams_lost() > 0);
+        assert!(simulator.num_datagrams_duplicated() > 0);
+        assert!(simulator.num_datagrams_reordered() > 0);
+        assert_eq!(received_values, (0..500).collect::<Vec<i32>>());
+    }
+}
@@ -0,0 +1,363 @@
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+
+use super::{
+    packets::{
+        object::{DynObject, Packet},
+        packet_info::PacketInfo,
+        read_packet, write_packet,
+    },
+    protocol_error::{ProtocolError, ProtocolErrorKind},
+};
+
+pub const PACKET_ENCRYPTION_KEY_BYTES: usize = 32;
+pub const PACKET_ENCRYPTION_PREFIX_BYTES: u32 = 8; // the sequence, sent unencrypted
+pub const PACKET_ENCRYPTION_MAC_BYTES: usize = 16; // Poly1305 tag, after the encrypted bytes
+pub const REPLAY_PROTECTION_BUFFER_SIZE: usize = 256;
+
+/**
+    Remembers which sequences were received recently, so a captured packet can't be sent again.
+    Sequences more than REPLAY_PROTECTION_BUFFER_SIZE older than the newest are rejected outright.
+*/
+pub struct ReplayProtection {
+    most_recent_sequence: u64,
+    received_sequences: [Option<u64>; REPLAY_PROTECTION_BUFFER_SIZE], // None for empty entries
+}
+
+impl Default for ReplayProtection {
+    fn default() -> Self {
+        ReplayProtection {
+            most_recent_sequence: 0,
+            received_sequences: [None; REPLAY_PROTECTION_BUFFER_SIZE],
+        }
+    }
+}
+
+impl ReplayProtection {
+    pub fn new() -> ReplayProtection {
+        ReplayProtection::default()
+    }
+
+    pub fn reset(&mut self) {
+        *self = ReplayProtection::default();
+    }
+
+    /** True if the sequence was received already, or is too old to tell */
+    pub fn already_received(&self, sequence: u64) -> bool {
+        if sequence.saturating_add(REPLAY_PROTECTION_BUFFER_SIZE as u64)
+            <= self.most_recent_sequence
+        {
+            return true;
+        }
+
+        let entry =
+            self.received_sequences[(sequence % REPLAY_PROTECTION_BUFFER_SIZE as u64) as usize];
+        entry.is_some_and(|entry| entry >= sequence)
+    }
+
+    /** Records a sequence as received. Only call it once the packet is known to be genuine. */
+    pub fn advance_sequence(&mut self, sequence: u64) {
+        if sequence > self.most_recent_sequence {
+            self.most_recent_sequence = sequence;
+        }
+        self.received_sequences[(sequence % REPLAY_PROTECTION_BUFFER_SIZE as u64) as usize] =
+            Some(sequence);
+    }
+}
+
+/**
+    Encrypts and authenticates packets with ChaCha20-Poly1305, for one end of a connection.
+
+    Packets are written with write_packet in raw format, so PacketInfo must have raw_format set
+    (the Poly1305 tag replaces the crc32) and prefix_bytes == PACKET_ENCRYPTION_PREFIX_BYTES.
+    The prefix holds the packet's sequence, the nonce is derived from it, so it is sent in the clear
+    but authenticated along with the protocol id.
+
+    Each direction needs its own key, ex. a client sends with client_to_server_key and receives
+    with server_to_client_key, so two ends never encrypt with the same key and nonce.
+*/
+pub struct PacketEncryption {
+    send_cipher: ChaCha20Poly1305,
+    receive_cipher: ChaCha20Poly1305,
+    sequence: u64, // sequence of the next packet sent, never reused
+    replay_protection: ReplayProtection,
+
+    pub num_packets_rejected: u64, // packets that failed to decrypt or were replayed
+}
+
+impl PacketEncryption {
+    pub fn new(
+        send_key: &[u8; PACKET_ENCRYPTION_KEY_BYTES],
+        receive_key: &[u8; PACKET_ENCRYPTION_KEY_BYTES],
+    ) -> PacketEncryption {
+        PacketEncryption {
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            receive_cipher: ChaCha20Poly1305::new(Key::from_slice(receive_key)),
+            sequence: 0,
+            replay_protection: ReplayProtection::new(),
+            num_packets_rejected: 0,
+        }
+    }
+
+    /** Random key, to hand to both ends of a connection for one direction */
+    pub fn generate_key() -> [u8; PACKET_ENCRYPTION_KEY_BYTES] {
+        rand::random()
+    }
+
+    /** Sequence the next packet will be sent with */
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /**
+        Writes a packet with write_packet and encrypts it in place.
+        Returns the bytes written, tag included, or 0 if the packet didn't fit
+        or info isn't set up for encryption.
+    */
+    pub fn write_packet(
+        &mut self,
+        info: &PacketInfo,
+        packet: &mut dyn Packet,
+        buffer: &mut [u8],
+        header: Option<&mut dyn DynObject>,
+    ) -> u32 {
+        if !has_encryption_format(info) {
+            return 0;
+        }
+
+        let prefix_bytes = PACKET_ENCRYPTION_PREFIX_BYTES as usize;
+        if buffer.len() <= prefix_bytes + PACKET_ENCRYPTION_MAC_BYTES {
+            return 0;
+        }
+
+        let packet_buffer_length = buffer.len() - PACKET_ENCRYPTION_MAC_BYTES;
+        let packet_buffer = &mut buffer[..packet_buffer_length];
+        let bytes_written = write_packet(info, packet, packet_buffer, packet_buffer_length, header);
+        if bytes_written == 0 {
+            return 0;
+        }
+        let bytes_written = bytes_written as usize;
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        let (prefix, payload) = buffer[..bytes_written].split_at_mut(prefix_bytes);
+        prefix.copy_from_slice(&sequence.to_le_bytes());
+        let aad = associated_data(info, prefix);
+        let tag = self
+            .send_cipher
+            .encrypt_in_place_detached(&nonce(sequence), &aad, payload)
+            .expect("packets are far below the chacha20 length limit");
+
+        buffer[bytes_written..bytes_written + PACKET_ENCRYPTION_MAC_BYTES].copy_from_slice(&tag);
+        (bytes_written + PACKET_ENCRYPTION_MAC_BYTES) as u32
+    }
+
+    /**
+        Decrypts a packet written by write_packet and reads it with read_packet.
+        Packets that fail to decrypt fail with DecryptFailed, packets already received with ReplayedPacket,
+        and any packet read with a PacketInfo not set up for encryption with InvalidPacketInfo.
+    */
+    pub fn read_packet(
+        &mut self,
+        info: &PacketInfo,
+        buffer: &[u8],
+        header: Option<&mut dyn DynObject>,
+    ) -> Result<Box<dyn Packet>, ProtocolError> {
+        if !has_encryption_format(info) {
+            self.num_packets_rejected += 1;
+            return Err(ProtocolError::new(ProtocolErrorKind::InvalidPacketInfo));
+        }
+
+        let prefix_bytes = PACKET_ENCRYPTION_PREFIX_BYTES as usize;
+        if buffer.len() <= prefix_bytes + PACKET_ENCRYPTION_MAC_BYTES {
+            self.num_packets_rejected += 1;
+            return Err(ProtocolError::new(ProtocolErrorKind::StreamOverflow));
+        }
+
+        let mut sequence_bytes = [0; 8];
+        sequence_bytes.copy_from_slice(&buffer[..prefix_bytes]);
+        let sequence = u64::from_le_bytes(sequence_bytes);
+        if self.replay_protection.already_received(sequence) {
+            self.num_packets_rejected += 1;
+            return Err(ProtocolError::new(ProtocolErrorKind::ReplayedPacket));
+        }
+
+        let (data, tag) = buffer.split_at(buffer.len() - PACKET_ENCRYPTION_MAC_BYTES);
+        let mut decrypted = data.to_vec();
+        let aad = associated_data(info, &data[..prefix_bytes]);
+        if self
+            .receive_cipher
+            .decrypt_in_place_detached(
+                &nonce(sequence),
+                &aad,
+                &mut decrypted[prefix_bytes..],
+                Tag::from_slice(tag),
+            )
+            .is_err()
+        {
+            self.num_packets_rejected += 1;
+            let mut error = ProtocolError::new(ProtocolErrorKind::DecryptFailed);
+            error.bit_offset = PACKET_ENCRYPTION_PREFIX_BYTES * 8;
+            return Err(error);
+        }
+
+        // Only a genuine packet moves the window, or forged sequences could push real packets out of it
+        self.replay_protection.advance_sequence(sequence);
+
+        read_packet(info, &decrypted, header)
+    }
+}
+
+/** Raw format, the tag replaces the crc32, with room for the sequence in the prefix */
+fn has_encryption_format(info: &PacketInfo) -> bool {
+    info.raw_format && info.prefix_bytes == PACKET_ENCRYPTION_PREFIX_BYTES
+}
+
+/** 96 bit nonce: 4 zero bytes then the 64 bit sequence */
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/** Authenticated but not encrypted: the protocol id, so packets for other protocols fail, and the sequence */
+fn associated_data(info: &PacketInfo, prefix: &[u8]) -> Vec<u8> {
+    let mut aad = info.config.protocol_id().to_le_bytes().to_vec();
+    aad.extend_from_slice(prefix);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::packets::object::downcast_packet;
+    use crate::protocol::test_helpers::{TestPacket, TestPacketFactory};
+
+    #[test]
+    fn test_replay_protection() {
+        let mut replay_protection = ReplayProtection::new();
+        for sequence in 0..1000 {
+            assert!(!replay_protection.already_received(sequence));
+            replay_protection.advance_sequence(sequence);
+            assert!(replay_protection.already_received(sequence));
+        }
+
+        // Out of order within the window is fine, once
+        assert!(!replay_protection.already_received(1100));
+        assert!(!replay_protection.already_received(1050));
+        replay_protection.advance_sequence(1100);
+        assert!(!replay_protection.already_received(1050));
+        replay_protection.advance_sequence(1050);
+        assert!(replay_protection.already_received(1050));
+
+        // Any sequence can arrive from the network, forged or not
+        assert!(!replay_protection.already_received(u64::MAX));
+        replay_protection.advance_sequence(u64::MAX);
+        assert!(replay_protection.already_received(u64::MAX));
+        assert!(replay_protection.already_received(u64::MAX - 1));
+
+        // Too old to tell
+        assert!(replay_protection.already_received(1100 - REPLAY_PROTECTION_BUFFER_SIZE as u64));
+    }
+
+    #[test]
+    fn test_packet_encryption() {
+        let packet_factory =
+            TestPacketFactory::new(1, |_packet_type| Box::new(TestPacket::default()));
+        let mut info = PacketInfo::new(&packet_factory);
+        info.raw_format = true;
+        info.prefix_bytes = PACKET_ENCRYPTION_PREFIX_BYTES;
+        info.allowed_packet_types = vec![0];
+
+        let client_to_server_key = PacketEncryption::generate_key();
+        let server_to_client_key = PacketEncryption::generate_key();
+        let mut client = PacketEncryption::new(&client_to_server_key, &server_to_client_key);
+        let mut server = PacketEncryption::new(&server_to_client_key, &client_to_server_key);
+
+        let mut buffer = vec![0; 256];
+        let mut packets = vec![];
+        for value in 0..10 {
+            let bytes_written =
+                client.write_packet(&info, &mut TestPacket { value }, &mut buffer, None);
+            assert!(bytes_written > 0);
+            packets.push(buffer[..bytes_written as usize].to_vec());
+        }
+        assert_eq!(client.sequence(), 10);
+
+        // Delivered out of order
+        for (value, data) in packets.iter().enumerate().rev() {
+            let packet = server.read_packet(&info, data, None).unwrap();
+            assert_eq!(
+                downcast_packet::<TestPacket>(packet.as_ref())
+                    .unwrap()
+                    .value,
+                value as i32
+            );
+        }
+
+        // Replayed
+        let error = server.read_packet(&info, &packets[3], None).err().unwrap();
+        assert_eq!(error.kind, ProtocolErrorKind::ReplayedPacket);
+
+        // Changing any byte, the sequence included, fails to decrypt
+        for i in 0..packets[0].len() {
+            let mut forged = packets[0].clone();
+            forged[i] ^= 1;
+            assert!(server.read_packet(&info, &forged, None).is_err());
+        }
+
+        // Wrong key, and the other direction's key
+        let bytes_written =
+            client.write_packet(&info, &mut TestPacket { value: 1 }, &mut buffer, None);
+        let data = &buffer[..bytes_written as usize];
+        let mut eavesdropper = PacketEncryption::new(
+            &PacketEncryption::generate_key(),
+            &PacketEncryption::generate_key(),
+        );
+        let error = eavesdropper.read_packet(&info, data, None).err().unwrap();
+        assert_eq!(error.kind, ProtocolErrorKind::DecryptFailed);
+        assert!(client.read_packet(&info, data, None).is_err());
+        assert!(server.read_packet(&info, data, None).is_ok());
+
+        // Another protocol
+        info.config = crate::protocol::protocol_config::ProtocolConfig::builder()
+            .protocol_id(info.config.protocol_id() + 1)
+            .build()
+            .unwrap();
+        let bytes_written =
+            client.write_packet(&info, &mut TestPacket { value: 1 }, &mut buffer, None);
+        info.config = Default::default();
+        let error = server
+            .read_packet(&info, &buffer[..bytes_written as usize], None)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind, ProtocolErrorKind::DecryptFailed);
+
+        // Doesn't fit
+        assert_eq!(
+            client.write_packet(&info, &mut TestPacket { value: 1 }, &mut buffer[..20], None),
+            0
+        );
+
+        // Not set up for encryption, fails instead of panicking
+        let bytes_written =
+            client.write_packet(&info, &mut TestPacket { value: 1 }, &mut buffer, None);
+        let data = buffer[..bytes_written as usize].to_vec();
+        info.raw_format = false;
+        assert_eq!(
+            client.write_packet(&info, &mut TestPacket { value: 1 }, &mut buffer, None),
+            0
+        );
+        let error = server.read_packet(&info, &data, None).err().unwrap();
+        assert_eq!(error.kind, ProtocolErrorKind::InvalidPacketInfo);
+        info.raw_format = true;
+        info.prefix_bytes = 0;
+        let error = server.read_packet(&info, &data, None).err().unwrap();
+        assert_eq!(error.kind, ProtocolErrorKind::InvalidPacketInfo);
+        info.prefix_bytes = PACKET_ENCRYPTION_PREFIX_BYTES;
+        assert!(server.read_packet(&info, &data, None).is_ok());
+    }
+}
@@ -1,19 +1,26 @@
-use crate::{
-    impl_object_for_packet,
-    protocol::{
-        constants::*,
-        serialization::*,
-        streams::{read_stream::ReadStream, write_stream::WriteStream, Stream},
-    },
+use crate::protocol::{
+    helpers::calc_packet_crc32,
+    protocol_config::ProtocolConfig,
+    streams::{write_stream::WriteStream, Stream},
 };
+use crate::{serialize_align, serialize_bits, serialize_bool, serialize_bytes, serialize_int};
 
 use super::object::Object;
 use super::object::Packet;
+use super::packet_data::PacketData;
+
+/** Packet type of fragments, the PacketFactory's own packet types start at 1 */
+pub const FRAGMENT_PACKET_TYPE: u32 = 0;
 
 // fragment packet on-the-wire format:
-// [crc32] (32 bits) | [sequence] (16 bits) | [packet type 0] (# of bits depends on number of packet types)
-// [fragment id] (8 bits) | [num fragments] (8 bits) | (pad zero bits to nearest byte) | <fragment data>
+// [crc32] (32 bits) | [sequence] (16 bits) | [packet type 0] (# of bits depends on config.num_packet_types())
+// [fragment id] (8 bits) | [num fragments - 1] (8 bits) | [num parity fragments]
+// [is parity] (1 bit, if there are parity fragments) | [last fragment size - 1] (parity fragments only)
+// (pad zero bits to nearest byte) | <fragment data>
 pub struct FragmentPacket {
+    // input
+    pub config: ProtocolConfig, // fragment limits, must match the config of the other end
+
     // input/output
     pub fragment_size: u32, // set as input on serialize write. output on serialize read (inferred from size of packet)
 
@@ -21,68 +28,238 @@ pub struct FragmentPacket {
     pub crc32: u32,
     pub sequence: u16,
     pub packet_type: u32,
-    pub fragment_id: u8,
-    pub num_fragments: u8,
+    pub fragment_id: u8,    // index of the parity fragment for parity fragments
+    pub num_fragments: u32, // 1 to config.max_fragments_per_packet()
+    pub num_parity_fragments: u32, // 0 to num_fragments, see split_packet_into_fragments_with_parity
+    pub is_parity: bool,
+    pub last_fragment_size: u32, // size of the packet's last fragment, parity fragments only
     pub fragment_data: Vec<u8>,
 }
 
 impl FragmentPacket {
-    pub fn new() -> FragmentPacket {
-        return FragmentPacket {
+    pub fn new(config: &ProtocolConfig) -> FragmentPacket {
+        FragmentPacket {
+            config: *config,
             fragment_size: 0,
             crc32: 0,
             sequence: 0,
             packet_type: 0,
             fragment_id: 0,
             num_fragments: 0,
-            fragment_data: vec![0; MAX_PACKET_FRAGMENT_SIZE],
-        };
+            num_parity_fragments: 0,
+            is_parity: false,
+            last_fragment_size: 0,
+            fragment_data: vec![],
+        }
+    }
+}
+
+impl Default for FragmentPacket {
+    fn default() -> Self {
+        Self::new(&ProtocolConfig::default())
     }
+}
 
-    pub fn serialize(&mut self, stream: &mut dyn Stream) -> bool {
-        serialize_bits_macro(stream, &mut self.crc32, 32);
-        serialize_bits_macro(stream, &mut (self.sequence as u32), 16);
-        self.packet_type = 0;
-        serialize_int_macro(
+impl Packet for FragmentPacket {
+    fn get_packet_type(&self) -> u32 {
+        FRAGMENT_PACKET_TYPE
+    }
+}
+
+impl Object for FragmentPacket {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+        serialize_bits!(stream, self.crc32, 32);
+
+        let mut sequence = self.sequence as u32;
+        serialize_bits!(stream, sequence, 16);
+        self.sequence = sequence as u16;
+
+        // Always written as a fragment. When reading, a regular packet has its own packet type here.
+        if stream.is_writing() {
+            self.packet_type = FRAGMENT_PACKET_TYPE;
+        }
+        let mut packet_type = self.packet_type as i32;
+        serialize_int!(
             stream,
-            &mut (self.packet_type as i32),
+            packet_type,
             0,
-            PacketTypes::NUM_TYPES as i32 - 1,
+            self.config.num_packet_types() as i32 - 1
         );
+        self.packet_type = packet_type as u32;
 
         // If packet type is not fragment, then return
-        if self.packet_type != 0 {
+        if self.packet_type != FRAGMENT_PACKET_TYPE {
             return true;
         }
 
-        serialize_bits_macro(stream, &mut (self.fragment_id as u32), 8);
-        serialize_bits_macro(stream, &mut (self.num_fragments as u32), 8);
-        serialize_align_macro(stream);
+        let mut fragment_id = self.fragment_id as u32;
+        serialize_bits!(stream, fragment_id, 8);
+        self.fragment_id = fragment_id as u8;
 
+        let mut num_fragments = self.num_fragments as i32;
+        serialize_int!(
+            stream,
+            num_fragments,
+            1,
+            self.config.max_fragments_per_packet() as i32
+        );
+        self.num_fragments = num_fragments as u32;
+
+        let mut num_parity_fragments = self.num_parity_fragments as i32;
+        serialize_int!(
+            stream,
+            num_parity_fragments,
+            0,
+            self.config.max_fragments_per_packet() as i32
+        );
+        self.num_parity_fragments = num_parity_fragments as u32;
+
+        if self.num_parity_fragments > 0 {
+            serialize_bool!(stream, self.is_parity);
+        } else {
+            self.is_parity = false;
+        }
+
+        // a lost last fragment is shorter than the parity covering it, so parity fragments carry its size
+        if self.is_parity && self.config.max_fragment_size() > 1 {
+            let mut last_fragment_size = self.last_fragment_size as i32;
+            serialize_int!(
+                stream,
+                last_fragment_size,
+                1,
+                self.config.max_fragment_size() as i32
+            );
+            self.last_fragment_size = last_fragment_size as u32;
+        } else if self.is_parity {
+            self.last_fragment_size = 1;
+        }
+
+        serialize_align!(stream);
+
+        // When reading, the fragment data is whatever is left of the datagram
         if stream.is_reading() {
-            assert!(stream.get_bits_remaining() % 8 == 0);
             self.fragment_size = stream.get_bits_remaining() / 8;
-            if self.fragment_size <= 0 || self.fragment_size > MAX_FRAGMENT_SIZE as u32 {
-                println!(
-                    "Packet fragment size is out of bounds ({:?})",
-                    self.fragment_size
-                );
-                return false;
-            }
+        }
+        if self.fragment_size == 0 || self.fragment_size > self.config.max_fragment_size() as u32 {
+            return false;
+        }
+        if stream.is_reading() {
+            self.fragment_data = vec![0; self.fragment_size as usize];
         }
 
-        assert!(self.fragment_size > 0);
-        assert!(self.fragment_size <= MAX_FRAGMENT_SIZE as u32);
-
-        serialize_bytes_internal(stream, &mut self.fragment_data, self.fragment_size);
+        serialize_bytes!(stream, self.fragment_data, self.fragment_size);
         true
     }
 }
 
-impl Packet for FragmentPacket {
-    fn get_packet_type(&self) -> u32 {
-        PacketTypes::FRAGMENT as u32
+/**
+    Splits a serialized packet into fragment datagrams, ready to send one at a time.
+
+    Every fragment holds config.max_fragment_size() bytes of the packet, except the last which holds the remainder.
+    Each one carries the packet sequence, its fragment id and the fragment count, and is stamped with
+    a crc32 salted with the config's protocol id, the same way write_packet stamps a regular packet.
+
+    Returns None if the packet is empty or would need more than config.max_fragments_per_packet() fragments.
+*/
+pub fn split_packet_into_fragments(
+    config: &ProtocolConfig,
+    sequence: u16,
+    packet_data: &[u8],
+) -> Option<Vec<PacketData>> {
+    split_packet_into_fragments_with_parity(config, sequence, packet_data, 0.0)
+}
+
+/**
+    Same as split_packet_into_fragments, followed by XOR parity fragments so the receiver
+    can rebuild lost fragments without a resend.
+
+    parity_ratio is parity fragments per fragment, ex. 0.25 sends one parity fragment for every 4 fragments
+    (rounded up, at most one per fragment). Fragment n is covered by parity fragment n % num_parity_fragments,
+    so each parity fragment can rebuild one lost fragment of its group, and a burst of up to
+    num_parity_fragments lost fragments in a row is always recovered.
+*/
+pub fn split_packet_into_fragments_with_parity(
+    config: &ProtocolConfig,
+    sequence: u16,
+    packet_data: &[u8],
+    parity_ratio: f32,
+) -> Option<Vec<PacketData>> {
+    let fragment_size = config.max_fragment_size();
+
+    let num_fragments = packet_data.len().div_ceil(fragment_size);
+    if num_fragments == 0 || num_fragments > config.max_fragments_per_packet() {
+        return None;
+    }
+
+    let num_parity_fragments =
+        ((num_fragments as f32 * parity_ratio.max(0.0)).ceil() as usize).min(num_fragments);
+    let last_fragment_size = packet_data.len() - (num_fragments - 1) * fragment_size;
+
+    let mut fragments: Vec<PacketData> = Vec::with_capacity(num_fragments + num_parity_fragments);
+    let mut parity_data: Vec<Vec<u8>> = vec![vec![]; num_parity_fragments];
+
+    for (fragment_id, fragment_bytes) in packet_data.chunks(fragment_size).enumerate() {
+        if num_parity_fragments > 0 {
+            xor_into(
+                &mut parity_data[fragment_id % num_parity_fragments],
+                fragment_bytes,
+            );
+        }
+
+        let mut fragment_packet = FragmentPacket::new(config);
+        fragment_packet.sequence = sequence;
+        fragment_packet.fragment_id = fragment_id as u8;
+        fragment_packet.num_fragments = num_fragments as u32;
+        fragment_packet.num_parity_fragments = num_parity_fragments as u32;
+        fragment_packet.fragment_data = fragment_bytes.to_vec();
+        fragments.push(write_fragment(config, &mut fragment_packet)?);
+    }
+
+    for (parity_id, parity_bytes) in parity_data.into_iter().enumerate() {
+        let mut fragment_packet = FragmentPacket::new(config);
+        fragment_packet.sequence = sequence;
+        fragment_packet.fragment_id = parity_id as u8;
+        fragment_packet.num_fragments = num_fragments as u32;
+        fragment_packet.num_parity_fragments = num_parity_fragments as u32;
+        fragment_packet.is_parity = true;
+        fragment_packet.last_fragment_size = last_fragment_size as u32;
+        fragment_packet.fragment_data = parity_bytes;
+        fragments.push(write_fragment(config, &mut fragment_packet)?);
+    }
+
+    Some(fragments)
+}
+
+/** XORs bytes into data, growing data with zeros if bytes is longer */
+pub fn xor_into(data: &mut Vec<u8>, bytes: &[u8]) {
+    if data.len() < bytes.len() {
+        data.resize(bytes.len(), 0);
+    }
+    for (a, b) in data.iter_mut().zip(bytes) {
+        *a ^= b;
     }
 }
 
-impl_object_for_packet!(FragmentPacket);
+/** Serializes a fragment and stamps its crc32 */
+fn write_fragment(
+    config: &ProtocolConfig,
+    fragment_packet: &mut FragmentPacket,
+) -> Option<PacketData> {
+    fragment_packet.fragment_size = fragment_packet.fragment_data.len() as u32;
+
+    let mut data = vec![0; config.fragment_header_bytes() + fragment_packet.fragment_data.len()];
+    let size = {
+        let mut stream = WriteStream::new(&mut data);
+        if !fragment_packet.serialize(&mut stream) || !stream.flush() {
+            return None;
+        }
+        stream.get_bytes_processed()
+    };
+    data.truncate(size as usize);
+
+    // The crc32 was serialized as zero, fill it in now the rest of the fragment is written
+    let crc32 = calc_packet_crc32(&data, config.protocol_id());
+    data[..4].copy_from_slice(&crc32.to_le_bytes());
+
+    Some(PacketData { size, data })
+}
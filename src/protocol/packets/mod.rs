@@ -1,5 +1,5 @@
-use std::slice::from_raw_parts;
-
+use crate::bits_required;
+use crate::protocol::streams::measure_stream::MeasureStream;
 use crate::protocol::streams::read_stream::ReadStream;
 use crate::protocol::streams::write_stream::WriteStream;
 use crate::protocol::streams::Stream;
@@ -7,8 +7,8 @@ use crate::protocol::streams::Stream;
 use self::object::*;
 use self::packet_info::*;
 
-use super::constants::Buffer;
-use super::constants::ProtocolError;
+use super::helpers::calc_packet_crc32;
+use super::protocol_error::{ProtocolError, ProtocolErrorKind};
 
 pub mod fragment_packet;
 pub mod object;
@@ -21,19 +21,21 @@ pub mod packet_info;
 pub fn write_packet(
     info: &PacketInfo,
     packet: &mut dyn Packet,
-    buffer: &mut Buffer,
+    buffer: &mut [u8],
     buffer_length: usize,
-    header: Option<&mut dyn Object>,
+    header: Option<&mut dyn DynObject>,
 ) -> u32 {
-    assert!(buffer.len() > 0);
-    assert!(buffer.len() <= buffer_length);
-
     let num_packet_types = info.packet_factory.get_num_packet_types();
-    let buffer_length = buffer.len();
-    let mut stream = WriteStream::new(buffer, buffer.len());
+    if buffer.is_empty() || buffer.len() > buffer_length || num_packet_types == 0 {
+        return 0;
+    }
+
+    let mut stream = WriteStream::new(buffer);
 
     let mut crc_32: u32 = 0;
-    // stream.SetContext(info.context);
+    if let Some(context) = info.context {
+        stream.set_context(context);
+    }
 
     // Serialize prefix bytes
     for _i in 0..info.prefix_bytes {
@@ -47,171 +49,248 @@ pub fn write_packet(
     }
 
     // Write header if there is one
-    match header {
-        Some(header) => {
-            header.serialize_internal_w(&mut stream);
+    if let Some(header) = header {
+        if !header.serialize_dyn(&mut stream) {
+            return 0;
         }
-        _ => (),
     }
 
     let mut packet_type = packet.get_packet_type() as i32;
-    assert!(num_packet_types > 0);
 
     // If we have more than one packet type, serialize the packet type into the buffer?
     if num_packet_types > 1 {
-        stream.serialise_int(&mut packet_type, 0, num_packet_types as i32);
+        stream.serialise_int(&mut packet_type, 0, num_packet_types as i32 - 1);
     }
 
     // Serialize the packet
-    if !packet.serialize_internal_w(&mut stream) {
+    if !packet.serialize_dyn(&mut stream) {
         return 0;
     }
 
     stream.serialize_check(&mut String::from("end of packet"));
 
-    stream.writer.flush();
+    stream.flush();
     let bytes_processed = stream.get_bytes_processed();
 
-    if ProtocolError::None != stream.get_error() {
+    if ProtocolErrorKind::None != stream.get_error() {
+        return 0;
+    }
+
+    // Write crc32 into packet, covering only the bytes that were written
+    if !info.raw_format {
+        let packet_bytes = &mut buffer[info.prefix_bytes as usize..bytes_processed as usize];
+        crc_32 = calc_packet_crc32(packet_bytes, info.config.protocol_id());
+        packet_bytes[..4].copy_from_slice(&crc_32.to_le_bytes());
+    }
+
+    bytes_processed
+}
+
+/**
+    Measures how many bytes write_packet would write for this packet, without writing anything.
+    Use it to decide whether a packet needs to be fragmented, or to size a buffer exactly.
+*/
+pub fn measure_packet(
+    info: &PacketInfo,
+    packet: &mut dyn Packet,
+    header: Option<&mut dyn DynObject>,
+) -> u32 {
+    let num_packet_types = info.packet_factory.get_num_packet_types();
+    if num_packet_types == 0 {
         return 0;
     }
 
-    // Write crc32 into packet
+    let mut stream = MeasureStream::new(info.config.max_packet_size());
+    if let Some(context) = info.context {
+        stream.set_context(context);
+    }
+
+    // Prefix bytes
+    for _i in 0..info.prefix_bytes {
+        let mut zero: u32 = 0;
+        stream.serialize_bits(&mut zero, 8);
+    }
+
+    // Space for crc32
     if !info.raw_format {
-        unsafe {
-            // Pointer to buffer start + prefix bytes
-            let dest_ptr = (buffer.as_mut_ptr() as *mut u8).add(info.prefix_bytes as usize);
-            let protocol_bytes_temp = info.protocol_id.to_le_bytes();
-            let protocol_bytes = protocol_bytes_temp.as_slice();
-            let buffer_bytes =
-                from_raw_parts::<u8>(dest_ptr, buffer_length - info.prefix_bytes as usize);
-
-            let mut crc_bytes: Vec<u8> = vec![];
-            crc_bytes.extend_from_slice(protocol_bytes);
-            crc_bytes.extend_from_slice(buffer_bytes);
-
-            crc_32 = crc32fast::hash(&crc_bytes);
-            let src_ptr = crc_32.to_le_bytes().as_ptr();
-
-            // Write crc32 directly into buffer
-            std::ptr::copy_nonoverlapping(src_ptr, dest_ptr, 4);
+        let mut crc_32: u32 = 0;
+        stream.serialize_bits(&mut crc_32, 32);
+    }
+
+    if let Some(header) = header {
+        if !header.serialize_dyn(&mut stream) {
+            return 0;
         }
     }
 
-    return bytes_processed;
+    let mut packet_type = packet.get_packet_type() as i32;
+
+    if num_packet_types > 1 {
+        stream.serialise_int(&mut packet_type, 0, num_packet_types as i32 - 1);
+    }
+
+    if !packet.serialize_dyn(&mut stream) {
+        return 0;
+    }
+
+    stream.serialize_check(&mut String::from("end of packet"));
+
+    if ProtocolErrorKind::None != stream.get_error() {
+        return 0;
+    }
+
+    stream.get_bytes_processed()
+}
+
+/**
+    Builds the error for a failed read, filling in where the stream stopped.
+    Errors recorded by the stream itself (overflow, failed check) take precedence over the given kind.
+*/
+fn read_error(
+    stream: &mut ReadStream,
+    kind: ProtocolErrorKind,
+    packet_type: Option<u32>,
+) -> ProtocolError {
+    let stream_kind = stream.get_error();
+    let mut error = ProtocolError::new(if stream_kind != ProtocolErrorKind::None {
+        stream_kind
+    } else {
+        kind
+    });
+    error.packet_type = packet_type;
+    error.field = stream.get_last_check().map(String::from);
+    error.bit_error = stream.get_bit_error();
+    error.bit_offset = match error.bit_error {
+        Some(bit_error) => bit_error.bit_offset,
+        None => stream.get_bits_processed(),
+    };
+    error
 }
 
+/**
+    Reads a packet written by write_packet.
+    On failure the error says which packet type and field broke, and at which bit.
+*/
 pub fn read_packet(
     info: &PacketInfo,
-    buffer: &mut Buffer,
-    header: Option<&mut dyn Object>,
-    error: &mut ProtocolError,
-) -> Option<Box<dyn Packet>> {
-    assert!(buffer.len() > 0);
-
-    if *error != ProtocolError::None {
-        *error = ProtocolError::None;
+    buffer: &[u8],
+    header: Option<&mut dyn DynObject>,
+) -> Result<Box<dyn Packet>, ProtocolError> {
+    // Empty datagrams come off the network like any other
+    if buffer.is_empty() {
+        return Err(ProtocolError::new(ProtocolErrorKind::StreamOverflow));
     }
 
-    let buffer_ptr = buffer.as_ptr() as *mut u8;
-    let buffer_length = buffer.len();
-    let mut stream = ReadStream::new(buffer, buffer.len());
-    // stream.SetContext(info.context);
+    let mut stream = ReadStream::new(buffer);
+    if let Some(context) = info.context {
+        stream.set_context(context);
+    }
 
     for _i in 0..info.prefix_bytes {
         let mut dummy: u32 = 0;
-        stream.serialize_bits(&mut dummy, 8);
+        if !stream.serialize_bits(&mut dummy, 8) {
+            return Err(read_error(
+                &mut stream,
+                ProtocolErrorKind::StreamOverflow,
+                None,
+            ));
+        }
     }
 
-    // TODO: Move crc32 stuff into functions.
-    let mut read_crc32 = 0;
     if !info.raw_format {
-        stream.serialize_bits(&mut read_crc32, 32);
-
-        // On read, we skip prefix bytes
-        // Overwrite CRC with 0's
-        // Read the rest.
-
-        unsafe {
-            let src_ptr = buffer_ptr.add(info.prefix_bytes as usize + 4);
-            let protocol_bytes_temp = info.protocol_id.to_le_bytes();
-            let protocol_bytes = protocol_bytes_temp.as_slice();
-            let buffer_bytes =
-                from_raw_parts::<u8>(src_ptr, buffer_length - info.prefix_bytes as usize - 4);
-            let mut crc_bytes: Vec<u8> = vec![];
-            crc_bytes.extend_from_slice(protocol_bytes);
-            crc_bytes.extend_from_slice(&[0, 0, 0, 0]); // Fill in space that CRC32 was in
-            crc_bytes.extend_from_slice(buffer_bytes);
-
-            let crc_32 = crc32fast::hash(&crc_bytes);
-
-            assert_eq!(
-                read_crc32, crc_32,
-                "Corrupt packet. Expected CRC32: {:?}, got CRC32: {:?}",
-                read_crc32, crc_32
-            );
+        let mut read_crc32 = 0;
+        if !stream.serialize_bits(&mut read_crc32, 32) {
+            return Err(read_error(
+                &mut stream,
+                ProtocolErrorKind::StreamOverflow,
+                None,
+            ));
+        }
+
+        // A packet from another protocol hashes with a different protocol id, so it is rejected here too
+        let crc_32 = calc_packet_crc32(
+            &buffer[info.prefix_bytes as usize..],
+            info.config.protocol_id(),
+        );
+        if read_crc32 != crc_32 {
+            let mut error = ProtocolError::new(ProtocolErrorKind::InvalidCrc32);
+            error.bit_offset = info.prefix_bytes * 8;
+            return Err(error);
         }
     }
 
-    match header {
-        Some(header) => {
-            if !header.serialize_internal_r(&mut stream) {
-                if *error != ProtocolError::None {
-                    *error = ProtocolError::SerializeHeaderFailed
-                }
-            }
+    if let Some(header) = header {
+        if !header.serialize_dyn(&mut stream) {
+            return Err(read_error(
+                &mut stream,
+                ProtocolErrorKind::SerializeHeaderFailed,
+                None,
+            ));
         }
-        None => (),
     }
 
     let mut packet_type: u32 = 0;
     let num_packet_types = info.packet_factory.get_num_packet_types();
-    assert!(num_packet_types > 0);
+    if num_packet_types == 0 {
+        return Err(read_error(
+            &mut stream,
+            ProtocolErrorKind::InvalidPacketType,
+            None,
+        ));
+    }
 
+    // Read as raw bits, so a type past the last one is reported as an invalid packet type
     if num_packet_types > 1 {
-        let mut temp_packet_type: i32 = 0;
-        if !stream.serialise_int(&mut temp_packet_type, 0, num_packet_types as i32) {
-            if *error != ProtocolError::None {
-                *error = ProtocolError::InvalidPacketType;
-                return None;
-            }
+        let bits = bits_required!(0u32, num_packet_types - 1);
+        if !stream.serialize_bits(&mut packet_type, bits) {
+            return Err(read_error(
+                &mut stream,
+                ProtocolErrorKind::InvalidPacketType,
+                None,
+            ));
+        }
+        if packet_type >= num_packet_types {
+            return Err(read_error(
+                &mut stream,
+                ProtocolErrorKind::InvalidPacketType,
+                Some(packet_type),
+            ));
         }
-        packet_type = temp_packet_type as u32;
     }
 
     if !info.allowed_packet_types.contains(&packet_type) {
-        if *error == ProtocolError::None {
-            *error = ProtocolError::PacketTypeNotAllowed;
-        }
-        return None;
+        return Err(read_error(
+            &mut stream,
+            ProtocolErrorKind::PacketTypeNotAllowed,
+            Some(packet_type),
+        ));
     }
 
     let mut packet = info.packet_factory.create_packet(packet_type);
 
-    if !packet.serialize_internal_r(&mut stream) {
-        if *error == ProtocolError::None {
-            *error = ProtocolError::SerializePacketFailed;
-        }
-        return None;
+    if !packet.serialize_dyn(&mut stream) {
+        return Err(read_error(
+            &mut stream,
+            ProtocolErrorKind::SerializePacketFailed,
+            Some(packet_type),
+        ));
     }
 
     if !stream.serialize_check(&mut String::from("end of packet")) {
-        if *error == ProtocolError::None {
-            *error = ProtocolError::SerializeCheckFailed;
-        }
-        return None;
-    }
-
-    if stream.get_error() != ProtocolError::None {
-        if *error == ProtocolError::None {
-            *error = stream.get_error();
-        }
-        return None;
+        return Err(read_error(
+            &mut stream,
+            ProtocolErrorKind::SerializeCheckFailed,
+            Some(packet_type),
+        ));
     }
 
-    if *error != ProtocolError::None {
-        return None;
+    if stream.get_error() != ProtocolErrorKind::None {
+        return Err(read_error(
+            &mut stream,
+            ProtocolErrorKind::None,
+            Some(packet_type),
+        ));
     }
 
-    return Some(packet);
+    Ok(packet)
 }
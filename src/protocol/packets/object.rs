@@ -1,14 +1,34 @@
-use crate::protocol::streams::{read_stream::ReadStream, write_stream::WriteStream};
+use std::any::Any;
+
+use crate::protocol::streams::Stream;
 
 /**
- * Objects have a two serialize functions for reading and writing an object
+ * Objects have a single serialize function that works against any stream (read, write, measure...)
+ * Check stream.is_reading() / stream.is_writing() when the two directions need to differ.
  */
 pub trait Object {
-    // fn serialize(&mut self, stream: &mut dyn Stream) -> bool;
-    fn serialize_internal_r(&mut self, stream: &mut ReadStream) -> bool;
-    fn serialize_internal_w(&mut self, stream: &mut WriteStream) -> bool;
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool;
+}
+
+/**
+ * Object safe shim over Object, so packets and headers can be passed around as trait objects.
+ * Implemented for every Object, never implement it by hand.
+ */
+pub trait DynObject {
+    fn serialize_dyn(&mut self, stream: &mut dyn Stream) -> bool;
 }
 
-pub trait Packet: Object {
+impl<T: Object> DynObject for T {
+    fn serialize_dyn(&mut self, mut stream: &mut dyn Stream) -> bool {
+        self.serialize(&mut stream)
+    }
+}
+
+pub trait Packet: DynObject + Any {
     fn get_packet_type(&self) -> u32;
 }
+
+/** Looks at a read packet as its concrete type, if it is a T */
+pub fn downcast_packet<T: Packet>(packet: &dyn Packet) -> Option<&T> {
+    (packet as &dyn Any).downcast_ref::<T>()
+}
@@ -1,178 +1,394 @@
-use crate::protocol::{constants::*, helpers::calc_packet_crc32, streams::read_stream::ReadStream};
+use crate::protocol::{
+    helpers::calc_packet_crc32, protocol_config::ProtocolConfig, sequence::Sequence16,
+    sequence_buffer::SequenceBuffer, streams::read_stream::ReadStream,
+};
 
-use super::fragment_packet::FragmentPacket;
+use super::fragment_packet::{xor_into, FragmentPacket, FRAGMENT_PACKET_TYPE};
+use super::object::Object;
+use super::packet_data::PacketData;
 
-pub struct PacketBufferEntry<'a> {
-    sequence: u16,                  // packet sequence number
+#[derive(Default)]
+pub struct PacketBufferEntry {
     num_fragments: u32,             // number of fragments for this packet
-    received_fragments: u32,        // number of received fragments so far
+    received_fragments: u32,        // number of received (or recovered) fragments so far
     fragment_size: Vec<u32>,        // size of fragment n in bytes
-    fragment_data: &'a mut Vec<u8>, // pointer to data for fragment n
+    fragment_data: Vec<Vec<u8>>, // data for fragment n, owned by the entry until the packet is received
+    num_parity_fragments: u32,   // number of parity fragments sent with this packet
+    received_parity_fragments: u32, // number of received parity fragments so far
+    parity_data: Vec<Vec<u8>>,   // data for parity fragment n, empty until received
+    last_fragment_size: u32, // size of the last fragment, from the parity fragments. 0 until one arrives
+}
+
+impl PacketBufferEntry {
+    /**
+        Rebuilds lost fragments from the parity fragments. A fragment can be rebuilt when its
+        parity fragment and every other fragment covered by that parity fragment have arrived.
+        Returns the number of fragments recovered.
+    */
+    fn recover_fragments(&mut self, max_fragment_size: usize) -> u32 {
+        let mut num_recovered = 0;
+
+        for parity_id in 0..self.num_parity_fragments as usize {
+            if self.parity_data[parity_id].is_empty() {
+                continue;
+            }
+
+            let group = (parity_id..self.num_fragments as usize)
+                .step_by(self.num_parity_fragments as usize);
+            let missing: Vec<usize> = group
+                .clone()
+                .filter(|&fragment_id| self.fragment_size[fragment_id] == 0)
+                .collect();
+            if missing.len() != 1 {
+                continue;
+            }
+
+            let fragment_id = missing[0];
+            let fragment_size = if fragment_id as u32 == self.num_fragments - 1 {
+                self.last_fragment_size as usize
+            } else {
+                max_fragment_size
+            };
+            if fragment_size == 0 || fragment_size > self.parity_data[parity_id].len() {
+                continue;
+            }
+
+            let mut data = self.parity_data[parity_id].clone();
+            for other_id in group.filter(|&other_id| other_id != fragment_id) {
+                xor_into(&mut data, &self.fragment_data[other_id]);
+            }
+            data.truncate(fragment_size);
+
+            self.fragment_size[fragment_id] = fragment_size as u32;
+            self.fragment_data[fragment_id] = data;
+            self.received_fragments += 1;
+            num_recovered += 1;
+        }
+
+        num_recovered
+    }
 }
 
 /** PacketBuffer is used to process packets as a RECEIVER */
-pub struct PacketBuffer<'a> {
-    pub current_sequence: u16, // sequence number of most recent packet in buffer
+pub struct PacketBuffer {
+    pub config: ProtocolConfig, // protocol id and fragment limits, must match the sender's config
     pub num_buffered_fragments: u32, // total number of fragments stored in the packet buffer (across *all* packets)
-    pub valid: [bool; PACKET_BUFFER_SIZE], // true if there is a valid buffered packet entry at this index
-    pub entries: Vec<PacketBufferEntry<'a>>, // buffered packets in range [ current_sequence - PacketBufferSize + 1, current_sequence ] (modulo 65536)
+    pub entries: SequenceBuffer<PacketBufferEntry>, // buffered packets in range [ current_sequence - PacketBufferSize + 1, current_sequence ] (modulo 65536)
+    pub num_invalid_crc32_packets: u64, // packets dropped because their crc32 did not match (corrupt, or from another protocol)
+    pub num_recovered_fragments: u64,   // lost fragments rebuilt from parity fragments
+    pub num_invalid_fragment_packets: u64, // packets dropped because they passed the crc32 check but failed to parse
+    pub received_first_packet: bool, // until then the sender's sequence is unknown, so there is no current sequence to gate on
 }
 
-impl PacketBuffer<'_> {
-    pub fn new() -> PacketBuffer<'static> {
-        return PacketBuffer {
-            current_sequence: 0,
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        Self::new(&ProtocolConfig::default())
+    }
+}
+
+impl PacketBuffer {
+    pub fn new(config: &ProtocolConfig) -> PacketBuffer {
+        PacketBuffer {
+            config: *config,
             num_buffered_fragments: 0,
-            valid: [false; PACKET_BUFFER_SIZE],
-            entries: vec![],
-        };
+            entries: SequenceBuffer::new(config.packet_buffer_size()),
+            num_invalid_crc32_packets: 0,
+            num_recovered_fragments: 0,
+            num_invalid_fragment_packets: 0,
+            received_first_packet: false,
+        }
     }
 
     /**
         Process packet fragment
 
         - Stores each fragment ready to receive the whole packet once all fragments for that packet are received.
-        - If any fragment is dropped, fragments are not resent, the whole packet is dropped.
+        - If any fragment is dropped, fragments are not resent. The whole packet is dropped,
+          unless it was sent with parity fragments that can rebuild the lost fragment.
 
         NOTE: This function is fairly complicated because it must handle all possible cases
         of maliciously constructed packets attempting to overflow and corrupt the packet buffer!
     */
     pub fn process_fragment(
         &mut self,
-        fragment_data: &mut Buffer,
-        fragment_size: u32,
+        fragment_data: &[u8],
         packet_sequence: u16,
         fragment_id: usize,
         num_fragments_in_packet: u32,
+        num_parity_fragments: u32,
     ) -> bool {
+        let fragment_size = fragment_data.len();
+
         // Fragment size <= 0 ? Discard the fragment
-        if fragment_size <= 0 {
+        if fragment_size == 0 {
             return false;
         }
 
         // fragment size exceeds max fragment size? discard the fragment.
-        if fragment_size as usize > MAX_FRAGMENT_SIZE {
+        if fragment_size > self.config.max_fragment_size() {
             return false;
         }
 
         // num fragments outside of range? discard the fragment
-        if num_fragments_in_packet <= 0
-            || num_fragments_in_packet as usize > MAX_FRAGMENTS_PER_PACKET
+        if num_fragments_in_packet == 0
+            || num_fragments_in_packet as usize > self.config.max_fragments_per_packet()
+            || num_parity_fragments > num_fragments_in_packet
         {
             return false;
         }
 
         // fragment index out of range? discard the fragment
-        if fragment_id <= 0 || fragment_id >= num_fragments_in_packet.try_into().unwrap() {
+        if fragment_id >= num_fragments_in_packet as usize {
             return false;
         }
 
         // if this is not the last fragment in the packet and fragment size is not equal to MaxFragmentSize, discard the fragment
         if fragment_id as u32 != num_fragments_in_packet - 1
-            && fragment_size != MAX_FRAGMENT_SIZE as u32
+            && fragment_size != self.config.max_fragment_size()
         {
             return false;
         }
 
-        // packet sequence number wildly out of range from the current sequence? discard the fragment
+        let max_fragment_size = self.config.max_fragment_size();
+        let max_fragments_per_packet = self.config.max_fragments_per_packet();
+        let Some(entry) = self.find_or_insert_entry(
+            packet_sequence,
+            num_fragments_in_packet,
+            num_parity_fragments,
+        ) else {
+            return false;
+        };
 
-        // if ( protocol2::sequence_difference( packetSequence, currentSequence ) > 1024 )
-        //     return false;
+        // if this fragment has already been received, ignore it because it must have come from a duplicate packet
+        assert!(fragment_id < num_fragments_in_packet as usize);
+        assert!(num_fragments_in_packet as usize <= max_fragments_per_packet);
 
-        // if the entry exists, but has a different sequence number, discard the fragment
-        let index = packet_sequence as usize % PACKET_BUFFER_SIZE;
-        if self.valid[index] && self.entries[index].sequence != packet_sequence {
+        if entry.fragment_size[fragment_id] != 0 {
             return false;
         }
 
-        // if the entry does not exist, add an entry for this sequence # and set total fragments
-        if !self.valid[index] {
-            // Advance( packetSequence );
-            // entries[index].sequence = packetSequence;
-            // entries[index].numFragments = numFragmentsInPacket;
-            // assert( entries[index].receivedFragments == 0 );            // IMPORTANT: Should have already been cleared to zeros in "Advance"
-            // valid[index] = true;
-        }
+        // add the fragment to the packet buffer
+        assert!(fragment_size > 0);
+        assert!(fragment_size <= max_fragment_size);
 
-        // at this point the entry must exist and have the same sequence number as the fragment
-        assert!(self.valid[index]);
-        assert!(self.entries[index].sequence == packet_sequence);
+        entry.fragment_size[fragment_id] = fragment_size as u32;
+        entry.fragment_data[fragment_id] = fragment_data.to_vec();
+        entry.received_fragments += 1;
 
-        // if the total number fragments is different for this packet vs. the entry, discard the fragment
-        if num_fragments_in_packet != self.entries[index].num_fragments {
-            return false;
-        }
+        let num_recovered = entry.recover_fragments(max_fragment_size);
 
-        // if this fragment has already been received, ignore it because it must have come from a duplicate packet
-        assert!(fragment_id < num_fragments_in_packet.try_into().unwrap());
-        assert!(fragment_id < MAX_FRAGMENTS_PER_PACKET.try_into().unwrap());
-        assert!(num_fragments_in_packet <= MAX_FRAGMENTS_PER_PACKET as u32);
+        assert!(entry.received_fragments <= entry.num_fragments);
+
+        self.num_buffered_fragments += 1 + num_recovered;
+        self.num_recovered_fragments += num_recovered as u64;
 
-        if self.entries[index].fragment_size[fragment_id] != 0 {
+        true
+    }
+
+    /**
+        Process a parity fragment, see split_packet_into_fragments_with_parity.
+        Stored like a fragment, and used to rebuild a lost fragment of its packet once the rest of its group arrives.
+    */
+    pub fn process_parity_fragment(
+        &mut self,
+        parity_data: &[u8],
+        packet_sequence: u16,
+        parity_id: usize,
+        num_fragments_in_packet: u32,
+        num_parity_fragments: u32,
+        last_fragment_size: u32,
+    ) -> bool {
+        let max_fragment_size = self.config.max_fragment_size();
+
+        // same limits as a fragment, and the parity fragment must be one of the packet's parity fragments
+        if parity_data.is_empty()
+            || parity_data.len() > max_fragment_size
+            || num_fragments_in_packet == 0
+            || num_fragments_in_packet as usize > self.config.max_fragments_per_packet()
+            || num_parity_fragments > num_fragments_in_packet
+            || parity_id >= num_parity_fragments as usize
+            || last_fragment_size == 0
+            || last_fragment_size as usize > max_fragment_size
+        {
             return false;
         }
 
-        // add the fragment to the packet buffer
-        println!(
-            "Added fragment {:?} of packet {:?} to buffer",
-            fragment_id, packet_sequence
-        );
+        let Some(entry) = self.find_or_insert_entry(
+            packet_sequence,
+            num_fragments_in_packet,
+            num_parity_fragments,
+        ) else {
+            return false;
+        };
 
-        assert!(fragment_size > 0);
-        assert!(fragment_size <= MAX_FRAGMENT_SIZE.try_into().unwrap());
+        // duplicate, or disagrees with the parity fragments already received? discard it
+        if !entry.parity_data[parity_id].is_empty()
+            || (entry.last_fragment_size != 0 && entry.last_fragment_size != last_fragment_size)
+        {
+            return false;
+        }
 
-        self.entries[index].fragment_size[fragment_id] = fragment_size;
-        // entries[index].fragmentData[fragmentId] = new uint8_t[fragmentSize];
-        // memcpy( entries[index].fragmentData[fragmentId], fragmentData, fragmentSize );
-        self.entries[index].received_fragments += 1;
+        entry.parity_data[parity_id] = parity_data.to_vec();
+        entry.received_parity_fragments += 1;
+        entry.last_fragment_size = last_fragment_size;
 
-        assert!(self.entries[index].received_fragments <= self.entries[index].num_fragments);
+        let num_recovered = entry.recover_fragments(max_fragment_size);
 
-        self.num_buffered_fragments += 1;
+        self.num_buffered_fragments += 1 + num_recovered;
+        self.num_recovered_fragments += num_recovered as u64;
 
         true
     }
 
     /**
-        Advance the current sequence for the packet buffer forward.
-        This function removes old packet entries and frees their fragments.
+        Entry for a packet sequence, added if this is the first fragment of the packet.
+        None if the sequence is out of range, or the entry disagrees on the number of fragments.
     */
-    fn advance(&mut self) {}
+    fn find_or_insert_entry(
+        &mut self,
+        packet_sequence: u16,
+        num_fragments_in_packet: u32,
+        num_parity_fragments: u32,
+    ) -> Option<&mut PacketBufferEntry> {
+        // the first packet can have any sequence, start the buffer there
+        if !self.received_first_packet {
+            self.entries.reset_to(packet_sequence);
+            self.received_first_packet = true;
+        }
 
-    /** Method for processing a RECEIVED packet */
-    pub fn process_packet(&mut self, data: &mut Buffer, size: u32) -> bool {
-        let mut stream = ReadStream::new(data, size as usize);
-        let mut fragment_packet = FragmentPacket::new();
+        // packet sequence number wildly out of range from the current sequence? discard the fragment
+        if Sequence16(packet_sequence)
+            .difference(Sequence16(self.current_sequence()))
+            .unsigned_abs()
+            > 1024
+        {
+            return None;
+        }
 
-        // Serialize the packet data into the fragment_packet
-        if !fragment_packet.serialize(&mut stream) {
-            println!("Error: Fragment packet failed to serialize");
+        // move the buffer forward first, so an old incomplete packet in this slot is evicted instead of blocking this one
+        for evicted in self.entries.advance(packet_sequence) {
+            self.num_buffered_fragments -=
+                evicted.received_fragments + evicted.received_parity_fragments;
+        }
+
+        // if the entry does not exist, add an entry for this sequence # and set total fragments
+        if !self.entries.exists(packet_sequence) {
+            let entry = PacketBufferEntry {
+                num_fragments: num_fragments_in_packet,
+                received_fragments: 0,
+                fragment_size: vec![0; num_fragments_in_packet as usize],
+                fragment_data: vec![vec![]; num_fragments_in_packet as usize],
+                num_parity_fragments,
+                received_parity_fragments: 0,
+                parity_data: vec![vec![]; num_parity_fragments as usize],
+                last_fragment_size: 0,
+            };
+
+            // too old to fit in the buffer? discard the fragment
+            self.entries.insert(packet_sequence, entry)?;
+        }
+
+        // at this point the entry must exist and have the same sequence number as the fragment
+        let entry = self.entries.find_mut(packet_sequence).unwrap();
+
+        // if the total number fragments is different for this packet vs. the entry, discard the fragment
+        if num_fragments_in_packet != entry.num_fragments
+            || num_parity_fragments != entry.num_parity_fragments
+        {
+            return None;
+        }
+
+        Some(entry)
+    }
+
+    /** Sequence number of the most recent packet in the buffer */
+    pub fn current_sequence(&self) -> u16 {
+        self.entries.current_sequence()
+    }
+
+    /**
+        Method for processing a RECEIVED packet.
+        Packets that fail the crc32 check are dropped and counted in num_invalid_crc32_packets,
+        packets that pass it but fail to parse are dropped and counted in num_invalid_fragment_packets.
+
+        Fragments are buffered until every fragment of their packet has arrived,
+        regular packets are buffered as a packet with a single fragment.
+        Either way the whole packet comes back out of receive_packets.
+    */
+    pub fn process_packet(&mut self, data: &[u8], size: u32) -> bool {
+        if size as usize > data.len() {
             return false;
         }
 
-        /*
-            uint32_t protocolId = protocol2::host_to_network( ProtocolId );
-            uint32_t crc32 = protocol2::calculate_crc32( (const uint8_t*) &protocolId, 4 );
-            uint32_t zero = 0;
-            crc32 = protocol2::calculate_crc32( (const uint8_t*) &zero, 4, crc32 );
-            crc32 = protocol2::calculate_crc32( data + 4, size - 4, crc32 );
-        */
+        let data = &data[..size as usize];
+        if data.len() < 4 {
+            return false;
+        }
 
-        let crc32 = calc_packet_crc32(data, PROTOCOL_ID);
+        // Check the crc32 before parsing, packets from another protocol may not parse with this config
+        let crc32 = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if calc_packet_crc32(data, self.config.protocol_id()) != crc32 {
+            self.num_invalid_crc32_packets += 1;
+            return false;
+        }
 
-        if crc32 != fragment_packet.crc32 {
-            println!(
-                "Corrupt packet: Expected crc32 {:?}, got {:?}",
-                crc32, fragment_packet.crc32
-            );
+        let mut stream = ReadStream::new(data);
+        let mut fragment_packet = FragmentPacket::new(&self.config);
+
+        // Serialize the packet data into the fragment_packet
+        if !fragment_packet.serialize(&mut stream) {
+            self.num_invalid_fragment_packets += 1;
+            return false;
         }
 
-        if fragment_packet.packet_type == 0 {
-            // return ProcessFragment( data + PacketFragmentHeaderBytes, fragmentPacket.fragmentSize, fragmentPacket.sequence, fragmentPacket.fragmentId, fragmentPacket.numFragments );
+        if fragment_packet.packet_type != FRAGMENT_PACKET_TYPE {
+            self.process_fragment(data, fragment_packet.sequence, 0, 1, 0)
+        } else if fragment_packet.is_parity {
+            self.process_parity_fragment(
+                &fragment_packet.fragment_data,
+                fragment_packet.sequence,
+                fragment_packet.fragment_id as usize,
+                fragment_packet.num_fragments,
+                fragment_packet.num_parity_fragments,
+                fragment_packet.last_fragment_size,
+            )
         } else {
-            // return ProcessFragment( data, size, fragmentPacket.sequence, 0, 1 );
+            self.process_fragment(
+                &fragment_packet.fragment_data,
+                fragment_packet.sequence,
+                fragment_packet.fragment_id as usize,
+                fragment_packet.num_fragments,
+                fragment_packet.num_parity_fragments,
+            )
+        }
+    }
+
+    /**
+        Returns every packet that has all of its fragments, oldest sequence first.
+        The fragments are joined back into the original packet and removed from the buffer.
+    */
+    pub fn receive_packets(&mut self) -> Vec<PacketData> {
+        let mut packets: Vec<PacketData> = vec![];
+        let sequences: Vec<u16> = self.entries.sequences().collect();
+
+        for sequence in sequences {
+            // have all fragments arrived for this packet?
+            let entry = self.entries.find(sequence).unwrap();
+            if entry.received_fragments != entry.num_fragments {
+                continue;
+            }
+
+            // reconstruct the packet from the fragment data
+            let entry = self.entries.remove(sequence).unwrap();
+            let data: Vec<u8> = entry.fragment_data.concat();
+            packets.push(PacketData {
+                size: data.len() as u32,
+                data,
+            });
+
+            self.num_buffered_fragments -= entry.num_fragments + entry.received_parity_fragments;
         }
 
-        true
+        packets
     }
 }
@@ -0,0 +1,286 @@
+use std::fmt;
+
+use super::constants::*;
+use crate::bits_required;
+
+/** Reason a ProtocolConfigBuilder refused to build */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProtocolConfigError {
+    InvalidPacketBufferSize, // zero, not a power of two, or more than half the sequence range
+    InvalidMaxFragmentSize,  // zero
+    InvalidMaxFragmentsPerPacket, // zero, or more than the 8 bit fragment id can address
+    FragmentHeaderTooSmall,  // fewer bytes than the serialized fragment header needs
+    InvalidAckBufferSize, // not a power of two, smaller than the 32 bit ack bitfield, or more than half the sequence range
+    InvalidNumPacketTypes, // fewer than two, fragments take packet type 0
+}
+
+impl fmt::Display for ProtocolConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ProtocolConfigError::InvalidPacketBufferSize => {
+                "packet buffer size must be a power of two between 1 and 32768"
+            }
+            ProtocolConfigError::InvalidMaxFragmentSize => "max fragment size must not be zero",
+            ProtocolConfigError::InvalidMaxFragmentsPerPacket => {
+                "max fragments per packet must be between 1 and 256"
+            }
+            ProtocolConfigError::FragmentHeaderTooSmall => {
+                "fragment header bytes is too small to hold the fragment header"
+            }
+            ProtocolConfigError::InvalidAckBufferSize => {
+                "ack buffer size must be a power of two between 32 and 32768"
+            }
+            ProtocolConfigError::InvalidNumPacketTypes => {
+                "num packet types must be at least 2, fragments take packet type 0"
+            }
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for ProtocolConfigError {}
+
+/**
+    Settings both ends of a connection must agree on.
+
+    Each endpoint can use its own config, so one process can run several profiles side by side.
+    ex. a LAN profile with large fragments, and an internet profile with a smaller MTU and a different protocol id.
+    Built with ProtocolConfig::builder(), the default is the values in protocol::constants.
+*/
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProtocolConfig {
+    protocol_id: u32, // salts the packet crc32, so packets from other protocols are dropped
+    packet_buffer_size: usize, // number of packets the receiver buffers while reassembling fragments
+    max_fragment_size: usize,  // packets bigger than this are split into fragments of this size
+    max_fragments_per_packet: usize, // largest packet is max_fragment_size * max_fragments_per_packet bytes
+    fragment_header_bytes: usize, // room reserved in each fragment datagram for the fragment header
+    ack_buffer_size: usize, // number of sent and received packets a ReliableEndpoint remembers for acks
+    num_packet_types: usize, // same as the PacketFactory's, fragments write their packet type 0 in as many bits as regular packets
+}
+
+impl ProtocolConfig {
+    pub fn builder() -> ProtocolConfigBuilder {
+        ProtocolConfigBuilder {
+            config: ProtocolConfig::default(),
+        }
+    }
+
+    pub fn protocol_id(&self) -> u32 {
+        self.protocol_id
+    }
+
+    pub fn packet_buffer_size(&self) -> usize {
+        self.packet_buffer_size
+    }
+
+    pub fn max_fragment_size(&self) -> usize {
+        self.max_fragment_size
+    }
+
+    pub fn max_fragments_per_packet(&self) -> usize {
+        self.max_fragments_per_packet
+    }
+
+    pub fn fragment_header_bytes(&self) -> usize {
+        self.fragment_header_bytes
+    }
+
+    pub fn ack_buffer_size(&self) -> usize {
+        self.ack_buffer_size
+    }
+
+    pub fn num_packet_types(&self) -> usize {
+        self.num_packet_types
+    }
+
+    /** Largest packet that can be split into fragments */
+    pub fn max_packet_size(&self) -> usize {
+        self.max_fragment_size * self.max_fragments_per_packet
+    }
+
+    /** Largest datagram a fragment can take on the wire */
+    pub fn max_packet_fragment_size(&self) -> usize {
+        self.max_fragment_size + self.fragment_header_bytes
+    }
+
+    /** Bytes the fragment header takes once serialized, see FragmentPacket */
+    fn min_fragment_header_bytes(&self) -> usize {
+        let bits = 32 // crc32
+            + 16 // sequence
+            + bits_required!(0u32, self.num_packet_types as u32 - 1)
+            + 8 // fragment id
+            + bits_required!(1u32, self.max_fragments_per_packet as u32)
+            + bits_required!(0u32, self.max_fragments_per_packet as u32) // num parity fragments
+            + 1 // is parity
+            + bits_required!(1u32, self.max_fragment_size as u32); // last fragment size
+        bits.div_ceil(8) as usize
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig {
+            protocol_id: PROTOCOL_ID,
+            packet_buffer_size: PACKET_BUFFER_SIZE,
+            max_fragment_size: MAX_FRAGMENT_SIZE,
+            max_fragments_per_packet: MAX_FRAGMENTS_PER_PACKET,
+            fragment_header_bytes: PACKET_FRAGMENT_HEADER_BYTES,
+            ack_buffer_size: ACK_BUFFER_SIZE,
+            num_packet_types: NUM_PACKET_TYPES,
+        }
+    }
+}
+
+/** Builds a ProtocolConfig, starting from the defaults. Settings are checked in build. */
+pub struct ProtocolConfigBuilder {
+    config: ProtocolConfig,
+}
+
+impl ProtocolConfigBuilder {
+    pub fn protocol_id(mut self, protocol_id: u32) -> ProtocolConfigBuilder {
+        self.config.protocol_id = protocol_id;
+        self
+    }
+
+    pub fn packet_buffer_size(mut self, packet_buffer_size: usize) -> ProtocolConfigBuilder {
+        self.config.packet_buffer_size = packet_buffer_size;
+        self
+    }
+
+    pub fn max_fragment_size(mut self, max_fragment_size: usize) -> ProtocolConfigBuilder {
+        self.config.max_fragment_size = max_fragment_size;
+        self
+    }
+
+    pub fn max_fragments_per_packet(
+        mut self,
+        max_fragments_per_packet: usize,
+    ) -> ProtocolConfigBuilder {
+        self.config.max_fragments_per_packet = max_fragments_per_packet;
+        self
+    }
+
+    pub fn fragment_header_bytes(mut self, fragment_header_bytes: usize) -> ProtocolConfigBuilder {
+        self.config.fragment_header_bytes = fragment_header_bytes;
+        self
+    }
+
+    pub fn ack_buffer_size(mut self, ack_buffer_size: usize) -> ProtocolConfigBuilder {
+        self.config.ack_buffer_size = ack_buffer_size;
+        self
+    }
+
+    pub fn num_packet_types(mut self, num_packet_types: usize) -> ProtocolConfigBuilder {
+        self.config.num_packet_types = num_packet_types;
+        self
+    }
+
+    pub fn build(self) -> Result<ProtocolConfig, ProtocolConfigError> {
+        let config = self.config;
+
+        // the packet buffer is a SequenceBuffer, indexed by a wrapping 16 bit sequence
+        if !config.packet_buffer_size.is_power_of_two() || config.packet_buffer_size > 32768 {
+            return Err(ProtocolConfigError::InvalidPacketBufferSize);
+        }
+
+        if config.max_fragment_size == 0 {
+            return Err(ProtocolConfigError::InvalidMaxFragmentSize);
+        }
+
+        // fragment id is serialized in 8 bits
+        if config.max_fragments_per_packet == 0 || config.max_fragments_per_packet > 256 {
+            return Err(ProtocolConfigError::InvalidMaxFragmentsPerPacket);
+        }
+
+        // checked before the fragment header size, which depends on it
+        if config.num_packet_types < 2 || config.num_packet_types > i32::MAX as usize {
+            return Err(ProtocolConfigError::InvalidNumPacketTypes);
+        }
+
+        if config.fragment_header_bytes < config.min_fragment_header_bytes() {
+            return Err(ProtocolConfigError::FragmentHeaderTooSmall);
+        }
+
+        // every sequence in the ack bitfield must still be in the buffer
+        if !config.ack_buffer_size.is_power_of_two()
+            || config.ack_buffer_size < 32
+            || config.ack_buffer_size > 32768
+        {
+            return Err(ProtocolConfigError::InvalidAckBufferSize);
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_config() {
+        let config = ProtocolConfig::builder().build().unwrap();
+        assert_eq!(config, ProtocolConfig::default());
+        assert_eq!(config.protocol_id(), PROTOCOL_ID);
+        assert_eq!(config.max_packet_size(), MAX_PACKET_SIZE);
+        assert_eq!(config.max_packet_fragment_size(), MAX_PACKET_FRAGMENT_SIZE);
+
+        let config = ProtocolConfig::builder()
+            .protocol_id(0x11223344)
+            .packet_buffer_size(64)
+            .max_fragment_size(1200)
+            .max_fragments_per_packet(16)
+            .fragment_header_bytes(10)
+            .build()
+            .unwrap();
+        assert_eq!(config.protocol_id(), 0x11223344);
+        assert_eq!(config.packet_buffer_size(), 64);
+        assert_eq!(config.max_packet_size(), 1200 * 16);
+        assert_eq!(config.max_packet_fragment_size(), 1210);
+
+        assert_eq!(
+            ProtocolConfig::builder().packet_buffer_size(100).build(),
+            Err(ProtocolConfigError::InvalidPacketBufferSize)
+        );
+        assert_eq!(
+            ProtocolConfig::builder().packet_buffer_size(65536).build(),
+            Err(ProtocolConfigError::InvalidPacketBufferSize)
+        );
+        assert_eq!(
+            ProtocolConfig::builder().max_fragment_size(0).build(),
+            Err(ProtocolConfigError::InvalidMaxFragmentSize)
+        );
+        assert_eq!(
+            ProtocolConfig::builder()
+                .max_fragments_per_packet(257)
+                .build(),
+            Err(ProtocolConfigError::InvalidMaxFragmentsPerPacket)
+        );
+        assert_eq!(
+            ProtocolConfig::builder().fragment_header_bytes(8).build(),
+            Err(ProtocolConfigError::FragmentHeaderTooSmall)
+        );
+        assert_eq!(
+            ProtocolConfig::builder().ack_buffer_size(16).build(),
+            Err(ProtocolConfigError::InvalidAckBufferSize)
+        );
+        assert_eq!(
+            ProtocolConfig::builder().num_packet_types(1).build(),
+            Err(ProtocolConfigError::InvalidNumPacketTypes)
+        );
+
+        // More packet types make the fragment header bigger
+        let config = ProtocolConfig::builder()
+            .num_packet_types(1000)
+            .build()
+            .unwrap();
+        assert_eq!(config.num_packet_types(), 1000);
+        assert_eq!(
+            ProtocolConfig::builder()
+                .num_packet_types(1 << 30)
+                .fragment_header_bytes(13)
+                .build(),
+            Err(ProtocolConfigError::FragmentHeaderTooSmall)
+        );
+    }
+}
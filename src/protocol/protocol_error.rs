@@ -0,0 +1,84 @@
+use std::fmt;
+
+use super::{bitpacker::bit_error::BitError, helpers::get_error_string};
+
+/** What went wrong while reading or writing a packet */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProtocolErrorKind {
+    None = 0,
+    StreamOverflow = 1,
+    SerializeHeaderFailed = 2,
+    InvalidPacketType = 3,
+    PacketTypeNotAllowed = 4,
+    CreatePacketFailed = 5,
+    SerializePacketFailed = 6,
+    SerializeCheckFailed = 7,
+    InvalidCrc32 = 8,   // corrupted packet, or one sent with a different protocol id
+    StalePacket = 9,    // sequence too old for the reliable endpoint to track
+    DecryptFailed = 10, // forged or corrupted encrypted packet, or one encrypted with another key
+    ReplayedPacket = 11, // encrypted packet with a sequence already received
+    ValueOutOfRange = 12, // integer outside the range it is serialized with, or an empty range
+    InvalidPacketInfo = 13, // PacketInfo not set up the way the reader needs, ex. encrypted packets without raw_format
+}
+
+/**
+    Error returned by read_packet, with enough detail to find the message that broke.
+
+    The field is taken from the labels passed to serialize_check, so packets that
+    use checks (or #[check] fields with the derive) are reported down to the field.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    pub packet_type: Option<u32>, // None if decoding failed before the packet type was read
+    pub field: Option<String>, // label of the check that failed, or the last check passed before the failure
+    pub bit_offset: u32,       // bit position in the packet where decoding stopped
+    pub bit_error: Option<BitError>, // the bitpacker error behind a stream overflow, if any
+}
+
+impl ProtocolError {
+    pub fn new(kind: ProtocolErrorKind) -> ProtocolError {
+        ProtocolError {
+            kind,
+            packet_type: None,
+            field: None,
+            bit_offset: 0,
+            bit_error: None,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(get_error_string(*self))
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(packet_type) = self.packet_type {
+            write!(f, " in packet type {}", packet_type)?;
+        }
+        if let Some(field) = &self.field {
+            if self.kind == ProtocolErrorKind::SerializeCheckFailed {
+                write!(f, " at check '{}'", field)?;
+            } else {
+                write!(f, " after check '{}'", field)?;
+            }
+        }
+        write!(f, " at bit {}", self.bit_offset)?;
+        if let Some(bit_error) = &self.bit_error {
+            write!(f, " ({})", bit_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.bit_error
+            .as_ref()
+            .map(|error| error as &(dyn std::error::Error + 'static))
+    }
+}
@@ -0,0 +1,291 @@
+use bitpacker_derive::Serialize;
+
+use super::{
+    packets::{self, object::Packet, packet_info::PacketInfo},
+    protocol_config::ProtocolConfig,
+    protocol_error::{ProtocolError, ProtocolErrorKind},
+    sequence_buffer::SequenceBuffer,
+};
+
+/**
+    Header written in front of every packet sent through a ReliableEndpoint.
+
+    ack is the most recent sequence received from the other end, and bit n of ack_bits
+    is set if sequence ack - n was received too. So each packet acks the last 32 packets,
+    and an ack is only lost if 32 packets in a row are lost.
+*/
+#[derive(Serialize, Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReliablePacketHeader {
+    pub sequence: u16, // sequence of this packet
+    pub ack: u16,      // most recent sequence received
+    pub ack_bits: u32, // bit n set if ack - n was received
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct SentPacketData {
+    acked: bool,       // true once the other end has acked this packet
+    packet_bytes: u32, // size of the packet, including the header
+}
+
+/**
+    Tracks which packets the other end received.
+
+    Every packet written with write_packet gets a new sequence and acks the packets received so far.
+    When the other end's acks come back through read_packet, the sequences of our packets that
+    arrived are queued for receive_acks, so higher layers know what to resend and what to forget.
+*/
+pub struct ReliableEndpoint {
+    sequence: u16,                                // sequence of the next packet sent
+    sent_packets: SequenceBuffer<SentPacketData>, // packets sent, waiting to be acked
+    received_packets: SequenceBuffer<()>,         // packets received, acked in every packet sent
+    acks: Vec<u16>, // sequences of sent packets acked since the last receive_acks
+
+    pub num_packets_sent: u64,
+    pub num_packets_received: u64,
+    pub num_packets_acked: u64,
+    pub num_packets_stale: u64, // received packets too old to track, dropped
+}
+
+impl ReliableEndpoint {
+    pub fn new(config: &ProtocolConfig) -> ReliableEndpoint {
+        ReliableEndpoint {
+            sequence: 0,
+            sent_packets: SequenceBuffer::new(config.ack_buffer_size()),
+            received_packets: SequenceBuffer::new(config.ack_buffer_size()),
+            acks: vec![],
+            num_packets_sent: 0,
+            num_packets_received: 0,
+            num_packets_acked: 0,
+            num_packets_stale: 0,
+        }
+    }
+
+    /** Sequence the next packet sent will have */
+    pub fn next_sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /** Header for the next packet, acking everything received so far */
+    fn build_header(&self) -> ReliablePacketHeader {
+        let ack = self.received_packets.current_sequence();
+        let mut ack_bits: u32 = 0;
+        for i in 0..32 {
+            if self.received_packets.exists(ack.wrapping_sub(i)) {
+                ack_bits |= 1 << i;
+            }
+        }
+
+        ReliablePacketHeader {
+            sequence: self.sequence,
+            ack,
+            ack_bits,
+        }
+    }
+
+    /**
+        Returns the header for the next packet and records the packet as sent.
+        Use this when writing the header yourself, otherwise use write_packet.
+    */
+    pub fn generate_header(&mut self, packet_bytes: u32) -> ReliablePacketHeader {
+        let header = self.build_header();
+
+        self.sent_packets.insert(
+            header.sequence,
+            SentPacketData {
+                acked: false,
+                packet_bytes,
+            },
+        );
+        self.sequence = self.sequence.wrapping_add(1);
+        self.num_packets_sent += 1;
+
+        header
+    }
+
+    /**
+        Records a header received from the other end, and queues acks for any of our packets it acks.
+        Returns false if the packet is too old to track, it should be dropped.
+    */
+    pub fn process_header(&mut self, header: &ReliablePacketHeader) -> bool {
+        if self.received_packets.insert(header.sequence, ()).is_none() {
+            self.num_packets_stale += 1;
+            return false;
+        }
+        self.num_packets_received += 1;
+
+        for i in 0..32 {
+            if header.ack_bits & (1 << i) == 0 {
+                continue;
+            }
+
+            let sequence = header.ack.wrapping_sub(i);
+            if let Some(sent_packet) = self.sent_packets.find_mut(sequence) {
+                if !sent_packet.acked {
+                    sent_packet.acked = true;
+                    self.acks.push(sequence);
+                    self.num_packets_acked += 1;
+                }
+            }
+        }
+
+        true
+    }
+
+    /** Sequences of our packets acked since the last call, in the order the acks arrived */
+    pub fn receive_acks(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.acks)
+    }
+
+    /** True if the sent packet with this sequence has been acked. False if unacked or no longer tracked. */
+    pub fn is_acked(&self, sequence: u16) -> bool {
+        self.sent_packets
+            .find(sequence)
+            .is_some_and(|sent_packet| sent_packet.acked)
+    }
+
+    /** Size in bytes of a sent packet that is still tracked */
+    pub fn sent_packet_bytes(&self, sequence: u16) -> Option<u32> {
+        self.sent_packets
+            .find(sequence)
+            .map(|sent_packet| sent_packet.packet_bytes)
+    }
+
+    /**
+        Writes packet with a ReliablePacketHeader through packets::write_packet.
+        The sequence is only used up if the packet is written. Returns bytes written, 0 on failure.
+    */
+    pub fn write_packet(
+        &mut self,
+        info: &PacketInfo,
+        packet: &mut dyn Packet,
+        buffer: &mut [u8],
+        buffer_length: usize,
+    ) -> u32 {
+        let mut header = self.build_header();
+        let bytes_written =
+            packets::write_packet(info, packet, buffer, buffer_length, Some(&mut header));
+
+        if bytes_written > 0 {
+            let generated_header = self.generate_header(bytes_written);
+            assert_eq!(generated_header, header);
+        }
+
+        bytes_written
+    }
+
+    /** Reads a packet written by the other end's write_packet, and processes its acks */
+    pub fn read_packet(
+        &mut self,
+        info: &PacketInfo,
+        buffer: &[u8],
+    ) -> Result<Box<dyn Packet>, ProtocolError> {
+        let mut header = ReliablePacketHeader::default();
+        let packet = packets::read_packet(info, buffer, Some(&mut header))?;
+
+        if !self.process_header(&header) {
+            let mut error = ProtocolError::new(ProtocolErrorKind::StalePacket);
+            error.packet_type = Some(packet.get_packet_type());
+            return Err(error);
+        }
+
+        Ok(packet)
+    }
+
+    /** Most recent sequence received from the other end */
+    pub fn latest_received_sequence(&self) -> u16 {
+        self.received_packets.current_sequence()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::test_helpers::{TestPacket, TestPacketFactory};
+
+    #[test]
+    fn test_reliable_endpoint() {
+        let config = ProtocolConfig::default();
+        let packet_factory = TestPacketFactory::new(1, |packet_type| {
+            assert_eq!(packet_type, 0);
+            Box::new(TestPacket::default())
+        });
+        let mut info = PacketInfo::new(&packet_factory);
+        info.config = config;
+        info.allowed_packet_types = vec![0];
+
+        let mut sender = ReliableEndpoint::new(&config);
+        let mut receiver = ReliableEndpoint::new(&config);
+
+        // Every third packet is lost
+        for value in 0..100 {
+            let mut buffer = vec![0; 64];
+            let bytes_written =
+                sender.write_packet(&info, &mut TestPacket { value }, &mut buffer, 64);
+            assert!(bytes_written > 0);
+            if value % 3 == 0 {
+                continue;
+            }
+            receiver
+                .read_packet(&info, &buffer[..bytes_written as usize])
+                .unwrap();
+        }
+        assert_eq!(sender.num_packets_sent, 100);
+        assert_eq!(receiver.num_packets_received, 66);
+        assert_eq!(receiver.latest_received_sequence(), 98);
+
+        // The reply acks the last 32 packets received
+        let mut buffer = vec![0; 64];
+        let bytes_written =
+            receiver.write_packet(&info, &mut TestPacket { value: 0 }, &mut buffer, 64);
+        let packet = sender
+            .read_packet(&info, &buffer[..bytes_written as usize])
+            .unwrap();
+        assert_eq!(packet.get_packet_type(), 0);
+
+        let acks = sender.receive_acks();
+        let expected: Vec<u16> = (67..=98)
+            .rev()
+            .filter(|sequence| sequence % 3 != 0)
+            .collect();
+        assert_eq!(acks, expected);
+        assert!(sender.is_acked(98));
+        assert!(!sender.is_acked(99));
+        assert!(!sender.is_acked(96));
+        assert!(sender.sent_packet_bytes(98).unwrap() > 0);
+        assert_eq!(sender.sent_packet_bytes(1000), None);
+        assert!(sender.receive_acks().is_empty());
+
+        // Acks are only reported once, even when the same header arrives again
+        assert!(sender.process_header(&ReliablePacketHeader {
+            sequence: 1,
+            ack: 98,
+            ack_bits: 1,
+        }));
+        assert!(sender.receive_acks().is_empty());
+    }
+
+    #[test]
+    fn test_reliable_endpoint_wrap_around() {
+        let config = ProtocolConfig::default();
+        let mut sender = ReliableEndpoint::new(&config);
+        let mut receiver = ReliableEndpoint::new(&config);
+
+        for _ in 0..70000 {
+            let header = sender.generate_header(0);
+            assert!(receiver.process_header(&header));
+            let reply = receiver.generate_header(0);
+            assert!(sender.process_header(&reply));
+            assert_eq!(sender.receive_acks(), vec![header.sequence]);
+        }
+        assert_eq!(sender.next_sequence(), (70000 % 65536) as u16);
+
+        // Too old to fit in the received packets buffer
+        let stale = ReliablePacketHeader {
+            sequence: sender.next_sequence().wrapping_sub(1000),
+            ack: 0,
+            ack_bits: 0,
+        };
+        assert!(!receiver.process_header(&stale));
+        assert_eq!(receiver.num_packets_stale, 1);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+
+/*
+    Sequence numbers wrap around (65535 + 1 == 0 for a 16 bit sequence), so they can't be compared with < and >.
+    Instead a sequence is treated as more recent if it is ahead by less than half the range.
+    ex. 1 is greater than 65535, because the sequence wrapped from 65535 to 0 to 1.
+
+    PartialOrd is deliberately not implemented: wrapped comparisons are not transitive.
+*/
+
+macro_rules! sequence_type {
+    ($name:ident, $unsigned:ty, $signed:ty, $difference:ty) => {
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct $name(pub $unsigned);
+
+        impl $name {
+            const HALF_RANGE: $unsigned = 1 << (<$unsigned>::BITS - 1);
+
+            /** True if this sequence is more recent than other, allowing for wrap around */
+            pub fn sequence_greater_than(self, other: $name) -> bool {
+                ((self.0 > other.0) && (self.0 - other.0 <= Self::HALF_RANGE))
+                    || ((self.0 < other.0) && (other.0 - self.0 > Self::HALF_RANGE))
+            }
+
+            /** True if this sequence is older than other, allowing for wrap around */
+            pub fn sequence_less_than(self, other: $name) -> bool {
+                other.sequence_greater_than(self)
+            }
+
+            /**
+                How far ahead of other this sequence is. Negative if it is behind.
+                ex. Sequence16(2).difference(Sequence16(65534)) == 4
+            */
+            pub fn difference(self, other: $name) -> $difference {
+                self.0.wrapping_sub(other.0) as $signed as $difference
+            }
+
+            pub fn wrapping_add(self, value: $unsigned) -> $name {
+                $name(self.0.wrapping_add(value))
+            }
+
+            pub fn wrapping_sub(self, value: $unsigned) -> $name {
+                $name(self.0.wrapping_sub(value))
+            }
+
+            /** The sequence after this one, wrapping back to 0 after the maximum */
+            pub fn next(self) -> $name {
+                self.wrapping_add(1)
+            }
+
+            /**
+                Iterates forward from start up to (but not including) end, wrapping around if needed.
+                ex. Sequence16::range(Sequence16(65534), Sequence16(1)) yields 65534, 65535, 0
+            */
+            pub fn range(start: $name, end: $name) -> impl Iterator<Item = $name> {
+                let count = end.0.wrapping_sub(start.0);
+                (0..count).map(move |offset| start.wrapping_add(offset))
+            }
+        }
+
+        impl From<$unsigned> for $name {
+            fn from(value: $unsigned) -> $name {
+                $name(value)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+sequence_type!(Sequence16, u16, i16, i32);
+sequence_type!(Sequence32, u32, i32, i64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_compare() {
+        assert!(Sequence16(1).sequence_greater_than(Sequence16(0)));
+        assert!(Sequence16(0).sequence_less_than(Sequence16(1)));
+        assert!(!Sequence16(5).sequence_greater_than(Sequence16(5)));
+        assert!(!Sequence16(5).sequence_less_than(Sequence16(5)));
+
+        // Wrapped around
+        assert!(Sequence16(0).sequence_greater_than(Sequence16(65535)));
+        assert!(Sequence16(10).sequence_greater_than(Sequence16(65000)));
+        assert!(Sequence16(65535).sequence_less_than(Sequence16(0)));
+
+        // More than half the range ahead is treated as behind
+        assert!(Sequence16(40000).sequence_less_than(Sequence16(0)));
+        assert!(Sequence16(32768).sequence_greater_than(Sequence16(0)));
+
+        assert!(Sequence32(0).sequence_greater_than(Sequence32(u32::MAX)));
+        assert!(Sequence32(u32::MAX / 2).sequence_greater_than(Sequence32(0)));
+        assert!(Sequence32(u32::MAX / 2 + 2).sequence_less_than(Sequence32(0)));
+    }
+
+    #[test]
+    fn test_sequence_difference() {
+        assert_eq!(Sequence16(10).difference(Sequence16(4)), 6);
+        assert_eq!(Sequence16(4).difference(Sequence16(10)), -6);
+        assert_eq!(Sequence16(2).difference(Sequence16(65534)), 4);
+        assert_eq!(Sequence16(65534).difference(Sequence16(2)), -4);
+        assert_eq!(Sequence16(32768).difference(Sequence16(0)), -32768);
+        assert_eq!(Sequence32(3).difference(Sequence32(u32::MAX)), 4);
+        assert_eq!(Sequence32(u32::MAX).difference(Sequence32(3)), -4);
+    }
+
+    #[test]
+    fn test_sequence_range() {
+        let wrapped: Vec<u16> = Sequence16::range(Sequence16(65534), Sequence16(2))
+            .map(|sequence| sequence.0)
+            .collect();
+        assert_eq!(wrapped, vec![65534, 65535, 0, 1]);
+
+        assert_eq!(Sequence16::range(Sequence16(7), Sequence16(7)).count(), 0);
+        assert_eq!(Sequence16(65535).next(), Sequence16(0));
+        assert_eq!(Sequence32(0).wrapping_sub(1), Sequence32(u32::MAX));
+    }
+}
@@ -0,0 +1,213 @@
+use super::sequence::Sequence16;
+
+/**
+    Fixed size buffer of entries keyed by a wrapping 16 bit sequence number.
+
+    Entries live at index sequence % size, and only the most recent size sequences can be stored:
+    [ current_sequence - size + 1, current_sequence ] (modulo 65536).
+    Moving the current sequence forward clears every entry that falls out of that range,
+    so a stale entry can never be mistaken for a newer sequence that maps to the same index.
+*/
+pub struct SequenceBuffer<T> {
+    current_sequence: Sequence16, // most recent sequence inserted or advanced to
+    entries: Vec<Option<(u16, T)>>, // sequence and value stored at index sequence % size
+}
+
+impl<T> SequenceBuffer<T> {
+    /** Size must be a power of two, so indices stay consistent when the sequence wraps around */
+    pub fn new(size: usize) -> SequenceBuffer<T> {
+        assert!(size.is_power_of_two());
+        assert!(size <= 32768);
+
+        SequenceBuffer {
+            current_sequence: Sequence16(0),
+            entries: (0..size).map(|_| None).collect(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn current_sequence(&self) -> u16 {
+        self.current_sequence.0
+    }
+
+    /** Oldest sequence that can still be stored */
+    pub fn oldest_sequence(&self) -> u16 {
+        self.current_sequence.wrapping_sub(self.size() as u16 - 1).0
+    }
+
+    fn index(&self, sequence: u16) -> usize {
+        sequence as usize % self.size()
+    }
+
+    /**
+        Stores value for sequence, replacing any entry already at that sequence.
+        Advances the buffer if the sequence is newer than the current sequence.
+        Returns None (and drops value) if the sequence is too old to fit in the buffer.
+    */
+    pub fn insert(&mut self, sequence: u16, value: T) -> Option<&mut T> {
+        if Sequence16(sequence).sequence_less_than(Sequence16(self.oldest_sequence())) {
+            return None;
+        }
+
+        self.advance(sequence);
+
+        let index = self.index(sequence);
+        self.entries[index] = Some((sequence, value));
+        self.entries[index].as_mut().map(|(_, value)| value)
+    }
+
+    pub fn exists(&self, sequence: u16) -> bool {
+        self.find(sequence).is_some()
+    }
+
+    pub fn find(&self, sequence: u16) -> Option<&T> {
+        match &self.entries[self.index(sequence)] {
+            Some((entry_sequence, value)) if *entry_sequence == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn find_mut(&mut self, sequence: u16) -> Option<&mut T> {
+        let index = self.index(sequence);
+        match &mut self.entries[index] {
+            Some((entry_sequence, value)) if *entry_sequence == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    /** Removes and returns the entry for sequence, if there is one */
+    pub fn remove(&mut self, sequence: u16) -> Option<T> {
+        if !self.exists(sequence) {
+            return None;
+        }
+        let index = self.index(sequence);
+        self.entries[index].take().map(|(_, value)| value)
+    }
+
+    /**
+        Moves the current sequence forward to sequence, if it is newer.
+        Entries that fall out of the buffer's range are removed and returned, oldest first.
+    */
+    pub fn advance(&mut self, sequence: u16) -> Vec<T> {
+        let sequence = Sequence16(sequence);
+        if !sequence.sequence_greater_than(self.current_sequence) {
+            return vec![];
+        }
+
+        // clear the indices the new sequences map to, which hold everything older than the new range
+        let difference = sequence.difference(self.current_sequence) as usize;
+        let start = if difference >= self.size() {
+            sequence.wrapping_sub(self.size() as u16 - 1)
+        } else {
+            self.current_sequence.next()
+        };
+
+        let mut removed: Vec<T> = vec![];
+        for clear_sequence in Sequence16::range(start, sequence.next()) {
+            let index = self.index(clear_sequence.0);
+            if let Some((_, value)) = self.entries[index].take() {
+                removed.push(value);
+            }
+        }
+
+        self.current_sequence = sequence;
+        removed
+    }
+
+    /** Sequences in the buffer's range that have an entry, oldest first */
+    pub fn sequences(&self) -> impl Iterator<Item = u16> + '_ {
+        Sequence16::range(
+            Sequence16(self.oldest_sequence()),
+            self.current_sequence.next(),
+        )
+        .map(|sequence| sequence.0)
+        .filter(move |sequence| self.exists(*sequence))
+    }
+
+    /** Removes every entry and moves the current sequence back to 0 */
+    pub fn reset(&mut self) {
+        self.reset_to(0);
+    }
+
+    /** Removes every entry and starts the buffer at sequence, which may be older than the current sequence */
+    pub fn reset_to(&mut self, sequence: u16) {
+        self.current_sequence = Sequence16(sequence);
+        for entry in self.entries.iter_mut() {
+            *entry = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_buffer() {
+        let mut buffer: SequenceBuffer<u32> = SequenceBuffer::new(16);
+
+        assert_eq!(buffer.size(), 16);
+        assert!(!buffer.exists(0));
+
+        for sequence in 0..16 {
+            assert!(buffer.insert(sequence, sequence as u32 * 10).is_some());
+        }
+        assert_eq!(buffer.current_sequence(), 15);
+        assert_eq!(buffer.find(3), Some(&30));
+        *buffer.find_mut(3).unwrap() += 1;
+        assert_eq!(buffer.remove(3), Some(31));
+        assert!(!buffer.exists(3));
+        assert_eq!(buffer.remove(3), None);
+
+        // Advancing clears everything that falls out of range, and hands it back oldest first
+        assert_eq!(buffer.advance(17), vec![0, 10]);
+        assert!(!buffer.exists(0));
+        assert!(!buffer.exists(1));
+        assert!(buffer.exists(2));
+        assert!(!buffer.exists(16));
+
+        // Too old to be stored, or already the current sequence
+        assert!(buffer.insert(1, 1).is_none());
+        assert!(buffer.advance(17).is_empty());
+        assert!(buffer.advance(5).is_empty());
+        assert_eq!(buffer.current_sequence(), 17);
+
+        assert_eq!(
+            buffer.sequences().collect::<Vec<u16>>(),
+            vec![2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+
+        // Jumping further than the buffer size clears everything
+        assert_eq!(buffer.advance(1000).len(), 13);
+        assert_eq!(buffer.sequences().count(), 0);
+
+        buffer.reset();
+        assert_eq!(buffer.current_sequence(), 0);
+    }
+
+    #[test]
+    fn test_sequence_buffer_wrap_around() {
+        let mut buffer: SequenceBuffer<u16> = SequenceBuffer::new(8);
+
+        for sequence in 65530..=65535 {
+            buffer.insert(sequence, sequence);
+        }
+        buffer.insert(0, 0);
+        buffer.insert(1, 1);
+
+        assert_eq!(buffer.current_sequence(), 1);
+        assert_eq!(
+            buffer.sequences().collect::<Vec<u16>>(),
+            vec![65530, 65531, 65532, 65533, 65534, 65535, 0, 1]
+        );
+
+        // 65530 and 65531 share indices with 2 and 3
+        assert_eq!(buffer.advance(3), vec![65530, 65531]);
+        assert!(buffer.insert(2, 2).is_some());
+        assert_eq!(buffer.find(2), Some(&2));
+        assert!(buffer.insert(65531, 65531).is_none());
+    }
+}
@@ -1,35 +1,45 @@
 use super::{packets::object::Object, streams::Stream};
-use crate::bits_required;
+use crate::{
+    bits_required, serialize_bits, serialize_bits_u64, serialize_bool, serialize_compressed_float,
+    serialize_float, serialize_int,
+};
 use num_traits::clamp;
 use vector3d::Vector3d;
 
+/*
+    These functions do the work behind the serialize_* macros in macros.rs.
+    Each returns false as soon as the stream fails or a value is out of range,
+    and the macros turn that into an early return from the enclosing serialize function.
+*/
+
 pub const MAX_OBJECTS: u32 = 1024;
 
 /** Serialize a known 32 bit value */
-pub fn serialize_check(stream: &mut dyn Stream, string: &mut String) -> bool {
-    return stream.serialize_check(string);
+pub fn serialize_check_internal(stream: &mut dyn Stream, string: &mut String) -> bool {
+    stream.serialize_check(string)
 }
 
-pub fn serialize_object(stream: &mut dyn Stream, object: &mut dyn Object) -> bool {
-    // return object.serialize(stream);
-    true
+pub fn serialize_object_internal<S: Stream, O: Object>(stream: &mut S, object: &mut O) -> bool {
+    object.serialize(stream)
 }
 
 pub fn serialize_float_internal(stream: &mut dyn Stream, value: &mut f32) -> bool {
     // Convert float to an integer representation
     let mut as_int = value.to_bits();
-    let result = stream.serialize_bits(&mut as_int, 32);
+    if !stream.serialize_bits(&mut as_int, 32) {
+        return false;
+    }
 
     if stream.is_reading() {
         // Convert integer representation to a float
         *value = f32::from_bits(as_int);
     }
 
-    return result;
+    true
 }
 
-pub fn serialize_compressed_float_internal<T: Stream>(
-    stream: &mut T,
+pub fn serialize_compressed_float_internal(
+    stream: &mut dyn Stream,
     value: &mut f32,
     min: f32,
     max: f32,
@@ -58,55 +68,57 @@ pub fn serialize_compressed_float_internal<T: Stream>(
         integer_value = f32::floor(normalised_value * max_integer_value as f32 + 0.05) as u32;
     }
 
-    if !stream.serialize_bits(&mut integer_value, bits) {
-        false;
-    }
+    serialize_bits!(stream, integer_value, bits);
 
     if stream.is_reading() {
+        // The bits can hold more than max_integer_value, anything above it was never written by us
+        if integer_value > max_integer_value {
+            return false;
+        }
         let normalised_value = integer_value as f32 / max_integer_value as f32;
         *value = normalised_value * delta + min;
     }
 
-    return true;
+    true
 }
 
-pub fn serialize_vector_internal<T: Stream>(stream: &mut T, vector: &mut Vector3d<f32>) -> bool {
-    let mut values = vec![0.; 3];
+pub fn serialize_vector_internal(stream: &mut dyn Stream, vector: &mut Vector3d<f32>) -> bool {
+    let mut values = [0.; 3];
 
     if stream.is_writing() {
         values[0] = vector.x;
         values[1] = vector.y;
         values[2] = vector.z;
     }
-    serialize_float_macro(stream, &mut values[0]);
-    serialize_float_macro(stream, &mut values[1]);
-    serialize_float_macro(stream, &mut values[2]);
+    serialize_float!(stream, values[0]);
+    serialize_float!(stream, values[1]);
+    serialize_float!(stream, values[2]);
     if stream.is_reading() {
         vector.x = values[0];
         vector.y = values[1];
         vector.z = values[2];
     }
 
-    return true;
+    true
 }
 
-pub fn serialize_compressed_vector_internal<T: Stream>(
-    stream: &mut T,
+pub fn serialize_compressed_vector_internal(
+    stream: &mut dyn Stream,
     vector: &mut Vector3d<f32>,
     min: f32,
     max: f32,
     precision: f32,
 ) -> bool {
-    let mut values = vec![0.; 3];
+    let mut values = [0.; 3];
 
     if stream.is_writing() {
         values[0] = vector.x;
         values[1] = vector.y;
         values[2] = vector.z;
     }
-    serialize_compressed_float_macro(stream, &mut values[0], min, max, precision);
-    serialize_compressed_float_macro(stream, &mut values[1], min, max, precision);
-    serialize_compressed_float_macro(stream, &mut values[2], min, max, precision);
+    serialize_compressed_float!(stream, values[0], min, max, precision);
+    serialize_compressed_float!(stream, values[1], min, max, precision);
+    serialize_compressed_float!(stream, values[2], min, max, precision);
 
     if stream.is_reading() {
         vector.x = values[0];
@@ -114,7 +126,7 @@ pub fn serialize_compressed_vector_internal<T: Stream>(
         vector.z = values[2];
     }
 
-    return true;
+    true
 }
 
 pub fn serialize_bytes_internal(
@@ -122,115 +134,77 @@ pub fn serialize_bytes_internal(
     bytes: &mut Vec<u8>,
     num_bytes: u32,
 ) -> bool {
-    return stream.serialize_bytes(bytes, num_bytes);
+    stream.serialize_bytes(bytes, num_bytes)
 }
 
-pub fn serialize_string_internal<T: Stream>(
-    stream: &mut T,
+pub fn serialize_string_internal(
+    stream: &mut dyn Stream,
     string: &mut String,
     buffer_size: u32,
 ) -> bool {
+    if buffer_size < 2 {
+        return false;
+    }
+
     let mut length: i32 = 0;
     if stream.is_writing() {
+        if string.len() as u32 >= buffer_size - 1 {
+            return false;
+        }
         length = string.len() as i32;
-        assert!((length as u32) < buffer_size - 1);
     }
 
-    serialize_int_macro(stream, &mut length, 0, (buffer_size as i32) - 1);
+    serialize_int!(stream, length, 0, (buffer_size as i32) - 1);
+
+    // The length bits can hold one more than the writer allows, reject it like the writer would
+    if length as u32 >= buffer_size - 1 {
+        return false;
+    }
 
-    // Create a temp vector to hold bytes
+    // Create a temp vector to hold bytes. Only the writer has a string to copy from.
     let mut bytes: Vec<u8> = vec![0; length as usize];
-    let string_bytes = string.as_bytes();
-    for i in 0..bytes.len() {
-        bytes[i] = string_bytes[i];
+    if stream.is_writing() {
+        bytes.copy_from_slice(string.as_bytes());
     }
 
-    serialize_bytes_macro(stream, &mut bytes, length as u32);
+    if length > 0 && !serialize_bytes_internal(stream, &mut bytes, length as u32) {
+        return false;
+    }
 
     if stream.is_reading() {
-        *string = String::from_utf8(bytes).unwrap();
+        match String::from_utf8(bytes) {
+            Ok(read_string) => *string = read_string,
+            Err(_) => return false,
+        }
     }
 
     true
 }
 
-pub fn serialize_int_macro(stream: &mut dyn Stream, value: &mut i32, min: i32, max: i32) -> bool {
-    assert!(min < max);
+pub fn serialize_int_internal(
+    stream: &mut dyn Stream,
+    value: &mut i32,
+    min: i32,
+    max: i32,
+) -> bool {
+    // The stream checks the range, and reports ValueOutOfRange instead of writing or reading a bad value
     let mut val: i32 = 0;
-
     if stream.is_writing() {
-        assert!(
-            *value >= min,
-            "Value ({}) is < minimum value ({})",
-            *value,
-            min
-        );
-        assert!(*value <= max);
         val = *value;
     }
 
     if !stream.serialise_int(&mut val, min, max) {
-        false;
+        return false;
     }
 
     if stream.is_reading() {
         *value = val;
-        if val < min || val > max {
-            return false;
-        }
     }
 
-    return true;
-}
-
-// TODO: Turn into a macro
-pub fn serialize_float_macro(stream: &mut dyn Stream, value: &mut f32) -> bool {
-    if !serialize_float_internal(stream, value) {
-        return false;
-    }
     true
 }
 
-// TODO: Turn into a macro
-pub fn serialize_compressed_float_macro<T: Stream>(
-    stream: &mut T,
-    value: &mut f32,
-    min: f32,
-    max: f32,
-    precision: f32,
-) -> bool {
-    if !serialize_compressed_float_internal(stream, value, min, max, precision) {
-        return false;
-    }
-    true
-}
-
-pub fn serialize_vector_macro<T: Stream>(stream: &mut T, vector: &mut Vector3d<f32>) -> bool {
-    if !serialize_vector_internal(stream, vector) {
-        false;
-    }
-    true
-}
-
-pub fn serialize_string_macro<T: Stream>(
-    stream: &mut T,
-    string: &mut String,
-    buffer_size: u32,
-) -> bool {
-    return serialize_string_internal(stream, string, buffer_size);
-}
-
-pub fn serialize_bytes_macro<T: Stream>(
-    stream: &mut T,
-    bytes: &mut Vec<u8>,
-    num_bytes: u32,
-) -> bool {
-    return serialize_bytes_internal(stream, bytes, num_bytes);
-}
-
-pub fn serialize_bits_macro(stream: &mut dyn Stream, value: &mut u32, bits: u32) -> bool {
-    assert!(bits > 0);
-    assert!(bits <= 32);
+pub fn serialize_bits_internal(stream: &mut dyn Stream, value: &mut u32, bits: u32) -> bool {
     let mut u32_val: u32 = 0;
     if stream.is_writing() {
         u32_val = *value;
@@ -244,22 +218,14 @@ pub fn serialize_bits_macro(stream: &mut dyn Stream, value: &mut u32, bits: u32)
     true
 }
 
-pub fn serialize_bool_macro(stream: &mut dyn Stream, value: &mut bool) -> bool {
+pub fn serialize_bool_internal(stream: &mut dyn Stream, value: &mut bool) -> bool {
     let mut uint32_bool_value = 0;
     if stream.is_writing() {
-        if *value == true {
-            uint32_bool_value = 1;
-        } else {
-            uint32_bool_value = 0;
-        }
+        uint32_bool_value = *value as u32;
     }
-    serialize_bits_macro(stream, &mut uint32_bool_value, 1);
+    serialize_bits!(stream, uint32_bool_value, 1);
     if stream.is_reading() {
-        if uint32_bool_value == 1 {
-            *value = true;
-        } else {
-            *value = false;
-        }
+        *value = uint32_bool_value == 1;
     }
     true
 }
@@ -283,9 +249,10 @@ pub fn serialize_object_index_internal(
     */
 
     if stream.is_writing() {
-        assert!(*previous < *current);
-        difference = (*current - *previous) as i32;
-        assert!(difference > 0);
+        if *previous >= *current {
+            return false;
+        }
+        difference = *current - *previous;
     }
 
     // +1 (1 bit)
@@ -293,7 +260,7 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         plus_one = difference == 1;
     }
-    serialize_bool_macro(stream, &mut plus_one);
+    serialize_bool!(stream, plus_one);
     if plus_one {
         if stream.is_reading() {
             *current = *previous + 1;
@@ -307,9 +274,9 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         two_bits = difference <= 5;
     }
-    serialize_bool_macro(stream, &mut two_bits);
+    serialize_bool!(stream, two_bits);
     if two_bits {
-        serialize_int_macro(stream, &mut difference, 2, 5);
+        serialize_int!(stream, difference, 2, 5);
         if stream.is_reading() {
             *current = *previous + difference;
         }
@@ -322,9 +289,9 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         three_bits = difference <= 13;
     }
-    serialize_bool_macro(stream, &mut three_bits);
+    serialize_bool!(stream, three_bits);
     if three_bits {
-        serialize_int_macro(stream, &mut difference, 6, 13);
+        serialize_int!(stream, difference, 6, 13);
         if stream.is_reading() {
             *current = *previous + difference;
         }
@@ -337,9 +304,9 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         four_bits = difference <= 29;
     }
-    serialize_bool_macro(stream, &mut four_bits);
+    serialize_bool!(stream, four_bits);
     if four_bits {
-        serialize_int_macro(stream, &mut difference, 14, 29);
+        serialize_int!(stream, difference, 14, 29);
         if stream.is_reading() {
             *current = *previous + difference;
         }
@@ -352,9 +319,9 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         five_bits = difference <= 61;
     }
-    serialize_bool_macro(stream, &mut five_bits);
+    serialize_bool!(stream, five_bits);
     if five_bits {
-        serialize_int_macro(stream, &mut difference, 30, 61);
+        serialize_int!(stream, difference, 30, 61);
         if stream.is_reading() {
             *current = *previous + difference;
         }
@@ -367,9 +334,9 @@ pub fn serialize_object_index_internal(
     if stream.is_writing() {
         six_bits = difference <= 125;
     }
-    serialize_bool_macro(stream, &mut six_bits);
+    serialize_bool!(stream, six_bits);
     if six_bits {
-        serialize_int_macro(stream, &mut difference, 62, 125);
+        serialize_int!(stream, difference, 62, 125);
         if stream.is_reading() {
             *current = *previous + difference;
         }
@@ -377,7 +344,7 @@ pub fn serialize_object_index_internal(
         return true;
     }
 
-    serialize_int_macro(stream, &mut difference, 126, (MAX_OBJECTS + 1) as i32);
+    serialize_int!(stream, difference, 126, (MAX_OBJECTS + 1) as i32);
 
     if stream.is_reading() {
         *current = *previous + difference;
@@ -387,59 +354,57 @@ pub fn serialize_object_index_internal(
     }
     *previous = *current;
 
-    return true;
-}
-
-// TODO: Turn into a macro
-pub fn read_object_index_macro(
-    stream: &mut dyn Stream,
-    previous: &mut i32,
-    current: &mut i32,
-) -> bool {
-    return serialize_object_index_internal(stream, previous, current);
-}
-
-// TODO: Turn into a macro
-pub fn write_object_index_macro(stream: &mut dyn Stream, previous: &mut i32, current: i32) -> bool {
-    let mut temp_current: i32 = current;
-    return serialize_object_index_internal(stream, previous, &mut temp_current);
+    true
 }
 
-pub fn serialize_u64_macro(stream: &mut dyn Stream, value: &mut u64) -> bool {
-    // Since we write a word at a time, u64's need to be split in half.
-    let mut hi: u32 = 0;
-    let mut lo: u32 = 0;
-
+pub fn serialize_bits_u64_internal(stream: &mut dyn Stream, value: &mut u64, bits: u32) -> bool {
+    let mut u64_val: u64 = 0;
     if stream.is_writing() {
-        lo = (*value & 0xFFFFFFFF) as u32; // Mask of 32 msbs
-        hi = (*value >> 32) as u32; // Shift 32 msbs to right
+        u64_val = *value;
+    }
+    if !stream.serialize_bits_u64(&mut u64_val, bits) {
+        return false;
+    }
+    if stream.is_reading() {
+        *value = u64_val;
     }
+    true
+}
 
-    serialize_bits_macro(stream, &mut lo, 32);
-    serialize_bits_macro(stream, &mut hi, 32);
+/** Serializes a full 128 bit value (ids, hashes) as two 64 bit halves, low half first */
+pub fn serialize_u128_internal(stream: &mut dyn Stream, value: &mut u128) -> bool {
+    let mut lo: u64 = *value as u64;
+    let mut hi: u64 = (*value >> 64) as u64;
 
-    // println!("HI: {:#034b}", hi);
-    // println!("LO: {:#034b}", lo);
+    serialize_bits_u64!(stream, lo, 64);
+    serialize_bits_u64!(stream, hi, 64);
 
     if stream.is_reading() {
-        *value = ((hi as u64) << 32) | lo as u64;
+        *value = ((hi as u128) << 64) | lo as u128;
     }
     true
 }
 
-/** TODO: ADD COMMENT */
-pub fn serialize_align_macro(stream: &mut dyn Stream) -> bool {
-    return stream.serialize_align();
+/** Pads the stream with zero bits up to the next byte boundary */
+pub fn serialize_align_internal(stream: &mut dyn Stream) -> bool {
+    stream.serialize_align()
 }
 
+#[cfg(test)]
 mod tests {
+    use bitpacker_derive::Serialize;
     use rand::random;
 
     use super::*;
     use crate::{
-        impl_object_for_packet,
         protocol::packets::object::Object,
-        protocol::streams::{read_stream::ReadStream, write_stream::WriteStream},
+        protocol::streams::{
+            get_context, measure_stream::MeasureStream, read_stream::ReadStream,
+            write_stream::WriteStream,
+        },
+        serialize_align, serialize_bits, serialize_bits_u64, serialize_bool, serialize_bytes,
+        serialize_check, serialize_float, serialize_int, serialize_string, serialize_u128,
+        serialize_u64,
     };
 
     #[derive(PartialEq, Debug)]
@@ -462,6 +427,8 @@ mod tests {
 
         test_float: f32,
         test_u64: u64,
+        test_u40: u64,
+        test_u128: u128,
         test_string: String,
     }
 
@@ -483,6 +450,8 @@ mod tests {
                 test_bool: false,
                 test_float: 0.0,
                 test_u64: 0,
+                test_u40: 0,
+                test_u128: 0,
                 test_string: String::from_utf8(vec![0; 500]).unwrap(),
             };
         }
@@ -511,6 +480,8 @@ mod tests {
 
             self.data.test_float = 3.1315926;
             self.data.test_u64 = u64::MAX;
+            self.data.test_u40 = 0xAB_CDEF_0123;
+            self.data.test_u128 = u128::MAX - 12345;
 
             self.data.num_items = self.data.max_items / 2;
             for i in 0..self.data.num_items {
@@ -523,72 +494,399 @@ mod tests {
 
             self.data.test_string = String::from("Hello world!");
         }
+    }
 
-        pub fn serialize<T: Stream>(&mut self, stream: &mut T) -> bool {
-            serialize_int_macro(
+    impl Object for TestObject {
+        fn serialize<T: Stream>(&mut self, stream: &mut T) -> bool {
+            serialize_int!(
                 stream,
-                &mut self.data.test_int_a,
+                self.data.test_int_a,
                 self.data.min_int,
-                self.data.max_int,
+                self.data.max_int
             );
-            serialize_int_macro(
+            serialize_int!(
                 stream,
-                &mut self.data.test_int_b,
+                self.data.test_int_b,
                 self.data.min_int,
-                self.data.max_int,
+                self.data.max_int
             );
-            serialize_int_macro(stream, &mut self.data.test_int_c, -100, 10000);
-            serialize_bits_macro(stream, &mut self.data.test_int_d, 6);
-            serialize_bits_macro(stream, &mut self.data.test_int_e, 8);
-            serialize_bits_macro(stream, &mut self.data.test_int_f, 7);
+            serialize_int!(stream, self.data.test_int_c, -100, 10000);
+            serialize_bits!(stream, self.data.test_int_d, 6);
+            serialize_bits!(stream, self.data.test_int_e, 8);
+            serialize_bits!(stream, self.data.test_int_f, 7);
 
-            serialize_align_macro(stream);
+            serialize_align!(stream);
 
-            serialize_bool_macro(stream, &mut self.data.test_bool);
-            serialize_float_macro(stream, &mut self.data.test_float);
+            serialize_bool!(stream, self.data.test_bool);
+            serialize_float!(stream, self.data.test_float);
 
-            serialize_check(stream, &mut String::from("test object serialize check"));
+            serialize_check!(stream, "test object serialize check");
 
-            serialize_string_macro(stream, &mut self.data.test_string, 100);
-            serialize_u64_macro(stream, &mut self.data.test_u64);
+            serialize_string!(stream, self.data.test_string, 100);
+            serialize_u64!(stream, self.data.test_u64);
+            serialize_bits_u64!(stream, self.data.test_u40, 40);
+            serialize_u128!(stream, self.data.test_u128);
 
-            serialize_int_macro(
-                stream,
-                &mut self.data.num_items,
-                0,
-                (self.data.max_items as i32) - 1,
-            );
+            serialize_int!(stream, self.data.num_items, 0, self.data.max_items - 1);
             for i in 0..self.data.num_items {
-                serialize_bits_macro(stream, &mut self.data.items[i as usize], 8);
+                serialize_bits!(stream, self.data.items[i as usize], 8);
             }
 
             let num_bytes = self.data.bytes.len() as u32;
-            serialize_bytes_macro(stream, &mut self.data.bytes, num_bytes);
+            serialize_bytes!(stream, self.data.bytes, num_bytes);
 
-            serialize_check(stream, &mut String::from("end of test object"));
+            serialize_check!(stream, "end of test object");
             return true;
         }
     }
 
-    impl_object_for_packet!(TestObject);
-
     #[test]
     fn test_serialization() {
         let mut write_obj = TestObject::new();
         write_obj.init();
         let mut buffer = vec![0; 1024];
-        let buffer_size = buffer.len();
 
         {
-            let mut write_stream = WriteStream::new(&mut buffer, buffer_size);
+            let mut write_stream = WriteStream::new(&mut buffer);
             write_obj.serialize(&mut write_stream);
-            write_stream.writer.flush();
+            write_stream.flush();
         }
 
-        let mut read_stream = ReadStream::new(&mut buffer, buffer_size);
+        let mut read_stream = ReadStream::new(&buffer);
         let mut read_object = TestObject::new();
         read_object.serialize(&mut read_stream);
 
         assert!(read_object.data == write_obj.data);
     }
+
+    #[test]
+    fn test_measure() {
+        let mut write_obj = TestObject::new();
+        write_obj.init();
+        let mut buffer = vec![0; 1024];
+
+        let mut measure_stream = MeasureStream::new(buffer.len());
+        write_obj.serialize(&mut measure_stream);
+
+        let mut write_stream = WriteStream::new(&mut buffer);
+        write_obj.serialize(&mut write_stream);
+        write_stream.flush();
+
+        assert_eq!(
+            measure_stream.get_bits_processed(),
+            write_stream.get_bits_processed()
+        );
+        assert_eq!(
+            measure_stream.get_bytes_processed(),
+            write_stream.get_bytes_processed()
+        );
+        assert!(measure_stream.get_worst_case_bits() >= measure_stream.get_bits_processed());
+        assert_eq!(
+            measure_stream.get_bits_remaining(),
+            1024 * 8 - measure_stream.get_bits_processed()
+        );
+    }
+
+    struct RangedValues {
+        first: i32,
+        second: i32,
+        reached_end: bool,
+    }
+
+    impl Object for RangedValues {
+        fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+            serialize_int!(stream, self.first, 0, 200);
+            serialize_int!(stream, self.second, 0, 200);
+            self.reached_end = true;
+            true
+        }
+    }
+
+    #[test]
+    fn test_early_return() {
+        let mut buffer = vec![0; 4];
+
+        {
+            // 8 bits can hold 255, which is outside [0, 200]
+            let mut write_stream = WriteStream::new(&mut buffer);
+            let mut value: u32 = 255;
+            assert!(serialize_bits_internal(&mut write_stream, &mut value, 8));
+            write_stream.flush();
+        }
+
+        let mut read_stream = ReadStream::new(&buffer);
+        let mut values = RangedValues {
+            first: 0,
+            second: 0,
+            reached_end: false,
+        };
+        assert!(!values.serialize(&mut read_stream));
+        assert!(!values.reached_end);
+        assert_eq!(values.first, 0);
+
+        // Writing an out of range value fails the same way instead of panicking
+        let mut write_stream = WriteStream::new(&mut buffer);
+        values.first = 201;
+        assert!(!values.serialize(&mut write_stream));
+        assert!(!values.reached_end);
+        assert_eq!(write_stream.get_bits_processed(), 0);
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        use crate::protocol::{
+            bitpacker::bit_error::BitErrorKind, protocol_error::ProtocolErrorKind,
+        };
+
+        let mut buffer = vec![0; 8];
+
+        // Out of range values and empty ranges are errors, not panics
+        for (value, min, max) in [(201, 0, 200), (-1, 0, 200), (0, 0, 0), (5, 10, 0)] {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            let mut value = value;
+            assert!(!write_stream.serialise_int(&mut value, min, max));
+            assert_eq!(write_stream.get_error(), ProtocolErrorKind::ValueOutOfRange);
+        }
+        for (value, min, max) in [(201, 0, 200), (-1, 0, 200), (0, 1, 1)] {
+            let mut measure_stream = MeasureStream::new(8);
+            let mut value = value;
+            assert!(!measure_stream.serialise_int(&mut value, min, max));
+            assert_eq!(
+                measure_stream.get_error(),
+                ProtocolErrorKind::ValueOutOfRange
+            );
+        }
+
+        // Measuring fails where writing would, without overflowing on huge byte counts
+        let mut measure_stream = MeasureStream::new(8);
+        assert!(!measure_stream.serialize_bytes(&mut vec![1, 2], 4));
+        assert_eq!(
+            measure_stream.get_error(),
+            ProtocolErrorKind::StreamOverflow
+        );
+        let mut measure_stream = MeasureStream::new(8);
+        assert!(!measure_stream.serialize_bytes(&mut vec![], u32::MAX));
+        assert_eq!(
+            measure_stream.get_error(),
+            ProtocolErrorKind::StreamOverflow
+        );
+
+        // 3 bits can hold 7, which is outside [0, 5]
+        {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            assert!(write_stream.serialize_bits(&mut 7, 3));
+            write_stream.flush();
+        }
+        let mut read_stream = ReadStream::new(&buffer);
+        let mut value = 0;
+        assert!(!read_stream.serialise_int(&mut value, 0, 5));
+        assert_eq!(read_stream.get_error(), ProtocolErrorKind::ValueOutOfRange);
+        assert_eq!(value, 0);
+        let mut read_stream = ReadStream::new(&buffer);
+        assert!(!read_stream.serialise_int(&mut value, 3, 3));
+        assert_eq!(read_stream.get_error(), ProtocolErrorKind::ValueOutOfRange);
+
+        // Zero bytes, and fewer bytes than asked to write
+        let mut write_stream = WriteStream::new(&mut buffer);
+        assert!(!write_stream.serialize_bytes(&mut vec![], 0));
+        assert_eq!(write_stream.get_error(), ProtocolErrorKind::StreamOverflow);
+        assert_eq!(
+            write_stream.get_bit_error().unwrap().kind,
+            BitErrorKind::InvalidBitCount
+        );
+        let mut write_stream = WriteStream::new(&mut buffer);
+        assert!(!write_stream.serialize_bytes(&mut vec![1, 2], 4));
+        assert_eq!(
+            write_stream.get_bit_error().unwrap().kind,
+            BitErrorKind::BufferTooSmall
+        );
+
+        // Reading makes room for the bytes, but only if they are there
+        let buffer = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut read_stream = ReadStream::new(&buffer);
+        let mut bytes = vec![];
+        assert!(read_stream.serialize_bytes(&mut bytes, 6));
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6]);
+        let mut read_stream = ReadStream::new(&buffer);
+        let mut bytes = vec![];
+        assert!(!read_stream.serialize_bytes(&mut bytes, u32::MAX));
+        assert!(bytes.is_empty());
+        assert_eq!(
+            read_stream.get_bit_error().unwrap().kind,
+            BitErrorKind::Overflow
+        );
+    }
+
+    struct WorldBounds {
+        min: i32,
+        max: i32,
+    }
+
+    struct BoundedPosition {
+        x: i32,
+    }
+
+    impl Object for BoundedPosition {
+        fn serialize<S: Stream>(&mut self, stream: &mut S) -> bool {
+            let bounds = match get_context::<WorldBounds>(stream) {
+                Some(bounds) => (bounds.min, bounds.max),
+                None => return false,
+            };
+            serialize_int!(stream, self.x, bounds.0, bounds.1);
+            true
+        }
+    }
+
+    #[test]
+    fn test_context() {
+        let bounds = WorldBounds {
+            min: -500,
+            max: 500,
+        };
+        let mut buffer = vec![0; 16];
+        let mut write_position = BoundedPosition { x: -321 };
+
+        {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            assert!(!write_position.serialize(&mut write_stream));
+
+            write_stream.set_context(&bounds);
+            assert!(write_position.serialize(&mut write_stream));
+            assert_eq!(write_stream.get_bits_processed(), 10);
+            write_stream.flush();
+        }
+
+        let mut read_stream = ReadStream::new(&buffer);
+        read_stream.set_context(&bounds);
+        let mut read_position = BoundedPosition { x: 0 };
+        assert!(read_position.serialize(&mut read_stream));
+        assert_eq!(read_position.x, -321);
+
+        // A context of the wrong type is not handed out
+        let mut measure_stream = MeasureStream::new(buffer.len());
+        measure_stream.set_context(&read_position.x);
+        assert!(get_context::<WorldBounds>(&measure_stream).is_none());
+        assert_eq!(get_context::<i32>(&measure_stream), Some(&-321));
+    }
+
+    #[derive(Serialize, Debug, PartialEq, Default)]
+    struct DerivedNested {
+        #[range(0, 10)]
+        level: i32,
+    }
+
+    #[derive(Serialize, Debug, PartialEq, Default)]
+    #[packet_type(7)]
+    struct DerivedPacket {
+        #[range(-10, 100)]
+        health: i32,
+        #[bits(6)]
+        ammo: u32,
+        #[range(0, 200)]
+        small: u8,
+        flag: bool,
+        speed: f32,
+        #[compressed(-100.0, 100.0, 0.01)]
+        angle: f32,
+        #[compressed(0.0, 1024.0, 0.1)]
+        position: Vector3d<f32>,
+        id: u64,
+        hash: u128,
+        #[check]
+        #[max_len(32)]
+        name: String,
+        #[max_len(16)]
+        payload: Vec<u8>,
+        #[max_len(8)]
+        #[bits(12)]
+        items: Vec<u32>,
+        nested: DerivedNested,
+    }
+
+    #[test]
+    fn test_derive() {
+        use crate::protocol::packets::object::Packet;
+
+        let mut write_packet = DerivedPacket {
+            health: -7,
+            ammo: 63,
+            small: 199,
+            flag: true,
+            speed: 12.5,
+            angle: -45.25,
+            position: Vector3d::new(1.0, 512.5, 1000.0),
+            id: u64::MAX - 3,
+            hash: u128::MAX / 3,
+            name: String::from("derived"),
+            payload: vec![1, 2, 3, 4, 5],
+            items: vec![4095, 0, 17],
+            nested: DerivedNested { level: 9 },
+        };
+        assert_eq!(write_packet.get_packet_type(), 7);
+
+        let mut buffer = vec![0; 256];
+        {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            assert!(write_packet.serialize(&mut write_stream));
+            write_stream.flush();
+        }
+
+        let mut read_stream = ReadStream::new(&buffer);
+        let mut read_packet = DerivedPacket::default();
+        assert!(read_packet.serialize(&mut read_stream));
+
+        // Compressed values only come back to within their precision
+        assert!((read_packet.angle - write_packet.angle).abs() <= 0.01);
+        assert!((read_packet.position.y - write_packet.position.y).abs() <= 0.1);
+        read_packet.angle = write_packet.angle;
+        read_packet.position = write_packet.position;
+        assert_eq!(read_packet, write_packet);
+
+        // A truncated packet stops decoding instead of producing garbage
+        let mut truncated_stream = ReadStream::new(&buffer[..20]);
+        assert!(!DerivedPacket::default().serialize(&mut truncated_stream));
+    }
+
+    #[derive(Serialize, Default)]
+    struct ShortName {
+        #[max_len(5)]
+        name: String,
+    }
+
+    #[derive(Serialize, Default)]
+    struct LongName {
+        #[max_len(6)]
+        name: String,
+    }
+
+    #[test]
+    fn test_derive_string_max_len() {
+        let mut buffer = vec![0; 16];
+
+        // max_len bytes round trip
+        {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            let mut short = ShortName {
+                name: String::from("abcde"),
+            };
+            assert!(short.serialize(&mut write_stream));
+            write_stream.flush();
+        }
+        let mut short = ShortName::default();
+        assert!(short.serialize(&mut ReadStream::new(&buffer)));
+        assert_eq!(short.name, "abcde");
+
+        // max_len + 1 is rejected when writing, and when reading something that wrote it
+        short.name.push('f');
+        assert!(!short.serialize(&mut WriteStream::new(&mut buffer)));
+        {
+            let mut write_stream = WriteStream::new(&mut buffer);
+            let mut long = LongName {
+                name: String::from("abcdef"),
+            };
+            assert!(long.serialize(&mut write_stream));
+            write_stream.flush();
+        }
+        assert!(!ShortName::default().serialize(&mut ReadStream::new(&buffer)));
+    }
 }
@@ -0,0 +1,139 @@
+use std::any::Any;
+
+use crate::{bits_required, protocol::protocol_error::ProtocolErrorKind};
+
+use super::Stream;
+
+/**
+    Walks the same serialize functions as a WriteStream but only counts bits.
+
+    Alignment is tracked two ways: the exact padding for the current position
+    (matches what a WriteStream would write), and a worst case of 7 bits per align
+    for callers that want a bound that doesn't depend on where the object starts.
+*/
+pub struct MeasureStream<'a> {
+    num_bits: u32,        // Bits in the buffer we are measuring against
+    bits_processed: u32,  // Bits that would be written
+    worst_case_bits: u32, // Bits that would be written if every align needed 7 bits of padding
+    error: ProtocolErrorKind,
+    context: Option<&'a dyn Any>,
+}
+
+impl<'a> MeasureStream<'a> {
+    pub fn new(buffer_size: usize) -> MeasureStream<'a> {
+        MeasureStream {
+            num_bits: (buffer_size as u32) * 8,
+            bits_processed: 0,
+            worst_case_bits: 0,
+            error: ProtocolErrorKind::None,
+            context: None,
+        }
+    }
+
+    /** Sets the context returned by get_context while serializing through this stream */
+    pub fn set_context(&mut self, context: &'a dyn Any) {
+        self.context = Some(context);
+    }
+
+    /** Bits that would be written if every align needed the maximum of 7 bits of padding */
+    pub fn get_worst_case_bits(&self) -> u32 {
+        self.worst_case_bits
+    }
+
+    pub fn get_worst_case_bytes(&self) -> u32 {
+        self.worst_case_bits.div_ceil(8)
+    }
+
+    fn add_bits(&mut self, bits: u32) {
+        self.bits_processed = self.bits_processed.saturating_add(bits);
+        self.worst_case_bits = self.worst_case_bits.saturating_add(bits);
+    }
+}
+
+impl<'a> Stream for MeasureStream<'a> {
+    fn get_error(&mut self) -> ProtocolErrorKind {
+        self.error
+    }
+
+    fn get_context(&self) -> Option<&dyn Any> {
+        self.context
+    }
+
+    fn is_reading(&self) -> bool {
+        false
+    }
+
+    fn is_writing(&self) -> bool {
+        true
+    }
+
+    /** Fails on the same values WriteStream does, so a packet that measures also writes */
+    fn serialise_int(&mut self, value: &mut i32, min: i32, max: i32) -> bool {
+        if min >= max || *value < min || *value > max {
+            self.error = ProtocolErrorKind::ValueOutOfRange;
+            return false;
+        }
+        self.add_bits(bits_required!(min, max));
+        true
+    }
+
+    fn serialize_bits(&mut self, _value: &mut u32, bits: u32) -> bool {
+        if bits == 0 || bits > 32 {
+            self.error = ProtocolErrorKind::StreamOverflow;
+            return false;
+        }
+        self.add_bits(bits);
+        true
+    }
+
+    fn serialize_bits_u64(&mut self, _value: &mut u64, bits: u32) -> bool {
+        if bits == 0 || bits > 64 {
+            self.error = ProtocolErrorKind::StreamOverflow;
+            return false;
+        }
+        self.add_bits(bits);
+        true
+    }
+
+    fn serialize_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool {
+        let Some(num_bits) = num_bytes.checked_mul(8) else {
+            self.error = ProtocolErrorKind::StreamOverflow;
+            return false;
+        };
+        if num_bytes == 0 || bytes.len() < num_bytes as usize {
+            self.error = ProtocolErrorKind::StreamOverflow;
+            return false;
+        }
+        if !self.serialize_align() {
+            return false;
+        }
+        self.add_bits(num_bits);
+        true
+    }
+
+    fn serialize_align(&mut self) -> bool {
+        self.bits_processed += (8 - (self.bits_processed % 8)) % 8;
+        self.worst_case_bits += 7;
+        true
+    }
+
+    fn serialize_check(&mut self, _string: &mut String) -> bool {
+        if !self.serialize_align() {
+            return false;
+        }
+        self.add_bits(32);
+        true
+    }
+
+    fn get_bytes_processed(&self) -> u32 {
+        self.bits_processed.div_ceil(8)
+    }
+
+    fn get_bits_processed(&self) -> u32 {
+        self.bits_processed
+    }
+
+    fn get_bits_remaining(&self) -> u32 {
+        self.num_bits.saturating_sub(self.bits_processed)
+    }
+}
@@ -1,17 +1,76 @@
+pub mod measure_stream;
 pub mod read_stream;
 pub mod write_stream;
-use super::constants::ProtocolError;
+use std::any::Any;
+
+use super::protocol_error::ProtocolErrorKind;
 
 pub trait Stream {
     fn is_reading(&self) -> bool;
     fn is_writing(&self) -> bool;
     fn serialise_int(&mut self, value: &mut i32, min: i32, max: i32) -> bool;
     fn serialize_bits(&mut self, value: &mut u32, bits: u32) -> bool;
+    fn serialize_bits_u64(&mut self, value: &mut u64, bits: u32) -> bool;
     fn serialize_align(&mut self) -> bool;
     fn serialize_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool;
     fn serialize_check(&mut self, hash: &mut String) -> bool;
     fn get_bytes_processed(&self) -> u32;
     fn get_bits_processed(&self) -> u32;
     fn get_bits_remaining(&self) -> u32;
-    fn get_error(&mut self) -> ProtocolError;
+    fn get_error(&mut self) -> ProtocolErrorKind;
+    fn get_context(&self) -> Option<&dyn Any>;
+}
+
+/**
+    Looks up the user supplied context set on a stream, if there is one and it is a T.
+    Lets serialize functions fetch per-connection data (world bounds, baselines, string tables...)
+*/
+pub fn get_context<T: Any>(stream: &dyn Stream) -> Option<&T> {
+    stream.get_context()?.downcast_ref::<T>()
+}
+
+/**
+    Lets a `&mut dyn Stream` be passed where a generic `S: Stream` is expected.
+    Used by the DynObject shim to call the generic Object::serialize from a trait object.
+*/
+impl Stream for &mut dyn Stream {
+    fn is_reading(&self) -> bool {
+        (**self).is_reading()
+    }
+    fn is_writing(&self) -> bool {
+        (**self).is_writing()
+    }
+    fn serialise_int(&mut self, value: &mut i32, min: i32, max: i32) -> bool {
+        (**self).serialise_int(value, min, max)
+    }
+    fn serialize_bits(&mut self, value: &mut u32, bits: u32) -> bool {
+        (**self).serialize_bits(value, bits)
+    }
+    fn serialize_bits_u64(&mut self, value: &mut u64, bits: u32) -> bool {
+        (**self).serialize_bits_u64(value, bits)
+    }
+    fn serialize_align(&mut self) -> bool {
+        (**self).serialize_align()
+    }
+    fn serialize_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool {
+        (**self).serialize_bytes(bytes, num_bytes)
+    }
+    fn serialize_check(&mut self, hash: &mut String) -> bool {
+        (**self).serialize_check(hash)
+    }
+    fn get_bytes_processed(&self) -> u32 {
+        (**self).get_bytes_processed()
+    }
+    fn get_bits_processed(&self) -> u32 {
+        (**self).get_bits_processed()
+    }
+    fn get_bits_remaining(&self) -> u32 {
+        (**self).get_bits_remaining()
+    }
+    fn get_error(&mut self) -> ProtocolErrorKind {
+        (**self).get_error()
+    }
+    fn get_context(&self) -> Option<&dyn Any> {
+        (**self).get_context()
+    }
 }
@@ -1,9 +1,11 @@
+use std::any::Any;
+
 use crate::{
     bits_required,
     protocol::{
-        bitpacker::bit_reader::BitReader,
-        constants::{Buffer, ProtocolError},
+        bitpacker::{bit_error::BitError, bit_reader::BitReader},
         helpers::hash_string,
+        protocol_error::ProtocolErrorKind,
     },
 };
 
@@ -11,21 +13,53 @@ use super::Stream;
 
 pub struct ReadStream<'a> {
     pub reader: BitReader<'a>,
-    error: ProtocolError,
+    error: ProtocolErrorKind,
+    bit_error: Option<BitError>,
+    context: Option<&'a dyn Any>,
+    last_check: Option<String>, // label of the most recent serialize_check, used to report where decoding failed
 }
 
 impl<'a> ReadStream<'a> {
-    pub fn new(buffer: &mut Buffer, buffer_size: usize) -> ReadStream {
-        return ReadStream {
-            reader: BitReader::new(buffer, buffer_size),
-            error: ProtocolError::None,
-        };
+    pub fn new(buffer: &'a [u8]) -> ReadStream<'a> {
+        ReadStream {
+            reader: BitReader::new(buffer),
+            error: ProtocolErrorKind::None,
+            bit_error: None,
+            context: None,
+            last_check: None,
+        }
+    }
+
+    /** Sets the context returned by get_context while serializing through this stream */
+    pub fn set_context(&mut self, context: &'a dyn Any) {
+        self.context = Some(context);
+    }
+
+    /** The bitpacker error that caused the stream to fail, if any */
+    pub fn get_bit_error(&self) -> Option<BitError> {
+        self.bit_error
+    }
+
+    /** Label passed to the most recent serialize_check, whether it passed or failed */
+    pub fn get_last_check(&self) -> Option<&str> {
+        self.last_check.as_deref()
+    }
+
+    /** Records a failed read so it is reported as a stream overflow */
+    fn fail(&mut self, error: BitError) -> bool {
+        self.error = ProtocolErrorKind::StreamOverflow;
+        self.bit_error = Some(error);
+        false
     }
 }
 
 impl<'a> Stream for ReadStream<'a> {
-    fn get_error(&mut self) -> ProtocolError {
-        return self.error;
+    fn get_error(&mut self) -> ProtocolErrorKind {
+        self.error
+    }
+
+    fn get_context(&self) -> Option<&dyn Any> {
+        self.context
     }
 
     fn is_reading(&self) -> bool {
@@ -37,85 +71,94 @@ impl<'a> Stream for ReadStream<'a> {
     }
 
     fn serialise_int(&mut self, value: &mut i32, min: i32, max: i32) -> bool {
-        assert!(min < max);
-        let bits = bits_required!(min, max);
-
-        if self.reader.would_read_past_end(bits) {
-            self.error = ProtocolError::StreamOverflow;
+        if min >= max {
+            self.error = ProtocolErrorKind::ValueOutOfRange;
             return false;
         }
+        let bits = bits_required!(min, max);
 
-        let unsigned_val: u32 = self.reader.read_bits(bits);
+        let unsigned_val: u32 = match self.reader.read_bits(bits) {
+            Ok(unsigned_val) => unsigned_val,
+            Err(error) => return self.fail(error),
+        };
 
-        *value = (unsigned_val as i64 + min as i64) as i32; // Add minimum back to unsigned value.
-        return true;
+        // The bits can hold more than max, anything above it was never written by a WriteStream
+        let read_value = unsigned_val as i64 + min as i64; // Add minimum back to unsigned value.
+        if read_value > max as i64 {
+            self.error = ProtocolErrorKind::ValueOutOfRange;
+            return false;
+        }
+        *value = read_value as i32;
+        true
     }
 
     fn serialize_bits(&mut self, value: &mut u32, bits: u32) -> bool {
-        assert!(bits > 0);
-        assert!(bits <= 32);
-        if self.reader.would_read_past_end(bits) {
-            self.error = ProtocolError::StreamOverflow;
-            return false;
+        match self.reader.read_bits(bits) {
+            Ok(bits_value) => *value = bits_value,
+            Err(error) => return self.fail(error),
         }
-        *value = self.reader.read_bits(bits);
+        true
+    }
 
-        return true;
+    fn serialize_bits_u64(&mut self, value: &mut u64, bits: u32) -> bool {
+        match self.reader.read_bits_u64(bits) {
+            Ok(bits_value) => *value = bits_value,
+            Err(error) => return self.fail(error),
+        }
+        true
     }
 
     fn serialize_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool {
-        assert!(self.serialize_align());
-
-        if self.reader.would_read_past_end(num_bytes * 8) {
-            self.error = ProtocolError::StreamOverflow;
+        if !self.serialize_align() {
             return false;
         }
 
-        self.reader.read_bytes(bytes, num_bytes);
-        self.reader.num_bits_read += num_bytes * 8;
+        // Only make room once the bytes are known to be there, num_bytes may have come off the network
+        if num_bytes as u64 * 8 <= self.reader.get_bits_remaining() as u64 {
+            bytes.resize(num_bytes as usize, 0);
+        }
+        if let Err(error) = self.reader.read_bytes(bytes, num_bytes) {
+            return self.fail(error);
+        }
         true
     }
 
     fn serialize_align(&mut self) -> bool {
-        let align_bits = self.reader.get_align_bits();
-        if self.reader.would_read_past_end(align_bits) {
-            self.error = ProtocolError::StreamOverflow;
-            return false;
+        if let Err(error) = self.reader.read_align() {
+            return self.fail(error);
         }
-        return self.reader.read_align();
+        true
     }
 
     fn serialize_check(&mut self, string: &mut String) -> bool {
-        // self.reader.print_word(self.reader.get_word_index());
-
-        self.serialize_align();
-        // self.reader.print_word(self.reader.get_word_index());
-        let mut val: u32 = 0;
+        self.last_check = Some(string.clone());
 
-        self.serialize_bits(&mut val, 32);
+        if !self.serialize_align() {
+            return false;
+        }
 
-        let hash = hash_string(string);
+        let mut val: u32 = 0;
+        if !self.serialize_bits(&mut val, 32) {
+            return false;
+        }
 
-        if hash != val {
-            println!(
-                "Serialize check failed: {:?}. Expected {:?}, got {:?}",
-                string, hash, val
-            );
-            return hash == val;
+        if hash_string(string) != val {
+            self.error = ProtocolErrorKind::SerializeCheckFailed;
+            return false;
         }
 
         true
     }
 
     fn get_bytes_processed(&self) -> u32 {
-        return (self.reader.num_bits_read + 7) / 8;
+        self.reader.num_bits_read.div_ceil(8)
     }
 
     fn get_bits_processed(&self) -> u32 {
-        return self.reader.get_bits_read();
+        self.reader.get_bits_read()
     }
 
     fn get_bits_remaining(&self) -> u32 {
-        return self.reader.get_bits_remaining();
+        self.reader.get_bits_remaining()
     }
 }
@@ -1,9 +1,11 @@
+use std::any::Any;
+
 use crate::{
     bits_required,
     protocol::{
-        bitpacker::bit_writer::BitWriter,
-        constants::{Buffer, ProtocolError},
+        bitpacker::{bit_error::BitError, bit_writer::BitWriter},
         helpers::hash_string,
+        protocol_error::ProtocolErrorKind,
     },
 };
 
@@ -11,21 +13,54 @@ use super::Stream;
 
 pub struct WriteStream<'a> {
     pub writer: BitWriter<'a>,
-    error: ProtocolError,
+    error: ProtocolErrorKind,
+    bit_error: Option<BitError>,
+    context: Option<&'a dyn Any>,
 }
 
 impl<'a> WriteStream<'a> {
-    pub fn new(buffer: &mut Buffer, buffer_size: usize) -> WriteStream {
-        return WriteStream {
-            writer: BitWriter::new(buffer, buffer_size),
-            error: ProtocolError::None,
-        };
+    pub fn new(buffer: &'a mut [u8]) -> WriteStream<'a> {
+        WriteStream {
+            writer: BitWriter::new(buffer),
+            error: ProtocolErrorKind::None,
+            bit_error: None,
+            context: None,
+        }
+    }
+
+    /** Sets the context returned by get_context while serializing through this stream */
+    pub fn set_context(&mut self, context: &'a dyn Any) {
+        self.context = Some(context);
+    }
+
+    /** The bitpacker error that caused the stream to fail, if any */
+    pub fn get_bit_error(&self) -> Option<BitError> {
+        self.bit_error
+    }
+
+    /** Flushes any bits left in the writers scratch into the buffer */
+    pub fn flush(&mut self) -> bool {
+        if let Err(error) = self.writer.flush() {
+            return self.fail(error);
+        }
+        true
+    }
+
+    /** Records a failed write so it is reported as a stream overflow */
+    fn fail(&mut self, error: BitError) -> bool {
+        self.error = ProtocolErrorKind::StreamOverflow;
+        self.bit_error = Some(error);
+        false
     }
 }
 
 impl<'a> Stream for WriteStream<'a> {
-    fn get_error(&mut self) -> ProtocolError {
-        return self.error;
+    fn get_error(&mut self) -> ProtocolErrorKind {
+        self.error
+    }
+
+    fn get_context(&self) -> Option<&dyn Any> {
+        self.context
     }
 
     fn is_reading(&self) -> bool {
@@ -37,67 +72,72 @@ impl<'a> Stream for WriteStream<'a> {
     }
 
     fn serialize_bits(&mut self, value: &mut u32, bits: u32) -> bool {
-        if !(bits > 0) {
-            false;
+        if let Err(error) = self.writer.write_bits(*value, bits) {
+            return self.fail(error);
         }
-        if !(bits <= 32) {
-            false;
+        true
+    }
+
+    fn serialize_bits_u64(&mut self, value: &mut u64, bits: u32) -> bool {
+        if let Err(error) = self.writer.write_bits_u64(*value, bits) {
+            return self.fail(error);
         }
-        self.writer.write_bits(*value, bits);
-        return true;
+        true
     }
 
     /** serialize_int will write the value (minus the min value to save space) */
     fn serialise_int(&mut self, value: &mut i32, min: i32, max: i32) -> bool {
-        assert!(min < max);
-        assert!(*value >= min);
-        assert!(*value <= max);
+        if min >= max || *value < min || *value > max {
+            self.error = ProtocolErrorKind::ValueOutOfRange;
+            return false;
+        }
 
         let bits: u32 = bits_required!(min, max);
         // Convert to higher size int before subtracting to prevent overflow
         let unsigned_val = ((*value as i64) - (min as i64)) as u32;
-        self.writer.write_bits(unsigned_val, bits);
-        return true;
+        if let Err(error) = self.writer.write_bits(unsigned_val, bits) {
+            return self.fail(error);
+        }
+        true
     }
 
     fn serialize_bytes(&mut self, bytes: &mut Vec<u8>, num_bytes: u32) -> bool {
-        assert!(num_bytes > 0);
         if !self.serialize_align() {
             return false;
         }
 
-        return self.writer.write_bytes(bytes, num_bytes);
+        if let Err(error) = self.writer.write_bytes(bytes, num_bytes) {
+            return self.fail(error);
+        }
+        true
     }
 
     /** Serializes as many 0's as it needs to align buffer data to next byte */
     fn serialize_align(&mut self) -> bool {
-        return self.writer.write_align();
+        if let Err(error) = self.writer.write_align() {
+            return self.fail(error);
+        }
+        true
     }
 
     /** Pads buffer data to next byte and serializes string hashed to 32 bits */
     fn serialize_check(&mut self, string: &mut String) -> bool {
-        // println!("------------ START");
-        // self.writer.print_word(self.writer.get_word_index());
-
-        self.serialize_align();
+        if !self.serialize_align() {
+            return false;
+        }
         let mut hash = hash_string(string);
-        self.serialize_bits(&mut hash, 32);
-
-        // self.writer.print_word(self.writer.get_word_index() - 1);
-        // self.writer.print_word(self.writer.get_word_index());
-        // println!("------------ END");
-        true
+        self.serialize_bits(&mut hash, 32)
     }
 
     fn get_bytes_processed(&self) -> u32 {
-        return self.writer.get_bytes_written();
+        self.writer.get_bytes_written()
     }
 
     fn get_bits_processed(&self) -> u32 {
-        return self.writer.get_bits_written();
+        self.writer.get_bits_written()
     }
 
     fn get_bits_remaining(&self) -> u32 {
-        return self.writer.get_total_bytes() * 8 - self.get_bits_processed();
+        self.writer.get_total_bytes() * 8 - self.get_bits_processed()
     }
 }
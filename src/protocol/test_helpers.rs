@@ -0,0 +1,116 @@
+use std::cell::Cell;
+
+use bitpacker_derive::Serialize;
+
+use crate::packet_factory_methods;
+use crate::protocol::messages::{Message, MessageFactory};
+use crate::protocol::packets::{object::Packet, packet_factory::PacketFactory};
+
+/** Packet with a single small value, for tests that only need something to send */
+#[derive(Serialize, Default)]
+#[packet_type(0)]
+pub struct TestPacket {
+    #[range(0, 1000)]
+    pub value: i32,
+}
+
+/** Packet factory shared by the protocol tests, creating packets with the function it was built with */
+pub struct TestPacketFactory {
+    pub num_packet_types: u32,
+    pub num_allocated_packets: Cell<u32>,
+    create: Box<dyn Fn(u32) -> Box<dyn Packet>>,
+}
+
+impl TestPacketFactory {
+    pub fn new(
+        num_packet_types: u32,
+        create: impl Fn(u32) -> Box<dyn Packet> + 'static,
+    ) -> TestPacketFactory {
+        TestPacketFactory {
+            num_packet_types,
+            num_allocated_packets: Cell::new(0),
+            create: Box::new(create),
+        }
+    }
+}
+
+impl PacketFactory for TestPacketFactory {
+    fn create_packet(&self, packet_type: u32) -> Box<dyn Packet> {
+        self.num_allocated_packets
+            .set(self.num_allocated_packets.get() + 1);
+        (self.create)(packet_type)
+    }
+
+    packet_factory_methods!();
+}
+
+/** Message with a single value, message type 0 */
+#[derive(Serialize, Default)]
+pub struct TestMessage {
+    #[range(0, 100000)]
+    pub value: i32,
+}
+
+impl Message for TestMessage {
+    fn get_message_type(&self) -> u32 {
+        0
+    }
+}
+
+/** Message with a value and up to 256 bytes of payload, message type 1 */
+#[derive(Serialize, Default)]
+pub struct TestPayloadMessage {
+    #[range(0, 100000)]
+    pub value: i32,
+    #[max_len(256)]
+    pub payload: Vec<u8>,
+}
+
+impl Message for TestPayloadMessage {
+    fn get_message_type(&self) -> u32 {
+        1
+    }
+}
+
+/** Message factory shared by the channel tests. With one message type it only creates TestMessage. */
+pub struct TestMessageFactory {
+    pub num_message_types: u32, // 1 or 2
+}
+
+impl TestMessageFactory {
+    pub fn new(num_message_types: u32) -> TestMessageFactory {
+        TestMessageFactory { num_message_types }
+    }
+}
+
+impl MessageFactory for TestMessageFactory {
+    fn get_num_message_types(&self) -> u32 {
+        self.num_message_types
+    }
+
+    fn create_message(&self, message_type: u32) -> Box<dyn Message> {
+        match message_type {
+            0 => Box::new(TestMessage::default()),
+            _ => Box::new(TestPayloadMessage::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_factory_counts_allocations() {
+        let factory = TestPacketFactory::new(1, |_| Box::new(TestPacket::default()));
+        let _a = factory.create_packet(0);
+        let _b = factory.create_packet(0);
+        assert_eq!(factory.get_num_allocated_packets(), 2);
+
+        factory.destroy_packet();
+        assert_eq!(factory.get_num_allocated_packets(), 1);
+        factory.destroy_packet();
+        factory.destroy_packet();
+        assert_eq!(factory.get_num_allocated_packets(), 0);
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    rc::Rc,
+};
+
+/**
+    Sends and receives datagrams. Packets are written and read with the packets module,
+    a Transport only moves the bytes between addresses.
+
+    Like UDP, datagrams can be lost and no connection is kept. recv_from never blocks.
+*/
+pub trait Transport {
+    /** Address other transports send to, to reach this one */
+    fn local_address(&self) -> SocketAddr;
+
+    /** Sends one datagram to address. Returns the number of bytes sent. */
+    fn send_to(&mut self, address: SocketAddr, data: &[u8]) -> io::Result<usize>;
+
+    /**
+        Receives one datagram into buffer, returning its size and the address it came from.
+        Returns Ok(None) when there is nothing to receive. Datagrams bigger than buffer are truncated.
+    */
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+}
+
+/** Transport over a non blocking std::net::UdpSocket */
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /** Binds a socket to address, ex. "0.0.0.0:40000", or "127.0.0.1:0" for any free port */
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<UdpTransport> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.socket
+            .local_addr()
+            .expect("bound socket has a local address")
+    }
+
+    fn send_to(&mut self, address: SocketAddr, data: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(data, address)
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buffer) {
+            Ok((size, address)) => Ok(Some((size, address))),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoopbackQueues {
+    queues: HashMap<SocketAddr, VecDeque<(SocketAddr, Vec<u8>)>>, // datagrams waiting for each bound address, with their sender
+    num_datagrams_sent: u64,
+    num_datagrams_dropped: u64,
+}
+
+/**
+    In-process network for LoopbackTransports. Nothing touches the OS network stack.
+
+    Delivery is deterministic: every datagram sent to a bound address arrives, once,
+    in the order it was sent. Datagrams sent to an address nobody bound are dropped, like UDP.
+    Clones share the same network.
+*/
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    queues: Rc<RefCell<LoopbackQueues>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> LoopbackNetwork {
+        LoopbackNetwork::default()
+    }
+
+    /** Binds a transport to address. Fails with AddrInUse if another transport has it. */
+    pub fn bind(&self, address: SocketAddr) -> io::Result<LoopbackTransport> {
+        let mut queues = self.queues.borrow_mut();
+        if queues.queues.contains_key(&address) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        queues.queues.insert(address, VecDeque::new());
+
+        Ok(LoopbackTransport {
+            network: self.clone(),
+            address,
+        })
+    }
+
+    /** Datagrams sent through the network, including dropped ones */
+    pub fn num_datagrams_sent(&self) -> u64 {
+        self.queues.borrow().num_datagrams_sent
+    }
+
+    /** Datagrams sent to an address nobody bound */
+    pub fn num_datagrams_dropped(&self) -> u64 {
+        self.queues.borrow().num_datagrams_dropped
+    }
+}
+
+/** Transport on a LoopbackNetwork. The address is unbound when the transport is dropped. */
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    address: SocketAddr,
+}
+
+impl Transport for LoopbackTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn send_to(&mut self, address: SocketAddr, data: &[u8]) -> io::Result<usize> {
+        let mut queues = self.network.queues.borrow_mut();
+        queues.num_datagrams_sent += 1;
+
+        match queues.queues.get_mut(&address) {
+            Some(queue) => queue.push_back((self.address, data.to_vec())),
+            None => queues.num_datagrams_dropped += 1,
+        }
+        Ok(data.len())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut queues = self.network.queues.borrow_mut();
+        let Some((from, data)) = queues
+            .queues
+            .get_mut(&self.address)
+            .and_then(|queue| queue.pop_front())
+        else {
+            return Ok(None);
+        };
+
+        let size = data.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&data[..size]);
+        Ok(Some((size, from)))
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network
+            .queues
+            .borrow_mut()
+            .queues
+            .remove(&self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(a: &mut dyn Transport, b: &mut dyn Transport) {
+        let mut buffer = [0; 64];
+
+        for i in 0..10u8 {
+            assert_eq!(a.send_to(b.local_address(), &[i; 10]).unwrap(), 10);
+        }
+        let mut received = vec![];
+        for _ in 0..1000 {
+            match b.recv_from(&mut buffer).unwrap() {
+                Some((size, from)) => {
+                    assert_eq!(size, 10);
+                    assert_eq!(from, a.local_address());
+                    received.push(buffer[0]);
+                }
+                None if received.len() == 10 => break,
+                None => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        }
+        received.sort();
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+        assert!(b.recv_from(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_loopback_transport() {
+        let network = LoopbackNetwork::new();
+        let address_a: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let address_b: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+
+        let mut a = network.bind(address_a).unwrap();
+        let mut b = network.bind(address_b).unwrap();
+        assert!(network.bind(address_a).is_err());
+
+        exchange(&mut a, &mut b);
+        exchange(&mut b, &mut a);
+
+        // Delivered in order, truncated to the receive buffer like UDP
+        a.send_to(address_b, &[1, 2, 3, 4]).unwrap();
+        a.send_to(address_b, &[5]).unwrap();
+        let mut buffer = [0; 2];
+        assert_eq!(b.recv_from(&mut buffer).unwrap(), Some((2, address_a)));
+        assert_eq!(buffer, [1, 2]);
+        assert_eq!(b.recv_from(&mut buffer).unwrap(), Some((1, address_a)));
+
+        // Nobody is bound to the address once the transport is dropped
+        drop(b);
+        a.send_to(address_b, &[1]).unwrap();
+        assert_eq!(network.num_datagrams_dropped(), 1);
+        assert_eq!(network.num_datagrams_sent(), 23);
+        assert!(network.bind(address_b).is_ok());
+    }
+
+    #[test]
+    fn test_udp_transport() {
+        let mut a = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut b = UdpTransport::bind("127.0.0.1:0").unwrap();
+        exchange(&mut a, &mut b);
+    }
+}